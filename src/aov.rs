@@ -0,0 +1,284 @@
+use crate::hitable::HitRecord;
+use crate::image::Image;
+use crate::material::Material;
+use crate::output;
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+
+// Auxiliary output variables, taken from the first surface a camera ray hits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum AovKind {
+    Depth,
+    Normal,
+    Albedo,
+    Position,
+    MaterialId,
+    ObjectId,
+}
+
+pub const ALL_AOVS: [AovKind; 6] = [
+    AovKind::Depth,
+    AovKind::Normal,
+    AovKind::Albedo,
+    AovKind::Position,
+    AovKind::MaterialId,
+    AovKind::ObjectId,
+];
+
+impl AovKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AovKind::Depth => "depth",
+            AovKind::Normal => "normal",
+            AovKind::Albedo => "albedo",
+            AovKind::Position => "position",
+            AovKind::MaterialId => "material_id",
+            AovKind::ObjectId => "object_id",
+        }
+    }
+
+    // IDs can't be averaged, so they keep the first sample of each pixel
+    fn is_id(self) -> bool {
+        self == AovKind::MaterialId || self == AovKind::ObjectId
+    }
+
+    fn channels(self) -> usize {
+        match self {
+            AovKind::Depth | AovKind::MaterialId | AovKind::ObjectId => 1,
+            _ => 3,
+        }
+    }
+}
+
+impl FromStr for AovKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ALL_AOVS
+            .iter()
+            .cloned()
+            .find(|kind| kind.name() == s)
+            .ok_or(())
+    }
+}
+
+// Material IDs in the order a scene creates its materials, starting at 1,
+// so the same scene description always gives the same IDs
+#[derive(Clone, Debug, Default)]
+pub struct MaterialIds {
+    ids: HashMap<usize, usize>,
+}
+
+// Identifies a material while the scene holding it is alive
+fn address(material: &dyn Material) -> usize {
+    material as *const _ as *const () as usize
+}
+
+impl MaterialIds {
+    pub fn add(&mut self, material: &dyn Material) {
+        let next_id = self.ids.len() + 1;
+        self.ids.entry(address(material)).or_insert(next_id);
+    }
+
+    // Zero for a material the scene never added
+    fn get(&self, material: usize) -> usize {
+        self.ids.get(&material).cloned().unwrap_or(0)
+    }
+}
+
+// What a camera ray saw first. `material` identifies the material by
+// address; it is turned into its ID when recorded.
+#[derive(Copy, Clone, Debug)]
+pub struct FirstHit {
+    pub depth: f64,
+    pub normal: Vec3,
+    pub albedo: Vec3,
+    pub position: Vec3,
+    pub material: usize,
+    pub object_id: usize,
+}
+
+impl FirstHit {
+    pub fn new(ray: &Ray, rec: &HitRecord) -> FirstHit {
+        FirstHit {
+            depth: (rec.p - ray.origin()).len(),
+            normal: rec.normal,
+            albedo: rec.material.albedo(),
+            position: rec.p,
+            material: address(rec.material),
+            object_id: rec.object_id,
+        }
+    }
+}
+
+// Per-pixel AOV accumulation. Pixels with no hit read as zero and IDs
+// start at 1, so zero also means "nothing".
+pub struct AovBuffers {
+    kinds: Vec<AovKind>,
+    width: usize,
+    height: usize,
+    sums: Vec<Vec<Vec3>>,
+    counts: Vec<usize>,
+    material_ids: MaterialIds,
+}
+
+impl AovBuffers {
+    pub fn new(kinds: &[AovKind], width: usize, height: usize) -> AovBuffers {
+        AovBuffers {
+            kinds: kinds.to_vec(),
+            width,
+            height,
+            sums: vec![vec![Vec3::default(); width * height]; kinds.len()],
+            counts: vec![0; width * height],
+            material_ids: MaterialIds::default(),
+        }
+    }
+
+    // Where the material ID pass gets its numbers; without it every
+    // material reads as zero
+    pub fn with_material_ids(mut self, material_ids: MaterialIds) -> Self {
+        self.material_ids = material_ids;
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.kinds.is_empty()
+    }
+
+    pub fn add(&mut self, i: usize, j: usize, hit: Option<FirstHit>) {
+        let index = j * self.width + i;
+        let first = self.counts[index] == 0;
+        self.counts[index] += 1;
+        let hit = match hit {
+            Some(hit) => hit,
+            None => return,
+        };
+        let material_id = self.material_ids.get(hit.material);
+        for (kind, sums) in self.kinds.iter().zip(&mut self.sums) {
+            let value = match kind {
+                AovKind::Depth => Vec3::new(hit.depth, 0.0, 0.0),
+                AovKind::Normal => hit.normal,
+                AovKind::Albedo => hit.albedo,
+                AovKind::Position => hit.position,
+                AovKind::MaterialId => Vec3::new(material_id as f64, 0.0, 0.0),
+                AovKind::ObjectId => Vec3::new(hit.object_id as f64 + 1.0, 0.0, 0.0),
+            };
+            if !kind.is_id() {
+                sums[index] += value;
+            } else if first {
+                sums[index] = value;
+            }
+        }
+    }
+
+    pub fn image(&self, kind: AovKind) -> Option<Image> {
+        let k = self.kinds.iter().position(|&k| k == kind)?;
+        let mut image = Image::new(self.width, self.height);
+        for j in 0..self.height {
+            for i in 0..self.width {
+                let index = j * self.width + i;
+                let sum = self.sums[k][index];
+                if kind.is_id() || self.counts[index] == 0 {
+                    image.set(i, j, sum);
+                } else {
+                    image.set(i, j, sum / self.counts[index] as f64);
+                }
+            }
+        }
+        Some(image)
+    }
+
+    // Writes the given passes to `<prefix>.<name>.pfm`
+    pub fn write(&self, prefix: &str, kinds: &[AovKind]) -> io::Result<()> {
+        for &kind in kinds {
+            let image = match self.image(kind) {
+                Some(image) => image,
+                None => continue,
+            };
+            let path = format!("{}.{}.pfm", prefix, kind.name());
+            let mut out = BufWriter::new(File::create(&path)?);
+            output::write_pfm(&mut out, &image, kind.channels())?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn aov_passes() {
+    use crate::integrator::PathTracer;
+    use crate::sampler::IndependentSampler;
+    use crate::scene::Scene;
+
+    // A ball in front of a wall, seen head on by an orthographic camera, with
+    // the materials defined in the opposite order to the objects
+    let scene = Scene::parse(
+        "material name wall type lambertian albedo 0.2 0.4 0.6\n\
+         material name ball type lambertian albedo 0.8 0.1 0.1\n\
+         sphere center 0 0 0 radius 1 material ball\n\
+         rect corner -3 -3 -1 edge_u 6 0 0 edge_v 0 6 0 material wall\n\
+         camera type orthographic look_from 0 0 5 look_at 0 0 0 height 4\n",
+        16,
+        16,
+        0,
+    )
+    .unwrap();
+    let integrator = PathTracer::new(1, 1);
+    let mut sampler = IndependentSampler::new(0);
+    let render = |pixels: &[(usize, usize)], sampler: &mut IndependentSampler| {
+        let mut aovs =
+            AovBuffers::new(&ALL_AOVS, 16, 16).with_material_ids(scene.material_ids.clone());
+        for &(i, j) in pixels {
+            // Two samples through the pixel center, which agree
+            for _ in 0..2 {
+                let (u, v) = ((i as f64 + 0.5) / 16.0, (j as f64 + 0.5) / 16.0);
+                let ray = scene.camera.generate_ray(u, v, sampler).unwrap();
+                let (_, hit) = integrator.color(&ray, &scene.world, &scene.lights, sampler);
+                aovs.add(i, j, hit);
+            }
+        }
+        aovs
+    };
+    let rows: Vec<(usize, usize)> = (0..16).flat_map(|j| (0..16).map(move |i| (i, j))).collect();
+    // Outwards from the middle, so the ball is reached before the wall
+    let mut spiral = rows.clone();
+    spiral.sort_by_key(|&(i, j)| (2 * i as isize - 15).pow(2) + (2 * j as isize - 15).pow(2));
+    let first = render(&rows, &mut sampler);
+    let second = render(&spiral, &mut sampler);
+
+    let pass = |aovs: &AovBuffers, kind: AovKind| aovs.image(kind).unwrap();
+    for j in 0..16 {
+        for i in 0..16 {
+            // The camera's window is 4 wide, so pixels are 0.25 apart
+            let (x, y) = (0.25 * i as f64 - 1.875, 0.25 * j as f64 - 1.875);
+            let on_ball = x * x + y * y < 1.0;
+            let (p, normal, albedo, material, object) = if on_ball {
+                let p = Vec3::new(x, y, (1.0 - x * x - y * y).sqrt());
+                (p, p, Vec3::new(0.8, 0.1, 0.1), 2.0, 1.0)
+            } else {
+                let p = Vec3::new(x, y, -1.0);
+                (
+                    p,
+                    Vec3::new(0.0, 0.0, 1.0),
+                    Vec3::new(0.2, 0.4, 0.6),
+                    1.0,
+                    2.0,
+                )
+            };
+            let close = |a: Vec3, b: Vec3| (a - b).len() < 1e-9;
+            assert!((pass(&first, AovKind::Depth).pixel(i, j).r() - (5.0 - p.z())).abs() < 1e-9);
+            assert!(close(pass(&first, AovKind::Position).pixel(i, j), p));
+            assert!(close(pass(&first, AovKind::Normal).pixel(i, j), normal));
+            assert!(close(pass(&first, AovKind::Albedo).pixel(i, j), albedo));
+            // IDs follow the scene description, whatever order pixels come in
+            for aovs in &[&first, &second] {
+                assert_eq!(pass(aovs, AovKind::MaterialId).pixel(i, j).r(), material);
+                assert_eq!(pass(aovs, AovKind::ObjectId).pixel(i, j).r(), object);
+            }
+        }
+    }
+}
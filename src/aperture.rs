@@ -0,0 +1,153 @@
+use crate::distribution::Distribution2D;
+use crate::image::Image;
+use crate::sampler::{remap, sample_concentric_disk};
+use std::f64::consts::PI;
+
+pub enum ApertureShape {
+    Circle,
+    // Regular polygon inscribed in the lens, with one corner at `rotation`
+    // radians from the horizontal
+    Polygon { blades: u32, rotation: f64 },
+    // Transmission given by a grayscale image covering the square around the
+    // lens; its overall brightness doesn't matter
+    Image(Distribution2D),
+}
+
+impl ApertureShape {
+    pub fn from_image(image: &Image) -> ApertureShape {
+        let weights: Vec<f64> = image.pixels().iter().map(|p| p.luminance()).collect();
+        ApertureShape::Image(Distribution2D::new(&weights, image.width(), image.height()))
+    }
+}
+
+// The shape of a lens opening, which is what out-of-focus highlights take
+// on. With `vignetting` the lens barrel also cuts into the opening towards
+// the edges of the image, giving the cat's eye bokeh of real lenses.
+pub struct Aperture {
+    shape: ApertureShape,
+    vignetting: f64,
+}
+
+impl Aperture {
+    pub fn new(shape: ApertureShape, vignetting: f64) -> Aperture {
+        Aperture { shape, vignetting }
+    }
+
+    pub fn circle() -> Aperture {
+        Aperture::new(ApertureShape::Circle, 0.0)
+    }
+
+    // Point on the aperture in units of the lens radius for a ray through
+    // `film`, the film position relative to the center of the image scaled
+    // so that the corners are at distance one. Returns None if the barrel
+    // blocks the ray.
+    pub fn sample(&self, u: (f64, f64), film: (f64, f64)) -> Option<(f64, f64)> {
+        let (x, y) = match &self.shape {
+            ApertureShape::Circle => sample_concentric_disk(u),
+            ApertureShape::Polygon { blades, rotation } => {
+                // Uniform over one of the triangles between the center and
+                // two neighboring corners
+                let n = *blades as f64;
+                let k = (u.0 * n).floor().min(n - 1.0);
+                let s = remap(u.0 * n, k, 1.0).sqrt();
+                let corner = |i: f64| {
+                    let angle = rotation + 2.0 * PI * i / n;
+                    (angle.cos(), angle.sin())
+                };
+                let (a, b) = (corner(k), corner(k + 1.0));
+                let (wa, wb) = (s * (1.0 - u.1), s * u.1);
+                (wa * a.0 + wb * b.0, wa * a.1 + wb * b.1)
+            }
+            ApertureShape::Image(distribution) => {
+                let ((x, y), _) = distribution.sample(u);
+                (2.0 * x - 1.0, 2.0 * y - 1.0)
+            }
+        };
+        // The barrel's opening, seen from off axis, is a circle the size of
+        // the lens sliding outwards
+        let (cx, cy) = (self.vignetting * film.0, self.vignetting * film.1);
+        if (x - cx).powi(2) + (y - cy).powi(2) > 1.0 {
+            return None;
+        }
+        Some((x, y))
+    }
+}
+
+#[test]
+fn aperture_sampling() {
+    use crate::sampler::{IndependentSampler, Sampler};
+
+    let mut sampler = IndependentSampler::new(0);
+    let n = 100_000;
+    let mut samples = |aperture: &Aperture, film: (f64, f64)| {
+        (0..n)
+            .filter_map(|index| {
+                sampler.start_pixel_sample((0, 0), index);
+                aperture.sample(sampler.get_2d(), film)
+            })
+            .collect::<Vec<(f64, f64)>>()
+    };
+
+    // A hexagon's samples stay inside it, and land evenly: each cell of a
+    // grid lying inside gets its share by area
+    let (blades, rotation) = (6, 0.3);
+    let hexagon = Aperture::new(ApertureShape::Polygon { blades, rotation }, 0.0);
+    let corner = |i: u32| {
+        let angle = rotation + 2.0 * PI * i as f64 / blades as f64;
+        (angle.cos(), angle.sin())
+    };
+    // Inside every edge, going counterclockwise
+    let inside = |p: (f64, f64)| {
+        (0..blades).all(|i| {
+            let (a, b) = (corner(i), corner(i + 1));
+            (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0) >= -1e-12
+        })
+    };
+    let points = samples(&hexagon, (0.0, 0.0));
+    assert_eq!(points.len(), n);
+    assert!(points.iter().all(|&p| inside(p)));
+    let area = 0.5 * blades as f64 * (2.0 * PI / blades as f64).sin();
+    let cells = 8;
+    let size = 2.0 / cells as f64;
+    for j in 0..cells {
+        for i in 0..cells {
+            let (x0, y0) = (-1.0 + i as f64 * size, -1.0 + j as f64 * size);
+            let corners = [
+                (x0, y0),
+                (x0 + size, y0),
+                (x0, y0 + size),
+                (x0 + size, y0 + size),
+            ];
+            if !corners.iter().all(|&c| inside(c)) {
+                continue;
+            }
+            let count = points
+                .iter()
+                .filter(|p| p.0 >= x0 && p.0 < x0 + size && p.1 >= y0 && p.1 < y0 + size)
+                .count();
+            let expected = n as f64 * size * size / area;
+            assert!(
+                (count as f64 - expected).abs() < 0.06 * expected,
+                "{} vs {}",
+                count,
+                expected
+            );
+        }
+    }
+
+    // Vignetting passes everything on axis. Off axis it keeps what the lens
+    // and a unit circle shifted towards the image corner have in common.
+    let vignetted = Aperture::new(ApertureShape::Circle, 0.5);
+    assert_eq!(samples(&vignetted, (0.0, 0.0)).len(), n);
+    let film = (0.6, 0.8);
+    let points = samples(&vignetted, film);
+    let (cx, cy) = (0.5 * film.0, 0.5 * film.1);
+    assert!(points
+        .iter()
+        .all(|p| p.0 * p.0 + p.1 * p.1 <= 1.0 && (p.0 - cx).powi(2) + (p.1 - cy).powi(2) <= 1.0));
+    // Overlap of two unit circles 0.5 apart, over the lens
+    let d: f64 = 0.5;
+    let overlap = 2.0 * (d / 2.0).acos() - d / 2.0 * (4.0 - d * d).sqrt();
+    let kept = points.len() as f64 / n as f64;
+    assert!((kept - overlap / PI).abs() < 0.01, "{}", kept);
+}
@@ -1,20 +1,98 @@
+use crate::aperture::Aperture;
+use crate::hitable::Hitable;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::Vec3;
-use rand::{thread_rng, Rng};
 use std::f64::consts::PI;
+use std::rc::Rc;
 
-fn random_in_unit_disk() -> Vec3 {
-    let mut rand = thread_rng();
-    loop {
-        let p = 2.0 * Vec3::new(rand.gen::<f64>(), rand.gen::<f64>(), 0.0);
-        if Vec3::dot(p, p) >= 1.0 {
-            return p;
+// Maps a point on the film to a camera ray. `u` and `v` run from 0 to 1
+// across the image, with `v` counted from the bottom. Returns None for film
+// positions the projection doesn't cover, like the corners of a fisheye.
+pub trait CameraModel {
+    fn generate_ray(&self, u: f64, v: f64, sampler: &mut dyn Sampler) -> Option<Ray>;
+
+    // The ray along with the weight of the radiance it brings back, for
+    // cameras that don't collect the same amount of light through every ray
+    fn generate_weighted_ray(
+        &self,
+        u: f64,
+        v: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, f64)> {
+        self.generate_ray(u, v, sampler).map(|ray| (ray, 1.0))
+    }
+}
+
+// Orthonormal camera frame: `u` points right, `v` up and `w` backwards
+#[derive(Copy, Clone, Debug)]
+pub struct Frame {
+    pub origin: Vec3,
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Frame {
+    pub fn new(look_from: Vec3, look_at: Vec3, vup: Vec3) -> Frame {
+        let w = Vec3::unit_vector(look_from - look_at);
+        let u = Vec3::unit_vector(Vec3::cross(vup, w));
+        let v = Vec3::cross(w, u);
+        Frame {
+            origin: look_from,
+            u,
+            v,
+            w,
         }
     }
+
+    pub fn local(&self, x: f64, y: f64, z: f64) -> Vec3 {
+        x * self.u + y * self.v + z * self.w
+    }
+}
+
+// Camera settings as a photographer gives them. Lengths are in millimeters,
+// on the assumption that scene units are meters, and `shutter` is in seconds.
+#[derive(Copy, Clone, Debug)]
+pub struct PhysicalSettings {
+    pub focal_length: f64,
+    pub sensor: (f64, f64),
+    pub f_number: f64,
+    pub shutter: f64,
+    pub iso: f64,
+}
+
+// The largest part of a sensor with the image's aspect ratio, centered
+pub fn film_size(sensor: (f64, f64), aspect: f64) -> (f64, f64) {
+    let height = sensor.1.min(sensor.0 / aspect);
+    (height * aspect, height)
 }
 
-#[derive(Copy, Clone, Default)]
-pub struct Camera {
+impl PhysicalSettings {
+    // Vertical field of view in degrees for an image of the given aspect
+    // ratio, cropped from the sensor as needed. The lens is taken to be
+    // focused at infinity, so the view doesn't change with focus.
+    pub fn vfov(&self, aspect: f64) -> f64 {
+        let (_, height) = film_size(self.sensor, aspect);
+        2.0 * (0.5 * height / self.focal_length).atan().to_degrees()
+    }
+
+    // Diameter of the entrance pupil in scene units
+    pub fn aperture(&self) -> f64 {
+        self.focal_length / self.f_number / 1000.0
+    }
+
+    // Scale from scene radiance to sensor values, with the usual saturation
+    // based calibration: EV100 = log2(N^2 / t * 100 / ISO), and a sensor
+    // value of 1 at 1.2 * 2^EV100
+    pub fn exposure(&self) -> f64 {
+        let ev100 = (self.f_number * self.f_number / self.shutter * 100.0 / self.iso).log2();
+        1.0 / (1.2 * 2f64.powf(ev100))
+    }
+}
+
+#[derive(Clone)]
+pub struct PerspectiveCamera {
     origin: Vec3,
     lower_left_corner: Vec3,
     horizontal: Vec3,
@@ -23,9 +101,19 @@ pub struct Camera {
     v: Vec3,
     w: Vec3,
     lens_radius: f64,
+    aperture: Rc<Aperture>,
+    // Maps film positions from [-1, 1] to a scale where the corners of the
+    // image are at distance one
+    film_scale: (f64, f64),
+    // Lens shift in image widths and heights
+    shift: (f64, f64),
+    focus_dist: f64,
+    // A point on the focal plane and its normal when the plane is tilted;
+    // otherwise it is perpendicular to the view at `focus_dist`
+    focal_plane: Option<(Vec3, Vec3)>,
 }
 
-impl Camera {
+impl PerspectiveCamera {
     //vfov is top to bottom in degrees
     pub fn new(
         look_from: Vec3,
@@ -35,14 +123,12 @@ impl Camera {
         aspect: f64,
         aperture: f64,
         focus_dist: f64,
-    ) -> Camera {
+    ) -> PerspectiveCamera {
         let theta: f64 = vfov * PI / 180.0;
         let half_height: f64 = (theta / 2.0).tan();
         let half_width: f64 = aspect * half_height;
-        let w = Vec3::unit_vector(look_from - look_at);
-        let u = Vec3::unit_vector(Vec3::cross(vup, w));
-        let v = Vec3::cross(w, u);
-        Camera {
+        let Frame { u, v, w, .. } = Frame::new(look_from, look_at, vup);
+        PerspectiveCamera {
             lower_left_corner: look_from
                 - half_width * u * focus_dist
                 - half_height * v * focus_dist
@@ -50,19 +136,641 @@ impl Camera {
             horizontal: 2.0 * half_width * u * focus_dist,
             vertical: 2.0 * half_height * v * focus_dist,
             origin: look_from,
-            u: u,
-            v: v,
-            w: w,
+            u,
+            v,
+            w,
             lens_radius: aperture / 2.0,
+            aperture: Rc::new(Aperture::circle()),
+            film_scale: (
+                aspect / (aspect * aspect + 1.0).sqrt(),
+                1.0 / (aspect * aspect + 1.0).sqrt(),
+            ),
+            shift: (0.0, 0.0),
+            focus_dist,
+            focal_plane: None,
         }
     }
-    pub fn get_ray(self, u: f64, v: f64) -> Ray {
-        let rd = self.lens_radius * random_in_unit_disk();
-        let offset = rd.x() * self.u + rd.y() * self.v;
 
-        Ray::new(
-            self.origin + offset,
-            self.lower_left_corner + u * self.horizontal + v * self.vertical - self.origin - offset,
+    // A camera set up from photographic settings instead of a field of view
+    // and an aperture in scene units
+    pub fn physical(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        settings: &PhysicalSettings,
+        aspect: f64,
+        focus_dist: f64,
+    ) -> PerspectiveCamera {
+        PerspectiveCamera::new(
+            look_from,
+            look_at,
+            vup,
+            settings.vfov(aspect),
+            aspect,
+            settings.aperture(),
+            focus_dist,
         )
     }
+
+    // Moves the image window by `x` image widths to the right and `y` image
+    // heights up without turning the camera, like the rising front used to
+    // keep verticals parallel in architecture shots
+    pub fn shifted(self, x: f64, y: f64) -> PerspectiveCamera {
+        PerspectiveCamera {
+            lower_left_corner: self.lower_left_corner + x * self.horizontal + y * self.vertical,
+            shift: (self.shift.0 + x, self.shift.1 + y),
+            ..self
+        }
+    }
+
+    // Tilts the focal plane, as a Scheimpflug lens tilt would, so it
+    // recedes towards the top of the image by `tilt` degrees and towards
+    // the right by `swing` degrees. It still passes through the point in
+    // focus at the center of the view.
+    pub fn tilted(self, tilt: f64, swing: f64) -> PerspectiveCamera {
+        if tilt == 0.0 && swing == 0.0 {
+            return PerspectiveCamera {
+                focal_plane: None,
+                ..self
+            };
+        }
+        let normal = Vec3::unit_vector(
+            -self.w - tilt.to_radians().tan() * self.v - swing.to_radians().tan() * self.u,
+        );
+        PerspectiveCamera {
+            focal_plane: Some((self.origin - self.focus_dist * self.w, normal)),
+            ..self
+        }
+    }
+
+    pub fn focus_dist(&self) -> f64 {
+        self.focus_dist
+    }
+
+    // The same camera focused at `focus_dist` instead
+    pub fn refocus(&self, focus_dist: f64) -> PerspectiveCamera {
+        let scale = focus_dist / self.focus_dist;
+        PerspectiveCamera {
+            lower_left_corner: self.origin + scale * (self.lower_left_corner - self.origin),
+            horizontal: scale * self.horizontal,
+            vertical: scale * self.vertical,
+            focus_dist,
+            focal_plane: self
+                .focal_plane
+                .map(|(_, normal)| (self.origin - focus_dist * self.w, normal)),
+            ..self.clone()
+        }
+    }
+
+    // Focuses on whatever is seen through the center of the lens at film
+    // position (u, v). The focus distance is where the focal plane through
+    // that point crosses the view axis, not the distance to the point, so
+    // off-center points end up exactly in focus too. Returns None if the ray
+    // hits nothing.
+    pub fn focus_on(&self, world: &dyn Hitable, u: f64, v: f64) -> Option<PerspectiveCamera> {
+        let target = self.lower_left_corner + u * self.horizontal + v * self.vertical;
+        let ray = Ray::new(self.origin, target - self.origin);
+        let rec = world.hit(&ray, 1e-3, f64::MAX)?;
+        let normal = self.focal_plane.map_or(-self.w, |(_, normal)| normal);
+        Some(self.refocus(Vec3::dot(rec.p - self.origin, normal) / Vec3::dot(-self.w, normal)))
+    }
+
+    pub fn with_aperture(self, aperture: Aperture) -> PerspectiveCamera {
+        PerspectiveCamera {
+            aperture: Rc::new(aperture),
+            ..self
+        }
+    }
+
+    // One eye of a stereo pair, moved `offset` to the right. The eyes look
+    // in parallel and their film windows are shifted so that both see the
+    // same picture at distance `convergence`, which avoids the vertical
+    // parallax of toed-in cameras.
+    pub fn eye(&self, offset: f64, convergence: f64) -> PerspectiveCamera {
+        let shift = offset * self.u;
+        PerspectiveCamera {
+            origin: self.origin + shift,
+            lower_left_corner: self.lower_left_corner
+                + (1.0 - self.focus_dist / convergence) * shift,
+            ..self.clone()
+        }
+    }
+}
+
+impl CameraModel for PerspectiveCamera {
+    fn generate_ray(&self, u: f64, v: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let film = (
+            (2.0 * (u + self.shift.0) - 1.0) * self.film_scale.0,
+            (2.0 * (v + self.shift.1) - 1.0) * self.film_scale.1,
+        );
+        let (dx, dy) = self.aperture.sample(sampler.get_2d(), film)?;
+        let offset = self.lens_radius * (dx * self.u + dy * self.v);
+        let target = self.lower_left_corner + u * self.horizontal + v * self.vertical;
+        // Rays through the center of the lens are in focus where they meet
+        // the focal plane
+        let focus = match self.focal_plane {
+            None => target,
+            Some((point, normal)) => {
+                let direction = target - self.origin;
+                let t = Vec3::dot(point - self.origin, normal) / Vec3::dot(direction, normal);
+                if !t.is_finite() || t <= 0.0 {
+                    return None;
+                }
+                self.origin + t * direction
+            }
+        };
+
+        Some(Ray::new(self.origin + offset, focus - self.origin - offset))
+    }
+}
+
+// Parallel rays from a `height` by `height * aspect` window around the eye
+pub struct OrthographicCamera {
+    frame: Frame,
+    half_width: f64,
+    half_height: f64,
+}
+
+impl OrthographicCamera {
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        height: f64,
+        aspect: f64,
+    ) -> OrthographicCamera {
+        OrthographicCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            half_width: 0.5 * height * aspect,
+            half_height: 0.5 * height,
+        }
+    }
+}
+
+impl CameraModel for OrthographicCamera {
+    fn generate_ray(&self, u: f64, v: f64, _sampler: &mut dyn Sampler) -> Option<Ray> {
+        let x = (2.0 * u - 1.0) * self.half_width;
+        let y = (2.0 * v - 1.0) * self.half_height;
+        Some(Ray::new(
+            self.frame.origin + self.frame.local(x, y, 0.0),
+            -self.frame.w,
+        ))
+    }
+}
+
+// How a fisheye maps the angle from the optical axis to the image radius
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FisheyeProjection {
+    // Radius proportional to the angle
+    Equidistant,
+    // Radius proportional to sin(angle / 2), which preserves solid angle
+    Equisolid,
+}
+
+// Circular fisheye whose image circle spans the height of the image and
+// covers `fov` degrees
+pub struct FisheyeCamera {
+    frame: Frame,
+    projection: FisheyeProjection,
+    aspect: f64,
+    theta_max: f64,
+}
+
+impl FisheyeCamera {
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        projection: FisheyeProjection,
+        fov: f64,
+        aspect: f64,
+    ) -> FisheyeCamera {
+        FisheyeCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            projection,
+            aspect,
+            theta_max: 0.5 * fov * PI / 180.0,
+        }
+    }
+}
+
+impl CameraModel for FisheyeCamera {
+    fn generate_ray(&self, u: f64, v: f64, _sampler: &mut dyn Sampler) -> Option<Ray> {
+        // Film position relative to the image circle, which has radius one
+        let x = (2.0 * u - 1.0) * self.aspect;
+        let y = 2.0 * v - 1.0;
+        let r = (x * x + y * y).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let theta = match self.projection {
+            FisheyeProjection::Equidistant => r * self.theta_max,
+            FisheyeProjection::Equisolid => 2.0 * (r * (0.5 * self.theta_max).sin()).asin(),
+        };
+        let (cos_phi, sin_phi) = if r > 0.0 { (x / r, y / r) } else { (1.0, 0.0) };
+        let direction =
+            self.frame
+                .local(theta.sin() * cos_phi, theta.sin() * sin_phi, -theta.cos());
+        Some(Ray::new(self.frame.origin, direction))
+    }
+}
+
+// Full 360 by 180 degree latitude-longitude panorama, looking at `look_at`
+// in the middle of the image
+pub struct EquirectangularCamera {
+    frame: Frame,
+    eye_offset: f64,
+    convergence: Option<f64>,
+}
+
+impl EquirectangularCamera {
+    pub fn new(look_from: Vec3, look_at: Vec3, vup: Vec3) -> EquirectangularCamera {
+        EquirectangularCamera::ods(look_from, look_at, vup, 0.0, None)
+    }
+
+    // One eye of an omni-directional stereo panorama. Every ray starts
+    // `eye_offset` to the right of `look_from`, on a circle perpendicular
+    // to its direction, so each column of the panorama has the right
+    // parallax for someone looking that way. Without `convergence` the eyes
+    // look in parallel, otherwise they turn in to meet at that distance.
+    pub fn ods(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        eye_offset: f64,
+        convergence: Option<f64>,
+    ) -> EquirectangularCamera {
+        EquirectangularCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            eye_offset,
+            convergence,
+        }
+    }
+}
+
+impl CameraModel for EquirectangularCamera {
+    fn generate_ray(&self, u: f64, v: f64, _sampler: &mut dyn Sampler) -> Option<Ray> {
+        let phi = 2.0 * PI * (u - 0.5);
+        let theta = PI * (v - 0.5);
+        let direction = self.frame.local(
+            theta.cos() * phi.sin(),
+            theta.sin(),
+            -theta.cos() * phi.cos(),
+        );
+        let offset = self.eye_offset * self.frame.local(phi.cos(), 0.0, phi.sin());
+        let direction = match self.convergence {
+            Some(distance) => distance * direction - offset,
+            None => direction,
+        };
+        Some(Ray::new(self.frame.origin + offset, direction))
+    }
+}
+
+// The six 90 degree faces of a cube side by side, in the order +x, -x, +y,
+// -y, +z, -z of the camera frame (x right, y up, z backwards), so images
+// should be six times as wide as they are high
+pub struct CubemapCamera {
+    frame: Frame,
+}
+
+// Forward, right and up axes of each face in camera coordinates. Faces are
+// seen from inside the cube, so none of them is mirrored.
+const CUBE_FACES: [[(f64, f64, f64); 3]; 6] = [
+    [(1.0, 0.0, 0.0), (0.0, 0.0, 1.0), (0.0, 1.0, 0.0)],
+    [(-1.0, 0.0, 0.0), (0.0, 0.0, -1.0), (0.0, 1.0, 0.0)],
+    [(0.0, 1.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, 1.0)],
+    [(0.0, -1.0, 0.0), (1.0, 0.0, 0.0), (0.0, 0.0, -1.0)],
+    [(0.0, 0.0, 1.0), (-1.0, 0.0, 0.0), (0.0, 1.0, 0.0)],
+    [(0.0, 0.0, -1.0), (1.0, 0.0, 0.0), (0.0, 1.0, 0.0)],
+];
+
+impl CubemapCamera {
+    pub fn new(look_from: Vec3, look_at: Vec3, vup: Vec3) -> CubemapCamera {
+        CubemapCamera {
+            frame: Frame::new(look_from, look_at, vup),
+        }
+    }
+}
+
+impl CameraModel for CubemapCamera {
+    fn generate_ray(&self, u: f64, v: f64, _sampler: &mut dyn Sampler) -> Option<Ray> {
+        let face = ((u * 6.0) as usize).min(5);
+        let s = 2.0 * (u * 6.0 - face as f64) - 1.0;
+        let t = 2.0 * v - 1.0;
+        let [forward, right, up] = CUBE_FACES[face];
+        let direction = self.frame.local(
+            forward.0 + s * right.0 + t * up.0,
+            forward.1 + s * right.1 + t * up.1,
+            forward.2 + s * right.2 + t * up.2,
+        );
+        Some(Ray::new(self.frame.origin, direction))
+    }
+}
+
+// How the two eyes of a stereo pair share the image
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum StereoLayout {
+    // Left eye in the left half
+    SideBySide,
+    // Left eye in the top half
+    OverUnder,
+}
+
+impl StereoLayout {
+    // Aspect ratio of each eye's view for an image of aspect `aspect`
+    pub fn eye_aspect(self, aspect: f64) -> f64 {
+        match self {
+            StereoLayout::SideBySide => aspect / 2.0,
+            StereoLayout::OverUnder => aspect * 2.0,
+        }
+    }
+}
+
+pub struct StereoCamera {
+    left: Box<dyn CameraModel>,
+    right: Box<dyn CameraModel>,
+    layout: StereoLayout,
+}
+
+impl StereoCamera {
+    pub fn new(
+        left: Box<dyn CameraModel>,
+        right: Box<dyn CameraModel>,
+        layout: StereoLayout,
+    ) -> StereoCamera {
+        StereoCamera {
+            left,
+            right,
+            layout,
+        }
+    }
+
+    // The eye that sees film position (u, v) and the position on its film
+    fn eye(&self, u: f64, v: f64) -> (&dyn CameraModel, f64, f64) {
+        let (left, u, v) = match self.layout {
+            StereoLayout::SideBySide => (u < 0.5, (2.0 * u).fract(), v),
+            StereoLayout::OverUnder => (v >= 0.5, u, (2.0 * v).fract()),
+        };
+        let eye = if left { &self.left } else { &self.right };
+        (eye.as_ref(), u, v)
+    }
+}
+
+impl CameraModel for StereoCamera {
+    fn generate_ray(&self, u: f64, v: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        let (eye, u, v) = self.eye(u, v);
+        eye.generate_ray(u, v, sampler)
+    }
+
+    fn generate_weighted_ray(
+        &self,
+        u: f64,
+        v: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, f64)> {
+        let (eye, u, v) = self.eye(u, v);
+        eye.generate_weighted_ray(u, v, sampler)
+    }
+}
+
+#[test]
+fn stereo_eyes_converge() {
+    use crate::sampler::IndependentSampler;
+
+    let mut sampler = IndependentSampler::new(0);
+    let center = PerspectiveCamera::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -1.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        60.0,
+        1.0,
+        0.0,
+        3.0,
+    );
+    // Both eyes see the same point at the convergence distance through any
+    // film position
+    let (left, right) = (center.eye(-0.1, 5.0), center.eye(0.1, 5.0));
+    for &(u, v) in &[(0.5, 0.5), (0.1, 0.8), (0.9, 0.3)] {
+        let mut at_convergence = |camera: &PerspectiveCamera| {
+            let ray = camera.generate_ray(u, v, &mut sampler).unwrap();
+            let t = (-5.0 - ray.origin().z()) / ray.direction().z();
+            ray.point_at_parameter(t)
+        };
+        assert!((at_convergence(&left) - at_convergence(&right)).len() < 1e-9);
+    }
+}
+
+#[test]
+fn tilted_focus_is_sharp() {
+    use crate::hitable::HitableList;
+    use crate::material::Lambertian;
+    use crate::sampler::IndependentSampler;
+    use crate::sphere::Sphere;
+
+    let mut world = HitableList::new();
+    world.push(Box::new(Sphere::new(
+        Vec3::new(0.5, 0.0, -4.0),
+        1.0,
+        Lambertian::new(Vec3::new(0.5, 0.5, 0.5)),
+    )));
+    let camera = PerspectiveCamera::new(
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -1.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        60.0,
+        1.5,
+        0.5,
+        10.0,
+    )
+    .shifted(0.1, -0.2)
+    .tilted(30.0, -10.0);
+    let u = 0.55;
+    let camera = camera.focus_on(&world, u, 0.7).unwrap();
+    // Rays through every part of the lens meet on the sphere
+    let mut sampler = IndependentSampler::new(0);
+    let mut hits = Vec::new();
+    for _ in 0..8 {
+        let ray = camera.generate_ray(u, 0.7, &mut sampler).unwrap();
+        hits.push(world.hit(&ray, 1e-3, f64::MAX).unwrap().p);
+    }
+    assert!(hits.iter().all(|&p| (p - hits[0]).len() < 1e-6));
+}
+
+#[test]
+fn physical_settings() {
+    let settings = PhysicalSettings {
+        focal_length: 50.0,
+        sensor: (36.0, 24.0),
+        f_number: 2.0,
+        shutter: 1.0 / 125.0,
+        iso: 100.0,
+    };
+    assert!((settings.vfov(1.5) - 2.0 * (12.0f64 / 50.0).atan().to_degrees()).abs() < 1e-9);
+    // A square image crops the sides of the sensor
+    assert!((settings.vfov(1.0) - settings.vfov(1.5)).abs() < 1e-9);
+    assert!((settings.aperture() - 0.025).abs() < 1e-12);
+    // Two stops more light from the shutter and two stops less from the
+    // aperture leave the exposure unchanged
+    let same = PhysicalSettings {
+        f_number: 4.0,
+        shutter: 4.0 / 125.0,
+        ..settings
+    };
+    assert!((same.exposure() / settings.exposure() - 1.0).abs() < 1e-12);
+    assert!((settings.exposure() * 1.2 * 4.0 * 125.0 - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn orthographic_rays() {
+    use crate::sampler::IndependentSampler;
+
+    let mut sampler = IndependentSampler::new(0);
+    let (look_from, look_at) = (Vec3::new(1.0, 2.0, 3.0), Vec3::new(-1.0, 0.0, 0.0));
+    let forward = Vec3::unit_vector(look_at - look_from);
+    let camera =
+        |height| OrthographicCamera::new(look_from, look_at, Vec3::new(0.0, 1.0, 0.0), height, 2.0);
+    let (small, large) = (camera(1.0), camera(3.0));
+    for &(u, v) in &[(0.0, 0.0), (0.5, 0.5), (0.2, 0.9), (1.0, 0.3)] {
+        let a = small.generate_ray(u, v, &mut sampler).unwrap();
+        let b = large.generate_ray(u, v, &mut sampler).unwrap();
+        assert!((Vec3::unit_vector(a.direction()) - forward).len() < 1e-12);
+        assert!((Vec3::unit_vector(b.direction()) - forward).len() < 1e-12);
+        // The window stays centered on the eye and grows with its height
+        let (da, db) = (a.origin() - look_from, b.origin() - look_from);
+        assert!(Vec3::dot(da, forward).abs() < 1e-12);
+        assert!((3.0 * da - db).len() < 1e-12);
+    }
+    // The corners are a height by height * aspect window apart
+    let mut corner = |u, v| small.generate_ray(u, v, &mut sampler).unwrap().origin();
+    assert!(((corner(1.0, 0.0) - corner(0.0, 0.0)).len() - 2.0).abs() < 1e-12);
+    assert!(((corner(0.0, 1.0) - corner(0.0, 0.0)).len() - 1.0).abs() < 1e-12);
+}
+
+#[test]
+fn fisheye_projections() {
+    use crate::sampler::IndependentSampler;
+
+    let mut sampler = IndependentSampler::new(0);
+    let (look_from, look_at) = (Vec3::new(0.0, 1.0, 0.0), Vec3::new(3.0, 1.0, -4.0));
+    let forward = Vec3::unit_vector(look_at - look_from);
+    for &projection in &[FisheyeProjection::Equidistant, FisheyeProjection::Equisolid] {
+        let fov = 150.0;
+        let camera = FisheyeCamera::new(
+            look_from,
+            look_at,
+            Vec3::new(0.0, 1.0, 0.0),
+            projection,
+            fov,
+            2.0,
+        );
+        let mut angle = |u, v| {
+            let ray: Ray = camera.generate_ray(u, v, &mut sampler).unwrap();
+            assert!((ray.origin() - look_from).len() < 1e-12);
+            Vec3::dot(Vec3::unit_vector(ray.direction()), forward)
+                .min(1.0)
+                .acos()
+                .to_degrees()
+        };
+        assert!(angle(0.5, 0.5) < 1e-6);
+        // The image circle spans the height, so it reaches the top and bottom
+        // edges but only the middle half of the width
+        for &(u, v) in &[(0.5, 1.0), (0.5, 0.0), (0.25, 0.5), (0.75, 0.5)] {
+            assert!((angle(u, v) - 0.5 * fov).abs() < 1e-6);
+        }
+        let half_way = match projection {
+            FisheyeProjection::Equidistant => 0.25 * fov,
+            FisheyeProjection::Equisolid => {
+                2.0 * (0.5 * (0.25 * fov).to_radians().sin()).asin().to_degrees()
+            }
+        };
+        assert!((angle(0.5, 0.75) - half_way).abs() < 1e-6);
+        for &(u, v) in &[(0.2, 0.5), (0.8, 0.5), (0.3, 0.1), (0.0, 0.0)] {
+            assert!(camera.generate_ray(u, v, &mut sampler).is_none());
+        }
+    }
+}
+
+#[test]
+fn equirectangular_wraps_around() {
+    use crate::sampler::IndependentSampler;
+
+    let mut sampler = IndependentSampler::new(0);
+    let (look_from, look_at) = (Vec3::new(1.0, 0.0, 2.0), Vec3::new(4.0, 1.0, -2.0));
+    let up = Vec3::new(0.0, 1.0, 0.0);
+    let camera = EquirectangularCamera::new(look_from, look_at, up);
+    let mut direction =
+        |u, v| Vec3::unit_vector(camera.generate_ray(u, v, &mut sampler).unwrap().direction());
+    assert!((direction(0.5, 0.5) - Vec3::unit_vector(look_at - look_from)).len() < 1e-12);
+    // Straight up and down at the top and bottom rows
+    let frame = Frame::new(look_from, look_at, up);
+    assert!((direction(0.3, 1.0) - frame.v).len() < 1e-12);
+    assert!((direction(0.7, 0.0) + frame.v).len() < 1e-12);
+    // A quarter turn to the right, and the left and right edges meet behind
+    assert!((direction(0.75, 0.5) - frame.u).len() < 1e-12);
+    for &v in &[0.1, 0.5, 0.8] {
+        assert!((direction(0.0, v) - direction(1.0, v)).len() < 1e-12);
+        assert!(Vec3::dot(direction(0.0, v), frame.w) > 0.0);
+    }
+}
+
+#[test]
+fn cubemap_faces() {
+    use crate::sampler::IndependentSampler;
+
+    let mut sampler = IndependentSampler::new(0);
+    let (look_from, look_at) = (Vec3::new(1.0, 2.0, 3.0), Vec3::new(-2.0, 0.5, 1.0));
+    let up = Vec3::new(0.0, 1.0, 0.0);
+    let camera = CubemapCamera::new(look_from, look_at, up);
+    let frame = Frame::new(look_from, look_at, up);
+    let axes = [
+        frame.local(1.0, 0.0, 0.0),
+        frame.local(-1.0, 0.0, 0.0),
+        frame.local(0.0, 1.0, 0.0),
+        frame.local(0.0, -1.0, 0.0),
+        frame.local(0.0, 0.0, 1.0),
+        frame.local(0.0, 0.0, -1.0),
+    ];
+    // Direction through (s, t) in [-1, 1] on a face
+    let mut ray = |face: usize, s: f64, t: f64| {
+        let u = (face as f64 + 0.5 * (s + 1.0)) / 6.0;
+        camera
+            .generate_ray(u, 0.5 * (t + 1.0), &mut sampler)
+            .unwrap()
+            .direction()
+    };
+    let mut faces = Vec::new();
+    for (face, &axis) in axes.iter().enumerate() {
+        let forward = ray(face, 0.0, 0.0);
+        let right = 2.0 * (ray(face, 0.5, 0.0) - forward);
+        let up = 2.0 * (ray(face, 0.0, 0.5) - forward);
+        assert!((forward - axis).len() < 1e-12);
+        // 90 degree faces, seen from inside and so not mirrored
+        assert!((right.len() - 1.0).abs() < 1e-12 && (up.len() - 1.0).abs() < 1e-12);
+        assert!((Vec3::cross(right, up) + forward).len() < 1e-12);
+        faces.push((forward, right, up));
+    }
+    // Every point on the edge of a face is also on the edge of its neighbor
+    // across that edge, and both faces see the same direction there
+    let inside = 1.0 - 1e-9;
+    for face in 0..6 {
+        for &a in &[-0.5, 0.0, 0.5] {
+            for &(s, t) in &[(inside, a), (-inside, a), (a, inside), (a, -inside)] {
+                let d = Vec3::unit_vector(ray(face, s, t));
+                let other = (0..6)
+                    .filter(|&other| other != face)
+                    .max_by(|&i, &j| {
+                        Vec3::dot(d, axes[i])
+                            .partial_cmp(&Vec3::dot(d, axes[j]))
+                            .unwrap()
+                    })
+                    .unwrap();
+                let (forward, right, up) = faces[other];
+                let s = Vec3::dot(d, right) / Vec3::dot(d, forward);
+                let t = Vec3::dot(d, up) / Vec3::dot(d, forward);
+                assert!(s.abs().max(t.abs()) > 1.0 - 1e-6 && s.abs().max(t.abs()) < 1.0 + 1e-6);
+                let (s, t) = (s.max(-inside).min(inside), t.max(-inside).min(inside));
+                assert!((Vec3::unit_vector(ray(other, s, t)) - d).len() < 1e-6);
+            }
+        }
+    }
 }
@@ -0,0 +1,48 @@
+use std::io::{self, Read, Write};
+
+// Little helpers for the checkpoint format. Floats are stored as their exact
+// bit patterns so a resumed render continues from precisely the same state.
+
+pub const MAGIC: &[u8; 8] = b"RTCKPT02";
+
+pub fn write_u64(out: &mut dyn Write, v: u64) -> io::Result<()> {
+    out.write_all(&v.to_le_bytes())
+}
+
+pub fn write_f64(out: &mut dyn Write, v: f64) -> io::Result<()> {
+    write_u64(out, v.to_bits())
+}
+
+pub fn write_str(out: &mut dyn Write, s: &str) -> io::Result<()> {
+    write_u64(out, s.len() as u64)?;
+    out.write_all(s.as_bytes())
+}
+
+pub fn read_u64(input: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f64(input: &mut dyn Read) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(input)?))
+}
+
+pub fn read_str(input: &mut dyn Read) -> io::Result<String> {
+    let len = read_u64(input)?;
+    let mut bytes = Vec::new();
+    input.take(len).read_to_end(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|err| invalid(&err.to_string()))
+}
+
+// 64-bit FNV-1a, which unlike std's hashers is fixed, so keys made from it
+// stay valid across builds
+pub fn fingerprint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
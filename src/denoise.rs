@@ -0,0 +1,308 @@
+use crate::image::Image;
+use crate::vec3::Vec3;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DenoiserKind {
+    None,
+    Atrous,
+    Bilateral,
+}
+
+impl FromStr for DenoiserKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(DenoiserKind::None),
+            "atrous" => Ok(DenoiserKind::Atrous),
+            "bilateral" => Ok(DenoiserKind::Bilateral),
+            _ => Err(()),
+        }
+    }
+}
+
+// Per-pixel buffers the filters use to find edges. `variance` is the
+// variance of each pixel's mean luminance, indexed like the image.
+pub struct Guides<'a> {
+    pub albedo: &'a Image,
+    pub normal: &'a Image,
+    pub variance: &'a [f64],
+}
+
+// Luminance edge stopping, in standard deviations of the center pixel
+const SIGMA_LUMINANCE: f64 = 2.0;
+const SIGMA_ALBEDO: f64 = 0.1;
+const NORMAL_POWER: i32 = 64;
+// Stand-in for the variance of pixels with too few samples to estimate it
+const UNKNOWN_VARIANCE: f64 = 1e10;
+const MIN_ALBEDO: f64 = 1e-3;
+
+const ATROUS_ITERATIONS: usize = 5;
+const ATROUS_KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+const BILATERAL_RADIUS: isize = 6;
+
+// Illumination (color divided by albedo) and its variance. Filtering it
+// instead of the color keeps texture and material detail sharp.
+struct Layer {
+    illumination: Vec<Vec3>,
+    variance: Vec<f64>,
+}
+
+// Albedo to divide by; pixels that hit nothing are left as they are
+fn demodulation_albedo(albedo: Vec3) -> Vec3 {
+    if albedo.max_component() <= 0.0 {
+        return Vec3::new(1.0, 1.0, 1.0);
+    }
+    Vec3::new(
+        albedo.r().max(MIN_ALBEDO),
+        albedo.g().max(MIN_ALBEDO),
+        albedo.b().max(MIN_ALBEDO),
+    )
+}
+
+// Weight for blending pixel `q` into pixel `p` from the normal and albedo
+// guides alone
+fn geometry_weight(guides: &Guides, p: usize, q: usize) -> f64 {
+    let (np, nq) = (guides.normal.pixels()[p], guides.normal.pixels()[q]);
+    // Normals are averaged over the pixel, and are zero where nothing was hit
+    let normal = match (np.squared_len() > 1e-12, nq.squared_len() > 1e-12) {
+        (false, false) => 1.0,
+        (true, true) => Vec3::dot(Vec3::unit_vector(np), Vec3::unit_vector(nq))
+            .max(0.0)
+            .powi(NORMAL_POWER),
+        _ => 0.0,
+    };
+    let (ap, aq) = (guides.albedo.pixels()[p], guides.albedo.pixels()[q]);
+    normal * (-(ap - aq).squared_len() / (SIGMA_ALBEDO * SIGMA_ALBEDO)).exp()
+}
+
+// 3x3 Gaussian blur of the variance around (i, j), which steadies the
+// luminance edge stopping when there are only a few samples per pixel
+fn prefiltered_variance(layer: &Layer, width: isize, height: isize, i: isize, j: isize) -> f64 {
+    let kernel = [0.25, 0.5, 0.25];
+    let (mut sum, mut weight_sum) = (0.0, 0.0);
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let (x, y) = (i + dx, j + dy);
+            if x < 0 || y < 0 || x >= width || y >= height {
+                continue;
+            }
+            let weight = kernel[(dx + 1) as usize] * kernel[(dy + 1) as usize];
+            sum += weight * layer.variance[(y * width + x) as usize];
+            weight_sum += weight;
+        }
+    }
+    sum / weight_sum
+}
+
+// One edge-aware filter pass over `taps`, given as pixel offsets with their
+// spatial weights. The (0, 0) tap must be present.
+fn filter(layer: &Layer, guides: &Guides, taps: &[(isize, isize, f64)]) -> Layer {
+    let (width, height) = (
+        guides.albedo.width() as isize,
+        guides.albedo.height() as isize,
+    );
+    let mut out = Layer {
+        illumination: Vec::with_capacity(layer.illumination.len()),
+        variance: Vec::with_capacity(layer.variance.len()),
+    };
+    for j in 0..height {
+        for i in 0..width {
+            let p = (j * width + i) as usize;
+            let luminance = layer.illumination[p].luminance();
+            let sigma =
+                SIGMA_LUMINANCE * prefiltered_variance(layer, width, height, i, j).sqrt() + 1e-10;
+            let (mut sum, mut variance_sum, mut weight_sum) = (Vec3::default(), 0.0, 0.0);
+            for &(dx, dy, spatial) in taps {
+                let (x, y) = (i + dx, j + dy);
+                if x < 0 || y < 0 || x >= width || y >= height {
+                    continue;
+                }
+                let q = (y * width + x) as usize;
+                let color = (-(luminance - layer.illumination[q].luminance()).abs() / sigma).exp();
+                let weight = spatial * color * geometry_weight(guides, p, q);
+                sum += weight * layer.illumination[q];
+                variance_sum += weight * weight * layer.variance[q];
+                weight_sum += weight;
+            }
+            out.illumination.push(sum / weight_sum);
+            out.variance.push(variance_sum / (weight_sum * weight_sum));
+        }
+    }
+    out
+}
+
+// Edge-avoiding à-trous wavelet filter as in SVGF: a 5x5 B-spline kernel
+// whose taps spread twice as far apart on each iteration
+fn atrous(mut layer: Layer, guides: &Guides) -> Layer {
+    for iteration in 0..ATROUS_ITERATIONS {
+        let step = 1 << iteration;
+        let mut taps = Vec::with_capacity(25);
+        for (y, ky) in ATROUS_KERNEL.iter().enumerate() {
+            for (x, kx) in ATROUS_KERNEL.iter().enumerate() {
+                taps.push(((x as isize - 2) * step, (y as isize - 2) * step, kx * ky));
+            }
+        }
+        layer = filter(&layer, guides, &taps);
+    }
+    layer
+}
+
+// Single pass joint bilateral filter with a Gaussian spatial falloff
+fn bilateral(layer: Layer, guides: &Guides) -> Layer {
+    let sigma = BILATERAL_RADIUS as f64 / 2.0;
+    let mut taps = Vec::new();
+    for y in -BILATERAL_RADIUS..=BILATERAL_RADIUS {
+        for x in -BILATERAL_RADIUS..=BILATERAL_RADIUS {
+            let d2 = (x * x + y * y) as f64;
+            taps.push((x, y, (-d2 / (2.0 * sigma * sigma)).exp()));
+        }
+    }
+    filter(&layer, guides, &taps)
+}
+
+pub fn denoise(kind: DenoiserKind, color: &Image, guides: &Guides) -> Image {
+    let albedo: Vec<Vec3> = guides
+        .albedo
+        .pixels()
+        .iter()
+        .map(|&a| demodulation_albedo(a))
+        .collect();
+    let layer = Layer {
+        illumination: color
+            .pixels()
+            .iter()
+            .zip(&albedo)
+            .map(|(&c, &a)| c / a)
+            .collect(),
+        variance: guides
+            .variance
+            .iter()
+            .zip(&albedo)
+            .map(|(&v, &a)| {
+                let v = if v.is_finite() { v } else { UNKNOWN_VARIANCE };
+                v / a.luminance().powi(2)
+            })
+            .collect(),
+    };
+    let layer = match kind {
+        DenoiserKind::None => return color.clone(),
+        DenoiserKind::Atrous => atrous(layer, guides),
+        DenoiserKind::Bilateral => bilateral(layer, guides),
+    };
+    let mut out = Image::new(color.width(), color.height());
+    for j in 0..color.height() {
+        for i in 0..color.width() {
+            let p = j * color.width() + i;
+            out.set(i, j, layer.illumination[p] * albedo[p]);
+        }
+    }
+    out
+}
+
+// Renders a small scene with `samples` per pixel, returning the color, the
+// guide buffers and the per-pixel variance
+#[cfg(test)]
+fn render_test_scene(samples: usize, seed: u64) -> (Image, Image, Image, Vec<f64>) {
+    use crate::aov::{AovBuffers, AovKind};
+    use crate::camera::{CameraModel, PerspectiveCamera};
+    use crate::film::{Film, Filter, FilterKind};
+    use crate::hitable::HitableList;
+    use crate::integrator::PathTracer;
+    use crate::light::Lights;
+    use crate::light_sampler::LightSamplerKind;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::render::{Renderer, SampleSettings};
+    use crate::sampler::{new_sampler, SamplerKind};
+    use crate::sphere::Sphere;
+
+    let (width, height) = (64, 48);
+    let mut world = HitableList::new();
+    let mut lights = Lights::new();
+    world.push(Box::new(Sphere::new(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Lambertian::new(Vec3::new(0.5, 0.5, 0.5)),
+    )));
+    world.push(Box::new(Sphere::new(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Lambertian::new(Vec3::new(0.7, 0.2, 0.1)),
+    )));
+    world.push(Box::new(Sphere::new(
+        Vec3::new(-2.2, 0.7, 0.8),
+        0.7,
+        Lambertian::new(Vec3::new(0.2, 0.4, 0.8)),
+    )));
+    let lamp = Sphere::new(
+        Vec3::new(1.0, 6.0, 4.0),
+        0.5,
+        DiffuseLight::new(Vec3::new(10.0, 9.0, 8.0)),
+    );
+    lights.add_area(Box::new(lamp.clone()), world.len());
+    world.push(Box::new(lamp));
+    lights.set_sampler(LightSamplerKind::Bvh);
+    let camera = PerspectiveCamera::new(
+        Vec3::new(5.0, 2.5, 6.0),
+        Vec3::new(0.0, 0.8, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        40.0,
+        width as f64 / height as f64,
+        0.0,
+        8.0,
+    );
+    let integrator = PathTracer::new(3, 8);
+    let settings = SampleSettings {
+        samples,
+        min_samples: samples,
+        max_samples: samples,
+        noise_threshold: 0.0,
+        pass_samples: samples,
+    };
+    let film = Film::new(width, height, Filter::new(FilterKind::Box, None));
+    let mut renderer = Renderer::new(film, settings);
+    let mut aovs = AovBuffers::new(&[AovKind::Albedo, AovKind::Normal], width, height);
+    let mut sampler = new_sampler(SamplerKind::Sobol, samples, seed);
+    let mut sample = |i: usize, j: usize, index: usize| {
+        sampler.start_pixel_sample((i, j), index);
+        let (du, dv) = sampler.get_2d();
+        let (x, y) = (i as f64 + du, j as f64 + dv);
+        let r = camera
+            .generate_ray(x / width as f64, y / height as f64, sampler.as_mut())
+            .unwrap();
+        let (col, first_hit) = integrator.color(&r, &world, &lights, sampler.as_mut());
+        aovs.add(i, j, first_hit);
+        ((x, y), col)
+    };
+    while renderer.render_pass(&mut sample) {}
+    let variance = renderer.pixels().iter().map(|p| p.variance()).collect();
+    (
+        Image::from_film(renderer.film()),
+        aovs.image(AovKind::Albedo).unwrap(),
+        aovs.image(AovKind::Normal).unwrap(),
+        variance,
+    )
+}
+
+#[test]
+fn denoising_reduces_error() {
+    let (reference, _, _, _) = render_test_scene(256, 2);
+    let (noisy, albedo, normal, variance) = render_test_scene(8, 1);
+    let guides = Guides {
+        albedo: &albedo,
+        normal: &normal,
+        variance: &variance,
+    };
+    let noisy_mse = noisy.mse(&reference);
+    for &kind in &[DenoiserKind::Atrous, DenoiserKind::Bilateral] {
+        let mse = denoise(kind, &noisy, &guides).mse(&reference);
+        assert!(
+            mse < 0.75 * noisy_mse,
+            "{:?}: mse {} against {} before denoising",
+            kind,
+            mse,
+            noisy_mse
+        );
+    }
+}
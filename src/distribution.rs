@@ -0,0 +1,124 @@
+// Piecewise constant distribution over [0, 1) with one segment per value of
+// `func`. If every value is zero it falls back to a uniform distribution.
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    func: Vec<f64>,
+    cdf: Vec<f64>,
+    integral: f64,
+}
+
+impl Distribution1D {
+    pub fn new(func: &[f64]) -> Distribution1D {
+        assert!(!func.is_empty());
+        let n = func.len();
+        let func: Vec<f64> = func.iter().map(|f| f.abs()).collect();
+        let mut cdf = vec![0.0; n + 1];
+        for i in 0..n {
+            cdf[i + 1] = cdf[i] + func[i] / n as f64;
+        }
+        let integral = cdf[n];
+        for (i, c) in cdf.iter_mut().enumerate() {
+            *c = if integral > 0.0 {
+                *c / integral
+            } else {
+                i as f64 / n as f64
+            };
+        }
+        Distribution1D {
+            func,
+            cdf,
+            integral,
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.func.len()
+    }
+
+    // Average of `func`
+    pub fn integral(&self) -> f64 {
+        self.integral
+    }
+
+    // Returns the sampled position, its density and the segment it is in
+    pub fn sample(&self, u: f64) -> (f64, f64, usize) {
+        // Last segment starting at or below u, which skips empty segments
+        let index = (self.cdf.partition_point(|&c| c <= u).max(1) - 1).min(self.count() - 1);
+        let (c0, c1) = (self.cdf[index], self.cdf[index + 1]);
+        let du = if c1 > c0 { (u - c0) / (c1 - c0) } else { 0.0 };
+        let x = (index as f64 + du) / self.count() as f64;
+        (x.min(1.0 - f64::EPSILON), self.pdf_segment(index), index)
+    }
+
+    // Probability of `sample` landing in segment `index`
+    pub fn pmf(&self, index: usize) -> f64 {
+        self.pdf_segment(index) / self.count() as f64
+    }
+
+    fn pdf_segment(&self, index: usize) -> f64 {
+        if self.integral > 0.0 {
+            self.func[index] / self.integral
+        } else {
+            1.0
+        }
+    }
+}
+
+// Piecewise constant distribution over [0, 1)^2 given `width` by `height`
+// values, row by row
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    conditional: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+
+impl Distribution2D {
+    pub fn new(func: &[f64], width: usize, height: usize) -> Distribution2D {
+        assert_eq!(func.len(), width * height);
+        let conditional: Vec<Distribution1D> =
+            func.chunks(width).map(Distribution1D::new).collect();
+        let marginal: Vec<f64> = conditional.iter().map(|d| d.integral()).collect();
+        Distribution2D {
+            conditional,
+            marginal: Distribution1D::new(&marginal),
+        }
+    }
+
+    // Returns the sampled point and its density
+    pub fn sample(&self, u: (f64, f64)) -> ((f64, f64), f64) {
+        let (y, pdf_y, row) = self.marginal.sample(u.1);
+        let (x, pdf_x, _) = self.conditional[row].sample(u.0);
+        ((x, y), pdf_x * pdf_y)
+    }
+
+    // Density of `sample` returning `p`
+    pub fn pdf(&self, p: (f64, f64)) -> f64 {
+        let row = ((p.1 * self.marginal.count() as f64) as usize).min(self.marginal.count() - 1);
+        let conditional = &self.conditional[row];
+        let col = ((p.0 * conditional.count() as f64) as usize).min(conditional.count() - 1);
+        self.marginal.pdf_segment(row) * conditional.pdf_segment(col)
+    }
+}
+
+#[test]
+fn distribution_2d_sampling_matches_pdf() {
+    let func = [0.0, 1.0, 2.0, 3.0, 0.5, 0.0, 4.0, 1.0];
+    let distribution = Distribution2D::new(&func, 4, 2);
+    let mut counts = [0usize; 8];
+    let n = 64;
+    for i in 0..n {
+        for j in 0..n {
+            let u = ((i as f64 + 0.5) / n as f64, (j as f64 + 0.5) / n as f64);
+            let ((x, y), pdf) = distribution.sample(u);
+            let index = (y * 2.0) as usize * 4 + (x * 4.0) as usize;
+            // Densities are relative to the average value
+            assert!((pdf - func[index] * 8.0 / 11.5).abs() < 1e-12);
+            counts[index] += 1;
+        }
+    }
+    let total: f64 = func.iter().sum();
+    for (count, f) in counts.iter().zip(&func) {
+        let expected = f / total;
+        assert!((*count as f64 / (n * n) as f64 - expected).abs() < 0.01);
+    }
+}
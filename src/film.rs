@@ -0,0 +1,257 @@
+use crate::checkpoint::{read_f64, write_f64};
+use crate::vec3::Vec3;
+use std::f64::consts::PI;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterKind {
+    Box,
+    Tent,
+    Gaussian,
+    Mitchell,
+    Lanczos,
+}
+
+impl FromStr for FilterKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(FilterKind::Box),
+            "tent" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "lanczos" => Ok(FilterKind::Lanczos),
+            _ => Err(()),
+        }
+    }
+}
+
+// Separable pixel reconstruction filter, `radius` in pixels
+#[derive(Copy, Clone, Debug)]
+pub struct Filter {
+    kind: FilterKind,
+    radius: f64,
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        return 1.0;
+    }
+    (PI * x).sin() / (PI * x)
+}
+
+// Mitchell-Netravali cubic with B = C = 1/3, defined on [-2, 2]
+fn mitchell_1d(x: f64) -> f64 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+            + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+            + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x.powi(3)
+            + (6.0 * b + 30.0 * c) * x.powi(2)
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+impl Filter {
+    // `radius` defaults to a customary size for each kind
+    pub fn new(kind: FilterKind, radius: Option<f64>) -> Filter {
+        let radius = radius.unwrap_or(match kind {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.0,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell => 2.0,
+            FilterKind::Lanczos => 3.0,
+        });
+        Filter { kind, radius }
+    }
+
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let r = self.radius;
+        if x.abs() > r {
+            return 0.0;
+        }
+        match self.kind {
+            FilterKind::Box => 1.0,
+            FilterKind::Tent => r - x.abs(),
+            FilterKind::Gaussian => {
+                let sigma = r / 3.0;
+                let gaussian = |x: f64| (-x * x / (2.0 * sigma * sigma)).exp();
+                gaussian(x) - gaussian(r)
+            }
+            FilterKind::Mitchell => mitchell_1d(2.0 * x / r),
+            FilterKind::Lanczos => sinc(x) * sinc(x / r),
+        }
+    }
+
+    pub fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+}
+
+// Accumulates filter weighted samples. Each sample is splatted into every
+// pixel whose center lies within the filter radius, and pixels are
+// normalized by their summed weights when read. Filters with negative lobes
+// can bring that sum close to zero in sparsely sampled pixels, so the sums
+// over positive weights alone are kept as a fallback.
+pub struct Film {
+    width: usize,
+    height: usize,
+    filter: Filter,
+    weighted_sum: Vec<Vec3>,
+    weight_sum: Vec<f64>,
+    positive_sum: Vec<Vec3>,
+    positive_weight: Vec<f64>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Film {
+        Film {
+            width,
+            height,
+            filter,
+            weighted_sum: vec![Vec3::default(); width * height],
+            weight_sum: vec![0.0; width * height],
+            positive_sum: vec![Vec3::default(); width * height],
+            positive_weight: vec![0.0; width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // `x` and `y` are continuous pixel coordinates; pixel (i, j) covers
+    // [i, i + 1) x [j, j + 1) with rows counted from the bottom
+    pub fn add_sample(&mut self, x: f64, y: f64, col: Vec3) {
+        let r = self.filter.radius;
+        let x0 = (x - 0.5 - r).floor().max(0.0) as usize;
+        let y0 = (y - 0.5 - r).floor().max(0.0) as usize;
+        let x1 = ((x - 0.5 + r).ceil().max(0.0) as usize).min(self.width - 1);
+        let y1 = ((y - 0.5 + r).ceil().max(0.0) as usize).min(self.height - 1);
+        for j in y0..=y1 {
+            for i in x0..=x1 {
+                let weight = self
+                    .filter
+                    .evaluate(x - (i as f64 + 0.5), y - (j as f64 + 0.5));
+                let index = j * self.width + i;
+                if weight != 0.0 {
+                    self.weighted_sum[index] += weight * col;
+                    self.weight_sum[index] += weight;
+                }
+                if weight > 0.0 {
+                    self.positive_sum[index] += weight * col;
+                    self.positive_weight[index] += weight;
+                }
+            }
+        }
+    }
+
+    // The filter weighted mean, unless negative weights cancel out more than
+    // half of the total weight; then the mean over positive weights, which
+    // can't blow up
+    pub fn pixel(&self, i: usize, j: usize) -> Vec3 {
+        let index = j * self.width + i;
+        let (weight, positive) = (self.weight_sum[index], self.positive_weight[index]);
+        let total = 2.0 * positive - weight;
+        if total == 0.0 {
+            return Vec3::default();
+        }
+        if weight.abs() >= 0.5 * total {
+            self.weighted_sum[index] / weight
+        } else {
+            self.positive_sum[index] / positive
+        }
+    }
+
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        for index in 0..self.weight_sum.len() {
+            for &col in &[self.weighted_sum[index], self.positive_sum[index]] {
+                write_f64(out, col.r())?;
+                write_f64(out, col.g())?;
+                write_f64(out, col.b())?;
+            }
+            write_f64(out, self.weight_sum[index])?;
+            write_f64(out, self.positive_weight[index])?;
+        }
+        Ok(())
+    }
+
+    pub fn load(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        let read_col = |input: &mut dyn Read| -> io::Result<Vec3> {
+            Ok(Vec3::new(
+                read_f64(input)?,
+                read_f64(input)?,
+                read_f64(input)?,
+            ))
+        };
+        for index in 0..self.weight_sum.len() {
+            self.weighted_sum[index] = read_col(input)?;
+            self.positive_sum[index] = read_col(input)?;
+            self.weight_sum[index] = read_f64(input)?;
+            self.positive_weight[index] = read_f64(input)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn film_reconstruction() {
+    use crate::sampler::{IndependentSampler, Sampler};
+
+    let kinds = [
+        FilterKind::Box,
+        FilterKind::Tent,
+        FilterKind::Gaussian,
+        FilterKind::Mitchell,
+        FilterKind::Lanczos,
+    ];
+    // A constant image comes back constant, however few samples each pixel
+    // has
+    let c = Vec3::new(0.25, 0.5, 1.0);
+    for &kind in &kinds {
+        for &n in &[1, 16] {
+            let mut film = Film::new(6, 4, Filter::new(kind, None));
+            let mut sampler = IndependentSampler::new(0);
+            for index in 0..6 * 4 * n {
+                sampler.start_pixel_sample((0, 0), index);
+                let (u, v) = sampler.get_2d();
+                let pixel = index / n;
+                film.add_sample((pixel % 6) as f64 + u, (pixel / 6) as f64 + v, c);
+            }
+            for j in 0..4 {
+                for i in 0..6 {
+                    let p = film.pixel(i, j);
+                    assert!((p - c).len() < 1e-9, "{:?}: {} at ({}, {})", kind, p, i, j);
+                }
+            }
+        }
+    }
+
+    // A white sample at a pixel's center and black ones in Mitchell's
+    // negative lobe, whose weights nearly cancel the white one's
+    let mut film = Film::new(5, 5, Filter::new(FilterKind::Mitchell, None));
+    film.add_sample(2.5, 2.5, Vec3::new(1.0, 1.0, 1.0));
+    for _ in 0..25 {
+        film.add_sample(4.0, 2.5, Vec3::default());
+    }
+    let p = film.pixel(2, 2).r();
+    assert!((0.0..=1.0).contains(&p), "{}", p);
+    // Only reached by a negative lobe
+    let mut film = Film::new(5, 5, Filter::new(FilterKind::Mitchell, None));
+    film.add_sample(4.0, 2.5, c);
+    assert!((film.pixel(2, 2) - c).len() < 1e-9);
+}
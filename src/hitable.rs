@@ -1,37 +1,67 @@
-use crate::material::{Lambertian, Material};
+use crate::light_sampler::LightBounds;
+use crate::material::Material;
 use crate::ray::Ray;
+use crate::sampler::remap;
 use crate::vec3::Vec3;
-use std::rc::Rc;
-#[derive(Clone)]
-pub struct HitRecord {
+
+// Borrows the material from the object that was hit, so producing a record
+// never allocates. `object_id` is the index of the hit object in the
+// outermost HitableList; primitives leave it at zero. `uv` locates the hit
+// on the surface for texture lookups, each coordinate in [0, 1].
+#[derive(Copy, Clone)]
+pub struct HitRecord<'a> {
     pub t: f64,
     pub p: Vec3,
     pub normal: Vec3,
-    pub material: Rc<dyn Material>,
+    pub uv: (f64, f64),
+    pub material: &'a dyn Material,
+    pub object_id: usize,
 }
 
-impl HitRecord {
-    pub fn new() -> HitRecord {
-        HitRecord {
-            t: Default::default(),
-            p: Default::default(),
-            normal: Default::default(),
-            material: Lambertian::new(Vec3::new(0.0, 0.0, 0.0)),
-        }
-    }
+// A point sampled on a light as seen from some shading point, with the
+// surface normal there. `pdf` is with respect to solid angle at that
+// shading point, and `two_sided` lights also emit from the back.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct LightSample {
+    pub p: Vec3,
+    pub normal: Vec3,
+    pub pdf: f64,
+    pub two_sided: bool,
 }
-impl Default for HitRecord {
-    fn default() -> Self {
-        Self::new()
+
+// Converts a density over the area of a surface around `p` to one over solid
+// angle as seen from `origin`
+pub fn area_to_solid_angle(pdf: f64, origin: Vec3, p: Vec3, normal: Vec3) -> f64 {
+    let to_origin = origin - p;
+    let dist_squared = to_origin.squared_len();
+    let cos_theta = Vec3::dot(normal, to_origin).abs() / dist_squared.sqrt();
+    if cos_theta == 0.0 {
+        return 0.0;
     }
+    pdf * dist_squared / cos_theta
 }
 
 pub trait Hitable {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool;
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>>;
+
+    // Samples a point on the surface visible from `origin`, for use as a light
+    fn sample(&self, _origin: Vec3, _u: (f64, f64)) -> Option<LightSample> {
+        None
+    }
+
+    // Solid angle density of `sample` generating `direction` from `origin`
+    fn pdf(&self, _origin: Vec3, _direction: Vec3) -> f64 {
+        0.0
+    }
+
+    // Where and which way the object emits, for choosing between lights
+    fn light_bounds(&self) -> Option<LightBounds> {
+        None
+    }
 }
 
 pub struct HitableList {
-    hit_list: Vec<Box<Hitable>>,
+    hit_list: Vec<Box<dyn Hitable>>,
 }
 
 impl HitableList {
@@ -41,26 +71,216 @@ impl HitableList {
         }
     }
 
-    pub fn push(&mut self, hitable: Box<Hitable>) {
+    pub fn push(&mut self, hitable: Box<dyn Hitable>) {
         self.hit_list.push(hitable)
     }
+
+    pub fn len(&self) -> usize {
+        self.hit_list.len()
+    }
+}
+
+impl Default for HitableList {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Hitable for HitableList {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
-        let mut temp_rec: HitRecord = Default::default();
-        let mut hit_anything = false;
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let mut closest = None;
         let mut closest_so_far = t_max;
-        for i in &self.hit_list {
-            if i.hit(ray, t_min, closest_so_far, &mut temp_rec) {
-                hit_anything = true;
-                closest_so_far = temp_rec.t;
-                rec.p = temp_rec.p;
-                rec.normal = temp_rec.normal;
-                rec.t = temp_rec.t;
-                rec.material = temp_rec.material.clone();
+        for (id, i) in self.hit_list.iter().enumerate() {
+            if let Some(mut rec) = i.hit(ray, t_min, closest_so_far) {
+                closest_so_far = rec.t;
+                rec.object_id = id;
+                closest = Some(rec);
             }
         }
-        hit_anything
+        closest
+    }
+
+    // Picks one member uniformly; the returned pdf accounts for every member
+    // that could have produced the same direction.
+    fn sample(&self, origin: Vec3, u: (f64, f64)) -> Option<LightSample> {
+        if self.hit_list.is_empty() {
+            return None;
+        }
+        // The first dimension picks the member and is then reused
+        let n = self.hit_list.len();
+        let i = ((u.0 * n as f64) as usize).min(n - 1);
+        let u0 = remap(u.0 * n as f64, i as f64, 1.0);
+        let mut sample = self.hit_list[i].sample(origin, (u0, u.1))?;
+        sample.pdf = self.pdf(origin, sample.p - origin);
+        Some(sample)
+    }
+
+    fn pdf(&self, origin: Vec3, direction: Vec3) -> f64 {
+        if self.hit_list.is_empty() {
+            return 0.0;
+        }
+        let sum: f64 = self.hit_list.iter().map(|h| h.pdf(origin, direction)).sum();
+        sum / self.hit_list.len() as f64
+    }
+}
+
+// Run with `cargo test --release hit_benchmark -- --ignored --nocapture`.
+// Times the closest hit against the API from before hit returned an
+// Option, which is kept here as a baseline.
+#[test]
+#[ignore]
+fn hit_benchmark() {
+    use crate::material::Lambertian;
+    use crate::sampler::{sample_uniform_sphere, IndependentSampler, Sampler};
+    use crate::sphere::{sphere_uv, Sphere};
+    use crate::stats;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    // The old records were filled in through an out-parameter and held their
+    // own reference to the material, so every fresh one started out with a
+    // dummy material allocation. They get the uv that records have gained
+    // since, so both sides do the same work.
+    struct OldHitRecord {
+        t: f64,
+        p: Vec3,
+        normal: Vec3,
+        uv: (f64, f64),
+        material: Rc<dyn Material>,
+    }
+
+    impl OldHitRecord {
+        fn new() -> OldHitRecord {
+            OldHitRecord {
+                t: Default::default(),
+                p: Default::default(),
+                normal: Default::default(),
+                uv: Default::default(),
+                material: Lambertian::new(Vec3::new(0.0, 0.0, 0.0)),
+            }
+        }
+    }
+
+    trait OldHitable {
+        fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut OldHitRecord) -> bool;
+    }
+
+    struct OldSphere {
+        center: Vec3,
+        radius: f64,
+        material: Rc<dyn Material>,
+    }
+
+    impl OldHitable for OldSphere {
+        fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut OldHitRecord) -> bool {
+            stats::intersection_test();
+            let oc = ray.origin() - self.center;
+            let a = Vec3::dot(ray.direction(), ray.direction());
+            let b = Vec3::dot(oc, ray.direction());
+            let c = Vec3::dot(oc, oc) - self.radius * self.radius;
+            let discriminant = b * b - a * c;
+            if discriminant > 0.0 {
+                for &temp in &[
+                    (-b - discriminant.sqrt()) / a,
+                    (-b + discriminant.sqrt()) / a,
+                ] {
+                    if temp < t_max && temp > t_min {
+                        rec.t = temp;
+                        rec.p = ray.point_at_parameter(rec.t);
+                        rec.normal = (rec.p - self.center) / self.radius;
+                        rec.uv = sphere_uv(rec.normal);
+                        rec.material = self.material.clone();
+                        return true;
+                    }
+                }
+            }
+            false
+        }
+    }
+
+    struct OldHitableList {
+        hit_list: Vec<Box<dyn OldHitable>>,
+    }
+
+    impl OldHitable for OldHitableList {
+        fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut OldHitRecord) -> bool {
+            let mut temp_rec = OldHitRecord::new();
+            let mut hit_anything = false;
+            let mut closest_so_far = t_max;
+            for i in &self.hit_list {
+                if i.hit(ray, t_min, closest_so_far, &mut temp_rec) {
+                    hit_anything = true;
+                    closest_so_far = temp_rec.t;
+                    rec.p = temp_rec.p;
+                    rec.normal = temp_rec.normal;
+                    rec.uv = temp_rec.uv;
+                    rec.t = temp_rec.t;
+                    rec.material = temp_rec.material.clone();
+                }
+            }
+            hit_anything
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut sampler = IndependentSampler::new(7);
+    let n = 200_000;
+    let directions: Vec<Vec3> = (0..n)
+        .map(|i| {
+            sampler.start_pixel_sample((0, 0), i);
+            let (x, y, z) = sample_uniform_sphere(sampler.get_2d());
+            Vec3::new(x, y, z)
+        })
+        .collect();
+    for &count in &[4, 500] {
+        let mut world = HitableList::new();
+        let mut old_world = OldHitableList {
+            hit_list: Vec::new(),
+        };
+        for _ in 0..count {
+            let center = Vec3::new(
+                20.0 * rng.gen::<f64>() - 10.0,
+                20.0 * rng.gen::<f64>() - 10.0,
+                20.0 * rng.gen::<f64>() - 10.0,
+            );
+            let radius = if count < 10 { 4.0 } else { 0.3 };
+            world.push(Box::new(Sphere::new(
+                center,
+                radius,
+                Lambertian::new(Vec3::new(0.5, 0.5, 0.5)),
+            )));
+            old_world.hit_list.push(Box::new(OldSphere {
+                center,
+                radius,
+                material: Lambertian::new(Vec3::new(0.5, 0.5, 0.5)),
+            }));
+        }
+        let time = |hit: &dyn Fn(&Ray) -> bool| {
+            let start = Instant::now();
+            let hits = directions
+                .iter()
+                .filter(|&&dir| hit(&Ray::new(Vec3::default(), dir)))
+                .count();
+            (hits, start.elapsed())
+        };
+        let (old_hits, old_elapsed) = time(&|ray| {
+            let mut rec = OldHitRecord::new();
+            old_world.hit(ray, 0.001, f64::MAX, &mut rec)
+        });
+        let (hits, elapsed) = time(&|ray| world.hit(ray, 0.001, f64::MAX).is_some());
+        assert_eq!(hits, old_hits);
+        let per_ray = |elapsed: Duration| elapsed.as_nanos() as f64 / n as f64;
+        println!(
+            "{} spheres: {} rays, {} hits in {:?} ({:.1} ns/ray), {:?} ({:.1} ns/ray) before",
+            count,
+            n,
+            hits,
+            elapsed,
+            per_ray(elapsed),
+            old_elapsed,
+            per_ray(old_elapsed)
+        );
     }
 }
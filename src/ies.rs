@@ -0,0 +1,294 @@
+use crate::vec3::Vec3;
+use std::f64::consts::PI;
+
+// Luminous intensity of a luminaire by direction, from an IES LM-63 file
+// with type C photometry. Vertical angles run from 0 straight down (the
+// nadir) to 180 straight up, horizontal angles around the vertical axis.
+#[derive(Clone, Debug)]
+pub struct IesProfile {
+    // In degrees, both ascending
+    vertical: Vec<f64>,
+    horizontal: Vec<f64>,
+    // Candela for each horizontal angle, one value per vertical angle
+    candela: Vec<Vec<f64>>,
+}
+
+// Index of the segment of ascending `angles` holding `angle`, and how far
+// along it `angle` is
+fn segment(angles: &[f64], angle: f64) -> (usize, f64) {
+    if angles.len() == 1 {
+        return (0, 0.0);
+    }
+    let i = (angles.partition_point(|&a| a <= angle).max(1) - 1).min(angles.len() - 2);
+    let t = (angle - angles[i]) / (angles[i + 1] - angles[i]);
+    (i, t.clamp(0.0, 1.0))
+}
+
+impl IesProfile {
+    // Candela towards vertical angle `gamma` and horizontal angle `c`, in
+    // degrees, with angles the file doesn't cover filled in by its symmetry
+    pub fn candela(&self, gamma: f64, c: f64) -> f64 {
+        let (first, last) = (self.vertical[0], *self.vertical.last().unwrap());
+        if gamma < first || gamma > last {
+            return 0.0;
+        }
+        let c = c.rem_euclid(360.0);
+        let (first, last) = (self.horizontal[0], *self.horizontal.last().unwrap());
+        let n = self.horizontal.len();
+        let (v, tv) = segment(&self.vertical, gamma);
+        let at = |h: usize| {
+            let row = &self.candela[h];
+            let next = row[(v + 1).min(row.len() - 1)];
+            row[v] * (1.0 - tv) + next * tv
+        };
+        let c = if last == 0.0 {
+            // Symmetric about the vertical axis
+            0.0
+        } else if last == 90.0 {
+            // The same in each quadrant, mirrored
+            let c = c % 180.0;
+            c.min(180.0 - c)
+        } else if last == 180.0 {
+            // Mirrored across the 0-180 plane
+            c.min(360.0 - c)
+        } else if first == 90.0 && last == 270.0 {
+            // Mirrored across the 90-270 plane
+            if c < 90.0 {
+                180.0 - c
+            } else if c > 270.0 {
+                540.0 - c
+            } else {
+                c
+            }
+        } else if (c < first || c > last) && last - first < 360.0 {
+            // The whole circle, which wraps around from the last angle to
+            // the first, as when a file stops at 350 rather than 360
+            let t = (c - last).rem_euclid(360.0) / (first + 360.0 - last);
+            return at(n - 1) * (1.0 - t) + at(0) * t;
+        } else {
+            c
+        };
+        let (h, th) = segment(&self.horizontal, c);
+        at(h) * (1.0 - th) + at((h + 1).min(n - 1)) * th
+    }
+
+    // Candela along `direction` in a frame with the nadir along -z and the
+    // horizontal angle measured from +x towards +y
+    pub fn candela_towards(&self, direction: Vec3) -> f64 {
+        let d = Vec3::unit_vector(direction);
+        let gamma = (-d.z()).clamp(-1.0, 1.0).acos().to_degrees();
+        let c = d.y().atan2(d.x()).to_degrees();
+        self.candela(gamma, c)
+    }
+
+    // The same profile scaled to give out `lumens` in total. A profile
+    // giving no light can't be scaled.
+    pub fn with_lumens(mut self, lumens: f64) -> Result<IesProfile, String> {
+        let total = self.lumens();
+        if total <= 0.0 {
+            return Err("profile gives no light to scale".to_string());
+        }
+        let scale = lumens / total;
+        for row in &mut self.candela {
+            for c in row.iter_mut() {
+                *c *= scale;
+            }
+        }
+        Ok(self)
+    }
+
+    // Total luminous flux in lumens
+    pub fn lumens(&self) -> f64 {
+        let (n_gamma, n_c) = (360, 720);
+        let (d_gamma, d_c) = (PI / n_gamma as f64, 2.0 * PI / n_c as f64);
+        let mut flux = 0.0;
+        for i in 0..n_gamma {
+            let gamma = (i as f64 + 0.5) * d_gamma;
+            for j in 0..n_c {
+                let c = (j as f64 + 0.5) * d_c;
+                flux += self.candela(gamma.to_degrees(), c.to_degrees()) * gamma.sin();
+            }
+        }
+        flux * d_gamma * d_c
+    }
+}
+
+// A profile placed in the scene
+#[derive(Clone, Debug)]
+pub struct AimedProfile {
+    profile: IesProfile,
+    // Horizontal angles 0 and 90, and straight up
+    x: Vec3,
+    y: Vec3,
+    z: Vec3,
+}
+
+impl AimedProfile {
+    // Points the nadir along `nadir`, with horizontal angle 0 towards +x, or
+    // +z when the nadir is close to the x axis, turned `rotation` degrees
+    // about the nadir
+    pub fn new(profile: IesProfile, nadir: Vec3, rotation: f64) -> AimedProfile {
+        let z = -Vec3::unit_vector(nadir);
+        let reference = if z.x().abs() > 0.9 {
+            Vec3::new(0.0, 0.0, 1.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let x0 = Vec3::unit_vector(reference - Vec3::dot(reference, z) * z);
+        let y0 = Vec3::cross(z, x0);
+        let (sin, cos) = rotation.to_radians().sin_cos();
+        AimedProfile {
+            profile,
+            x: cos * x0 + sin * y0,
+            y: cos * y0 - sin * x0,
+            z,
+        }
+    }
+
+    // Candela along `direction` from the luminaire
+    pub fn candela(&self, direction: Vec3) -> f64 {
+        self.profile.candela_towards(Vec3::new(
+            Vec3::dot(direction, self.x),
+            Vec3::dot(direction, self.y),
+            Vec3::dot(direction, self.z),
+        ))
+    }
+}
+
+// Reads an IES LM-63 file (1986, 1991, 1995 or 2002). Candela values are
+// scaled by the file's multiplier and ballast factors.
+pub fn parse_ies(text: &str) -> Result<IesProfile, String> {
+    let mut lines = text.lines();
+    // Keywords and the format line come before TILT, which ends the header
+    let tilt = loop {
+        match lines.next() {
+            Some(line) if line.trim_start().starts_with("TILT=") => {
+                break line.trim_start()["TILT=".len()..].trim().to_string()
+            }
+            Some(_) => {}
+            None => return Err("missing TILT line".to_string()),
+        }
+    };
+    // The rest is numbers separated by any whitespace or commas
+    let rest: Vec<&str> = lines.collect();
+    let mut numbers = rest
+        .iter()
+        .flat_map(|line| line.split(|c: char| c.is_whitespace() || c == ','))
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            word.parse::<f64>()
+                .map_err(|_| format!("invalid number: {}", word))
+        });
+    let mut next = || {
+        numbers
+            .next()
+            .unwrap_or_else(|| Err("file ends early".to_string()))
+    };
+    match tilt.as_str() {
+        "NONE" => {}
+        // Tilt only matters for lamps that change output when tilted, so
+        // the table is read and ignored
+        "INCLUDE" => {
+            next()?;
+            let pairs = next()? as usize;
+            for _ in 0..2 * pairs {
+                next()?;
+            }
+        }
+        other => return Err(format!("TILT files are not supported: {}", other)),
+    }
+    let _lamps = next()?;
+    let _lumens_per_lamp = next()?;
+    let multiplier = next()?;
+    let n_vertical = next()? as usize;
+    let n_horizontal = next()? as usize;
+    let photometric_type = next()?;
+    let _units = next()?;
+    for _ in 0..3 {
+        // Luminous opening width, length and height
+        next()?;
+    }
+    let ballast = next()?;
+    let ballast_lamp = next()?;
+    let _watts = next()?;
+    if photometric_type != 1.0 {
+        return Err("only type C photometry is supported".to_string());
+    }
+    if n_vertical == 0 || n_horizontal == 0 {
+        return Err("no angles".to_string());
+    }
+    let mut read = |n: usize| (0..n).map(|_| next()).collect::<Result<Vec<f64>, String>>();
+    let vertical = read(n_vertical)?;
+    let horizontal = read(n_horizontal)?;
+    let ascending = |a: &[f64]| a.windows(2).all(|w| w[0] < w[1]);
+    if !ascending(&vertical) || !ascending(&horizontal) {
+        return Err("angles must increase".to_string());
+    }
+    let scale = multiplier * ballast * ballast_lamp;
+    let candela = (0..n_horizontal)
+        .map(|_| Ok(read(n_vertical)?.iter().map(|c| c * scale).collect()))
+        .collect::<Result<Vec<Vec<f64>>, String>>()?;
+    Ok(IesProfile {
+        vertical,
+        horizontal,
+        candela,
+    })
+}
+
+#[test]
+fn ies_profiles() {
+    // A downlight with a cosine distribution of 1000 cd peak, symmetric
+    // about its axis, with numbers split across lines as files do
+    let gammas: Vec<f64> = (0..=18).map(|i| i as f64 * 5.0).collect();
+    let values: Vec<String> = gammas
+        .iter()
+        .map(|g| format!("{}", 500.0 * g.to_radians().cos()))
+        .collect();
+    let text = format!(
+        "IESNA:LM-63-2002\n[TEST] cosine\nTILT=NONE\n1 -1 2 19 1 1 2 0 0 0\n1.0 1.0 20\n{}\n0\n{}\n",
+        gammas
+            .iter()
+            .map(|g| g.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+        values.join("\n")
+    );
+    let profile = parse_ies(&text).unwrap();
+    assert!((profile.candela(0.0, 123.0) - 1000.0).abs() < 1e-9);
+    let expected = 1000.0 * 30f64.to_radians().cos();
+    assert!((profile.candela(30.0, 0.0) - expected).abs() < 1e-9);
+    assert_eq!(profile.candela(120.0, 0.0), 0.0);
+    let down = profile.candela_towards(Vec3::new(0.0, 0.0, -1.0));
+    assert!((down - 1000.0).abs() < 1e-9);
+    // A cosine lobe of peak I gives pi I lumens
+    assert!((profile.lumens() - 1000.0 * PI).abs() < 0.01 * 1000.0 * PI);
+
+    // Bilateral symmetry: 0 to 180 mirrored onto 180 to 360
+    let profile = parse_ies(
+        "IESNA91\nTILT=INCLUDE\n1\n2\n0 90\n1 1\n\
+         1 1000 1 2 3 1 1 0.1 0.1 0\n1 1 10\n0 90\n0 90 180\n\
+         100 0\n200 0\n300 0\n",
+    )
+    .unwrap();
+    assert!((profile.candela(0.0, 90.0) - 200.0).abs() < 1e-9);
+    assert!((profile.candela(0.0, 270.0) - 200.0).abs() < 1e-9);
+    assert!((profile.candela(0.0, 315.0) - 150.0).abs() < 1e-9);
+    assert!((profile.candela(45.0, 0.0) - 50.0).abs() < 1e-9);
+    assert!(parse_ies("TILT=NONE\n1 1000 1 2 1 2 1 0 0 0\n1 1 10\n0 90\n0\n1 1\n").is_err());
+
+    // A full circle that stops short of 360 wraps back to its first angle
+    let profile = parse_ies(
+        "TILT=NONE\n1 1000 1 2 3 1 1 0 0 0\n1 1 10\n0 90\n0 120 240\n100 100\n200 200\n400 400\n",
+    )
+    .unwrap();
+    assert!((profile.candela(0.0, 60.0) - 150.0).abs() < 1e-9);
+    assert!((profile.candela(0.0, 300.0) - 250.0).abs() < 1e-9);
+    assert!((profile.candela(0.0, -30.0) - 175.0).abs() < 1e-9);
+    assert!((profile.candela(0.0, 360.0) - 100.0).abs() < 1e-9);
+
+    // Scaling to a given output, which a dark profile can't be
+    let scaled = profile.with_lumens(500.0).unwrap();
+    assert!((scaled.lumens() - 500.0).abs() < 1e-6);
+    let dark = parse_ies("TILT=NONE\n1 1000 1 2 1 1 1 0 0 0\n1 1 10\n0 90\n0\n0 0\n").unwrap();
+    assert!(dark.with_lumens(500.0).is_err());
+}
@@ -0,0 +1,62 @@
+use crate::film::Film;
+use crate::vec3::Vec3;
+
+// A plain linear framebuffer, rows counted from the bottom like the film
+#[derive(Clone, Debug)]
+pub struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<Vec3>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image {
+            width,
+            height,
+            pixels: vec![Vec3::default(); width * height],
+        }
+    }
+
+    pub fn from_film(film: &Film) -> Image {
+        let mut image = Image::new(film.width(), film.height());
+        for j in 0..image.height {
+            for i in 0..image.width {
+                image.set(i, j, film.pixel(i, j));
+            }
+        }
+        image
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, i: usize, j: usize) -> Vec3 {
+        self.pixels[j * self.width + i]
+    }
+
+    pub fn set(&mut self, i: usize, j: usize, col: Vec3) {
+        self.pixels[j * self.width + i] = col;
+    }
+
+    pub fn pixels(&self) -> &[Vec3] {
+        &self.pixels
+    }
+
+    // Mean squared error per channel against an image of the same size
+    pub fn mse(&self, other: &Image) -> f64 {
+        assert_eq!((self.width, self.height), (other.width, other.height));
+        let sum: f64 = self
+            .pixels
+            .iter()
+            .zip(&other.pixels)
+            .map(|(&a, &b)| (a - b).squared_len())
+            .sum();
+        sum / (3 * self.pixels.len()) as f64
+    }
+}
@@ -0,0 +1,443 @@
+use crate::aov::FirstHit;
+use crate::hitable::{HitRecord, Hitable, HitableList};
+use crate::light::{Lights, SampledLight};
+use crate::material::BsdfFlags;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::stats;
+use crate::vec3::Vec3;
+
+fn power_heuristic(f_pdf: f64, g_pdf: f64) -> f64 {
+    let f2 = f_pdf * f_pdf;
+    let g2 = g_pdf * g_pdf;
+    if f2 + g2 == 0.0 {
+        return 0.0;
+    }
+    f2 / (f2 + g2)
+}
+
+// Direct lighting at a non-specular hit from one point sampled on `lights`,
+// weighted against the chance of the BSDF sample finding the same light
+fn sample_light(
+    rec: &HitRecord,
+    uvw: &Onb,
+    wo: Vec3,
+    world: &HitableList,
+    lights: &Lights,
+    u: (f64, f64),
+) -> Vec3 {
+    let light = match lights.sample(rec.p, rec.normal, u) {
+        Some(light) => light,
+        None => return Vec3::default(),
+    };
+    // How far along `wi` the sampled point is
+    let (wi, pdf, distance) = match &light {
+        SampledLight::Area(light) => {
+            let wi = Vec3::unit_vector(light.p - rec.p);
+            // One sided lights only emit from their front
+            if !light.two_sided && Vec3::dot(light.normal, wi) >= 0.0 {
+                return Vec3::default();
+            }
+            (wi, light.pdf, (light.p - rec.p).len())
+        }
+        SampledLight::Environment { wi, pdf } => (*wi, *pdf, f64::INFINITY),
+    };
+    if pdf == 0.0 {
+        return Vec3::default();
+    }
+    let wi_local = uvw.to_local(wi);
+    let f = rec.material.eval(wo, wi_local);
+    if f == Vec3::default() {
+        return Vec3::default();
+    }
+    // The sample only counts if the shadow ray reaches the sampled point.
+    // Anything in front of it occludes it, even another emitter or another
+    // part of the same one, since `pdf` is only the density of this light
+    // producing this point. The environment is only seen if nothing is hit.
+    let shadow_ray = Ray::new(rec.p, wi);
+    stats::shadow_ray();
+    let emitted = match (world.hit(&shadow_ray, 0.001, f64::MAX), light) {
+        (Some(shadow_rec), SampledLight::Area(_))
+            if (shadow_rec.t - distance).abs() <= 1e-6 * distance =>
+        {
+            shadow_rec.material.emitted(&shadow_ray, &shadow_rec)
+        }
+        (None, SampledLight::Environment { .. }) => lights.environment().radiance(wi),
+        _ => return Vec3::default(),
+    };
+    let weight = power_heuristic(pdf, rec.material.pdf(wo, wi_local));
+    weight * wi_local.z().abs() / pdf * f * emitted
+}
+
+// Direct lighting at a non-specular hit from every delta light. Nothing else
+// can find them, so there is nothing to weight against.
+fn sample_delta_lights(
+    rec: &HitRecord,
+    uvw: &Onb,
+    wo: Vec3,
+    world: &HitableList,
+    lights: &Lights,
+) -> Vec3 {
+    let mut col = Vec3::default();
+    for light in &lights.delta {
+        let sample = match light.sample(rec.p) {
+            Some(sample) => sample,
+            None => continue,
+        };
+        let wi_local = uvw.to_local(sample.wi);
+        let f = rec.material.eval(wo, wi_local);
+        if f == Vec3::default() {
+            continue;
+        }
+        stats::shadow_ray();
+        let shadow_ray = Ray::new(rec.p, sample.wi);
+        let t_max = (sample.distance - 0.001).min(f64::MAX);
+        if world.hit(&shadow_ray, 0.001, t_max).is_none() {
+            col += wi_local.z().abs() * f * sample.radiance;
+        }
+    }
+    col
+}
+
+// Unidirectional path tracer with next-event estimation.
+// Paths are cut by Russian roulette once they have bounced `min_depth`
+// times, and unconditionally after `max_depth` bounces.
+#[derive(Copy, Clone, Debug)]
+pub struct PathTracer {
+    min_depth: u32,
+    max_depth: u32,
+}
+
+impl PathTracer {
+    pub fn new(min_depth: u32, max_depth: u32) -> PathTracer {
+        PathTracer {
+            min_depth,
+            max_depth,
+        }
+    }
+
+    // Returns the radiance along `ray` and what it hit first
+    pub fn color(
+        &self,
+        ray: &Ray,
+        world: &HitableList,
+        lights: &Lights,
+        sampler: &mut dyn Sampler,
+    ) -> (Vec3, Option<FirstHit>) {
+        let mut first_hit = None;
+        let mut ray = *ray;
+        let mut col = Vec3::default();
+        let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+        // Density with which the previous bounce picked `ray`, or None when
+        // it came from the camera or a specular bounce and so could not
+        // have been found by light sampling
+        let mut bsdf_pdf: Option<f64> = None;
+        // Normal at the surface `ray` left from
+        let mut normal = Vec3::default();
+        let mut depth = 0;
+        let mut rays = 0;
+        loop {
+            stats::ray(depth as usize);
+            rays += 1;
+            let rec = match world.hit(&ray, 0.001, f64::MAX) {
+                Some(rec) => rec,
+                None => {
+                    let mut background = lights.environment().radiance(ray.direction());
+                    if let Some(bsdf_pdf) = bsdf_pdf {
+                        let light_pdf = lights.environment_pdf(normal, &ray);
+                        background *= power_heuristic(bsdf_pdf, light_pdf);
+                    }
+                    col += throughput * background;
+                    break;
+                }
+            };
+            if depth == 0 {
+                first_hit = Some(FirstHit::new(&ray, &rec));
+            }
+            let mut emitted = rec.material.emitted(&ray, &rec);
+            if let Some(bsdf_pdf) = bsdf_pdf {
+                let light_pdf = lights.pdf(ray.origin(), normal, rec.object_id, ray.direction());
+                emitted *= power_heuristic(bsdf_pdf, light_pdf);
+            }
+            col += throughput * emitted;
+            if depth >= self.max_depth {
+                break;
+            }
+
+            let uvw = Onb::build_from_w(rec.normal);
+            let wo = uvw.to_local(-Vec3::unit_vector(ray.direction()));
+            // Dimensions are drawn in the same order at every bounce, used or
+            // not, so they line up across the samples of a pixel
+            let u_light = sampler.get_2d();
+            let uc = sampler.get_1d();
+            let u = sampler.get_2d();
+            let u_roulette = sampler.get_1d();
+            if !rec.material.flags().is_specular() {
+                col += throughput * sample_light(&rec, &uvw, wo, world, lights, u_light);
+                col += throughput * sample_delta_lights(&rec, &uvw, wo, world, lights);
+            }
+            let bs = match rec.material.sample(wo, uc, u) {
+                Some(bs) => bs,
+                None => break,
+            };
+            throughput *= bs.f * bs.wi.z().abs() / bs.pdf;
+            bsdf_pdf = if bs.flags.contains(BsdfFlags::SPECULAR) {
+                None
+            } else {
+                Some(bs.pdf)
+            };
+            ray = Ray::new(rec.p, uvw.to_world(bs.wi));
+            normal = rec.normal;
+            depth += 1;
+
+            if depth >= self.min_depth {
+                let max = throughput.max_component();
+                if max < 1.0 {
+                    let q = (1.0 - max).max(0.0);
+                    if u_roulette < q {
+                        break;
+                    }
+                    throughput /= 1.0 - q;
+                }
+            }
+        }
+        stats::path(rays);
+        (col, first_hit)
+    }
+}
+
+// An environment giving no light, so tests see only their lights
+#[cfg(test)]
+struct Dark;
+
+#[cfg(test)]
+impl crate::light::Environment for Dark {
+    fn radiance(&self, _direction: Vec3) -> Vec3 {
+        Vec3::default()
+    }
+}
+
+// Mean radiance along `ray` over `n` independent samples
+#[cfg(test)]
+fn mean_radiance(
+    integrator: &PathTracer,
+    ray: &Ray,
+    world: &HitableList,
+    lights: &Lights,
+    n: usize,
+) -> Vec3 {
+    use crate::sampler::IndependentSampler;
+    let mut sampler = IndependentSampler::new(0);
+    let mut sum = Vec3::default();
+    for index in 0..n {
+        sampler.start_pixel_sample((0, 0), index);
+        sum += integrator.color(ray, world, lights, &mut sampler).0;
+    }
+    sum / n as f64
+}
+
+// Projected solid angle of a square of half width `w` centered `h` above a
+// point and facing it
+#[cfg(test)]
+fn square_projected_solid_angle(w: f64, h: f64) -> f64 {
+    use std::f64::consts::PI;
+    // Form factor to a rectangle with a corner straight above the point
+    let (a, b) = (w / h, w / h);
+    let (sa, sb) = ((1.0 + a * a).sqrt(), (1.0 + b * b).sqrt());
+    let corner = (a / sa * (b / sa).atan() + b / sb * (a / sb).atan()) / (2.0 * PI);
+    4.0 * PI * corner
+}
+
+#[test]
+fn lamps_blocking_lamps() {
+    use crate::light_sampler::LightSamplerKind;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::rect::Rect;
+    use std::f64::consts::PI;
+
+    // A bright lamp right above a point on the floor, hiding the middle of a
+    // dim, larger one behind it
+    let mut world = HitableList::new();
+    let mut lights = Lights::new();
+    lights.set_environment(Box::new(Dark));
+    world.push(Box::new(Rect::new(
+        Vec3::new(-10.0, 0.0, -10.0),
+        Vec3::new(0.0, 0.0, 20.0),
+        Vec3::new(20.0, 0.0, 0.0),
+        Lambertian::new(Vec3::new(1.0, 1.0, 1.0)),
+    )));
+    let lamp = |w: f64, h: f64, emit: f64| {
+        Rect::new(
+            Vec3::new(-w, h, -w),
+            Vec3::new(2.0 * w, 0.0, 0.0),
+            Vec3::new(0.0, 0.0, 2.0 * w),
+            DiffuseLight::new(Vec3::new(emit, emit, emit)),
+        )
+    };
+    for &(w, h, emit) in &[(0.25, 1.0, 10.0), (1.0, 2.0, 1.0)] {
+        let lamp = lamp(w, h, emit);
+        lights.add_area(Box::new(lamp.clone()), world.len());
+        world.push(Box::new(lamp));
+    }
+    // The near lamp covers what the far one would from half its width
+    let near = square_projected_solid_angle(0.25, 1.0);
+    let far = square_projected_solid_angle(1.0, 2.0) - near;
+    let expected = (10.0 * near + far) / PI;
+
+    let ray = Ray::new(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, -1.0, 0.0));
+    // Direct lighting only
+    let integrator = PathTracer::new(1, 1);
+    for &kind in &[LightSamplerKind::Uniform, LightSamplerKind::Bvh] {
+        lights.set_sampler(kind);
+        let mean = mean_radiance(&integrator, &ray, &world, &lights, 20_000).r();
+        assert!(
+            (mean - expected).abs() < 0.02 * expected,
+            "{:?}: {} vs {}",
+            kind,
+            mean,
+            expected
+        );
+    }
+}
+
+#[test]
+fn direct_lighting_converges() {
+    use crate::light_sampler::LightSamplerKind;
+    use crate::material::{DiffuseLight, Lambertian};
+    use crate::rect::Rect;
+    use crate::sphere::Sphere;
+
+    // A white floor under a glowing ball. A ball of radius r whose center is
+    // d above a point gives it irradiance pi L (r / d)^2, so the floor
+    // reflects L (r / d)^2 towards any direction.
+    let ray = Ray::new(Vec3::new(0.0, 0.05, 0.1), Vec3::new(0.0, -0.5, -1.0));
+    let integrator = PathTracer::new(1, 1);
+    for &(radius, height) in &[(0.2, 3.0), (1.0, 2.0), (1.8, 2.0)] {
+        let mut world = HitableList::new();
+        let mut lights = Lights::new();
+        lights.set_environment(Box::new(Dark));
+        world.push(Box::new(Rect::new(
+            Vec3::new(-10.0, 0.0, -10.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            Lambertian::new(Vec3::new(1.0, 1.0, 1.0)),
+        )));
+        let ball = Sphere::new(
+            Vec3::new(0.0, height, 0.0),
+            radius,
+            DiffuseLight::new(Vec3::new(4.0, 4.0, 4.0)),
+        );
+        lights.add_area(Box::new(ball.clone()), world.len());
+        world.push(Box::new(ball));
+        let expected = 4.0 * (radius / height) * (radius / height);
+        for &kind in &[LightSamplerKind::Uniform, LightSamplerKind::Bvh] {
+            lights.set_sampler(kind);
+            let mean = mean_radiance(&integrator, &ray, &world, &lights, 20_000).r();
+            assert!(
+                (mean - expected).abs() < 0.02 * expected,
+                "radius {} {:?}: {} vs {}",
+                radius,
+                kind,
+                mean,
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn delta_lighting() {
+    use crate::light::{DirectionalLight, PointLight};
+    use crate::material::{Lambertian, Material, Metal};
+    use crate::rect::Rect;
+    use std::f64::consts::PI;
+    use std::rc::Rc;
+
+    let floor = |material: Rc<dyn Material>| {
+        let mut world = HitableList::new();
+        world.push(Box::new(Rect::new(
+            Vec3::new(-10.0, 0.0, -10.0),
+            Vec3::new(0.0, 0.0, 20.0),
+            Vec3::new(20.0, 0.0, 0.0),
+            material,
+        )));
+        world
+    };
+    let ray = Ray::new(Vec3::new(0.0, 1.0, 1.0), Vec3::new(0.0, -1.0, -1.0));
+    let integrator = PathTracer::new(1, 1);
+    let radiance = |world: &HitableList, lights: &Lights| {
+        mean_radiance(&integrator, &ray, world, lights, 4).r()
+    };
+
+    // A point light 2 above the point seen, and a directional light 60
+    // degrees off the normal, over a floor of albedo 0.5
+    let world = floor(Lambertian::new(Vec3::new(0.5, 0.5, 0.5)));
+    let mut lights = Lights::new();
+    lights.set_environment(Box::new(Dark));
+    lights.delta.push(Box::new(PointLight::new(
+        Vec3::new(0.0, 2.0, 0.0),
+        Vec3::new(8.0, 8.0, 8.0),
+    )));
+    let expected = 0.5 / PI * 8.0 / 4.0;
+    assert!((radiance(&world, &lights) - expected).abs() < 1e-9);
+    lights.delta.push(Box::new(DirectionalLight::new(
+        Vec3::new(-3f64.sqrt(), -1.0, 0.0),
+        Vec3::new(3.0, 3.0, 3.0),
+    )));
+    let expected = expected + 0.5 / PI * 3.0 * 0.5;
+    assert!((radiance(&world, &lights) - expected).abs() < 1e-9);
+
+    // Rough metals are lit by delta lights, mirrors can't be
+    let rough = radiance(
+        &floor(Metal::new(Vec3::new(0.9, 0.9, 0.9), (0.3, 0.3))),
+        &lights,
+    );
+    assert!(rough > 0.0);
+    let mirror = radiance(
+        &floor(Metal::new(Vec3::new(0.9, 0.9, 0.9), (0.0, 0.0))),
+        &lights,
+    );
+    assert_eq!(mirror, 0.0);
+}
+
+#[test]
+fn russian_roulette_is_unbiased() {
+    use crate::material::Lambertian;
+    use crate::rect::Rect;
+    use crate::sphere::Sphere;
+
+    // A uniformly lit sky over a bright floor and a ball resting on it. Rays
+    // into the crease between them bounce many times before escaping.
+    struct Sky;
+    impl crate::light::Environment for Sky {
+        fn radiance(&self, _direction: Vec3) -> Vec3 {
+            Vec3::new(1.0, 1.0, 1.0)
+        }
+    }
+    let mut world = HitableList::new();
+    let mut lights = Lights::new();
+    lights.set_environment(Box::new(Sky));
+    world.push(Box::new(Rect::new(
+        Vec3::new(-10.0, 0.0, -10.0),
+        Vec3::new(0.0, 0.0, 20.0),
+        Vec3::new(20.0, 0.0, 0.0),
+        Lambertian::new(Vec3::new(0.9, 0.9, 0.9)),
+    )));
+    world.push(Box::new(Sphere::new(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Lambertian::new(Vec3::new(0.9, 0.9, 0.9)),
+    )));
+    let ray = Ray::new(Vec3::new(0.0, 0.3, 3.0), Vec3::new(0.0, -0.12, -1.0));
+    let n = 40_000;
+    // Roulette from the first bounce against none within any likely path
+    let roulette = mean_radiance(&PathTracer::new(0, 100), &ray, &world, &lights, n).r();
+    let full = mean_radiance(&PathTracer::new(100, 100), &ray, &world, &lights, n).r();
+    assert!(
+        (roulette - full).abs() < 0.02 * full,
+        "{} vs {}",
+        roulette,
+        full
+    );
+}
@@ -0,0 +1,431 @@
+use crate::camera::{CameraModel, Frame};
+use crate::material::refract;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vec3::Vec3;
+
+// Rings of the film, from its center out to the corners, that each get
+// their own bounds on the exit pupil
+const PUPIL_SEGMENTS: usize = 32;
+// Film positions and the grid of points on the rear element traced to bound
+// the exit pupil of each ring
+const PUPIL_FILM_SAMPLES: usize = 8;
+const PUPIL_GRID: usize = 32;
+
+// One spherical surface of a lens. `thickness` is the distance along the
+// axis to the next surface, or to the film for the last one, and `ior` is
+// that of the medium behind the surface. A zero radius is the aperture stop.
+// Lengths are in scene units.
+#[derive(Copy, Clone, Debug)]
+pub struct LensElement {
+    pub radius: f64,
+    pub thickness: f64,
+    pub ior: f64,
+    pub aperture_radius: f64,
+}
+
+// Reads a lens prescription: one surface per line, from the front of the
+// lens to the back, each given as its radius of curvature, thickness, index
+// of refraction and aperture diameter. Lengths are in millimeters, taking
+// scene units as meters, an index of 0 stands for air and `#` starts a
+// comment. Positive radii bulge towards the front of the lens.
+pub fn parse_lens_data(text: &str) -> Result<Vec<LensElement>, String> {
+    let mut elements = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |msg: &str| format!("line {}: {}", index + 1, msg);
+        let numbers = line
+            .split_whitespace()
+            .map(|value| value.parse())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|_| error("invalid number"))?;
+        if numbers.len() != 4 {
+            return Err(error("expected radius, thickness, ior and aperture"));
+        }
+        if numbers[1] < 0.0 || numbers[2] < 0.0 || numbers[3] <= 0.0 {
+            return Err(error("thickness, ior and aperture must be positive"));
+        }
+        elements.push(LensElement {
+            radius: numbers[0] / 1000.0,
+            thickness: numbers[1] / 1000.0,
+            ior: if numbers[2] == 0.0 { 1.0 } else { numbers[2] },
+            aperture_radius: numbers[3] / 2000.0,
+        });
+    }
+    if elements.is_empty() {
+        return Err("lens has no elements".to_string());
+    }
+    Ok(elements)
+}
+
+// Intersects a ray with the surface whose vertex is at `z` on the axis and
+// refracts it from index `eta_i` into `eta_t`. None if the ray misses the
+// surface, is blocked by its aperture or is totally reflected.
+fn refract_at(element: &LensElement, z: f64, ray: Ray, eta_i: f64, eta_t: f64) -> Option<Ray> {
+    let (origin, direction) = (ray.origin(), ray.direction());
+    let inside = |p: Vec3| p.x() * p.x() + p.y() * p.y() <= element.aperture_radius.powi(2);
+    if element.radius == 0.0 {
+        let t = (z - origin.z()) / direction.z();
+        let p = origin + t * direction;
+        return if t > 0.0 && inside(p) {
+            Some(Ray::new(p, direction))
+        } else {
+            None
+        };
+    }
+
+    let center = Vec3::new(0.0, 0.0, z + element.radius);
+    let oc = origin - center;
+    let a = direction.squared_len();
+    let b = Vec3::dot(oc, direction);
+    let c = oc.squared_len() - element.radius * element.radius;
+    let discriminant = b * b - a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // The surface is the cap of the sphere nearest the vertex
+    let t = if (direction.z() > 0.0) != (element.radius < 0.0) {
+        (-b - discriminant.sqrt()) / a
+    } else {
+        (-b + discriminant.sqrt()) / a
+    };
+    let p = origin + t * direction;
+    if t <= 0.0 || !inside(p) {
+        return None;
+    }
+    let mut normal = Vec3::unit_vector(p - center);
+    if Vec3::dot(normal, direction) > 0.0 {
+        normal = -normal;
+    }
+    let mut refracted = Vec3::default();
+    if !refract(&direction, &normal, eta_i / eta_t, &mut refracted) {
+        return None;
+    }
+    Some(Ray::new(p, refracted))
+}
+
+// Where a ray that entered the lens parallel to the axis, at some height
+// along x, crosses the axis after leaving it (the focal point) and where it
+// regains its height (the principal plane)
+fn cardinal_points(incoming: Ray, outgoing: Ray) -> (f64, f64) {
+    let (o, d) = (outgoing.origin(), outgoing.direction());
+    let focal = o.z() - o.x() / d.x() * d.z();
+    let principal = o.z() + (incoming.origin().x() - o.x()) / d.x() * d.z();
+    (focal, principal)
+}
+
+// A rectangle on the plane of the rear element
+#[derive(Copy, Clone, Debug)]
+struct PupilBounds {
+    x: (f64, f64),
+    y: (f64, f64),
+}
+
+impl PupilBounds {
+    fn area(&self) -> f64 {
+        (self.x.1 - self.x.0) * (self.y.1 - self.y.0)
+    }
+}
+
+// A lens in its own space: the film is at z = 0, facing -z, and the lens in
+// front of it
+#[derive(Clone, Debug)]
+struct LensSystem {
+    elements: Vec<LensElement>,
+}
+
+impl LensSystem {
+    fn rear_z(&self) -> f64 {
+        -self.elements.last().unwrap().thickness
+    }
+
+    fn front_z(&self) -> f64 {
+        -self.elements.iter().map(|e| e.thickness).sum::<f64>()
+    }
+
+    fn ior_in_front_of(&self, index: usize) -> f64 {
+        if index == 0 {
+            1.0
+        } else {
+            self.elements[index - 1].ior
+        }
+    }
+
+    fn trace_from_film(&self, mut ray: Ray) -> Option<Ray> {
+        let mut z = 0.0;
+        for (index, element) in self.elements.iter().enumerate().rev() {
+            z -= element.thickness;
+            ray = refract_at(element, z, ray, element.ior, self.ior_in_front_of(index))?;
+        }
+        Some(ray)
+    }
+
+    fn trace_from_scene(&self, mut ray: Ray) -> Option<Ray> {
+        let mut z = self.front_z();
+        for (index, element) in self.elements.iter().enumerate() {
+            ray = refract_at(element, z, ray, self.ior_in_front_of(index), element.ior)?;
+            z += element.thickness;
+        }
+        Some(ray)
+    }
+
+    // Focal length and the positions of the rear principal plane and the
+    // front principal plane, from nearly paraxial rays
+    fn thick_lens(&self) -> Option<(f64, f64, f64)> {
+        let height = 1e-3
+            * self
+                .elements
+                .iter()
+                .map(|e| e.aperture_radius)
+                .fold(f64::INFINITY, f64::min);
+        let from_scene = Ray::new(
+            Vec3::new(height, 0.0, self.front_z() - 1.0),
+            Vec3::new(0.0, 0.0, 1.0),
+        );
+        let (rear_focal, rear_principal) =
+            cardinal_points(from_scene, self.trace_from_scene(from_scene)?);
+        let from_film = Ray::new(
+            Vec3::new(height, 0.0, self.rear_z() + 1.0),
+            Vec3::new(0.0, 0.0, -1.0),
+        );
+        let (_, front_principal) = cardinal_points(from_film, self.trace_from_film(from_film)?);
+        Some((rear_focal - rear_principal, rear_principal, front_principal))
+    }
+
+    // Moves the lens along its axis so that objects `distance` in front of
+    // the film are in focus. A thick lens at offset `delta` images objects
+    // at s_o = a + delta from its front principal plane to s_i = b - delta
+    // behind its rear one, and 1/s_o + 1/s_i = 1/f gives a quadratic in
+    // `delta`.
+    fn focus(&mut self, distance: f64) -> Result<(), String> {
+        let (f, rear_principal, front_principal) =
+            self.thick_lens().ok_or("lens blocks rays near its axis")?;
+        if !f.is_finite() || f <= 0.0 {
+            return Err("lens doesn't converge light".to_string());
+        }
+        let (a, b) = (front_principal + distance, -rear_principal);
+        let discriminant = (a + b) * (a + b - 4.0 * f);
+        if discriminant < 0.0 {
+            return Err(format!("lens can't focus as close as {}", distance));
+        }
+        let delta = 0.5 * (b - a + discriminant.sqrt());
+        let last = self.elements.last_mut().unwrap();
+        last.thickness -= delta;
+        if last.thickness <= 0.0 {
+            return Err(format!("lens can't focus as close as {}", distance));
+        }
+        Ok(())
+    }
+
+    // Bounds, on the plane of the rear element, of where rays from film
+    // points between `r0` and `r1` along x make it through the lens, and
+    // the area they get through weighted by cos^4 of the rays' angle
+    fn bound_exit_pupil(&self, r0: f64, r1: f64) -> Option<(PupilBounds, f64)> {
+        let extent = 1.5 * self.elements.last().unwrap().aperture_radius;
+        let cell = 2.0 * extent / PUPIL_GRID as f64;
+        let rear_z = self.rear_z();
+        let mut bounds: Option<((f64, f64), (f64, f64))> = None;
+        let mut weighted_area = 0.0;
+        for i in 0..PUPIL_FILM_SAMPLES {
+            let film_x = r0 + (i as f64 + 0.5) / PUPIL_FILM_SAMPLES as f64 * (r1 - r0);
+            let film = Vec3::new(film_x, 0.0, 0.0);
+            for gx in 0..PUPIL_GRID {
+                for gy in 0..PUPIL_GRID {
+                    let x = -extent + (gx as f64 + 0.5) * cell;
+                    let y = -extent + (gy as f64 + 0.5) * cell;
+                    let direction = Vec3::new(x, y, rear_z) - film;
+                    if self.trace_from_film(Ray::new(film, direction)).is_none() {
+                        continue;
+                    }
+                    weighted_area += (direction.z() / direction.len()).powi(4) * cell * cell;
+                    bounds = Some(match bounds {
+                        None => ((x, x), (y, y)),
+                        Some(((x0, x1), (y0, y1))) => {
+                            ((x0.min(x), x1.max(x)), (y0.min(y), y1.max(y)))
+                        }
+                    });
+                }
+            }
+        }
+        // Grow by a grid cell to cover what fell between the samples
+        let ((x0, x1), (y0, y1)) = bounds?;
+        let bounds = PupilBounds {
+            x: (x0 - cell, x1 + cell),
+            y: (y0 - cell, y1 + cell),
+        };
+        Some((bounds, weighted_area / PUPIL_FILM_SAMPLES as f64))
+    }
+}
+
+// A camera that traces rays through a real lens prescription, which brings
+// its own distortion, vignetting and change of view with focus. Rays start
+// on the film and aim at the part of the rear element light gets through
+// from there, the exit pupil.
+pub struct LensCamera {
+    frame: Frame,
+    lens: LensSystem,
+    film_size: (f64, f64),
+    pupils: Vec<Option<PupilBounds>>,
+    // Weighted area of the exit pupil seen from the center of the film,
+    // which scales the weights of rays there to average one
+    center_pupil_area: f64,
+}
+
+impl LensCamera {
+    // The film, of `film_size` in scene units, sits at `look_from`, and the
+    // lens is focused `focus_dist` in front of it
+    pub fn new(
+        look_from: Vec3,
+        look_at: Vec3,
+        vup: Vec3,
+        elements: Vec<LensElement>,
+        film_size: (f64, f64),
+        focus_dist: f64,
+    ) -> Result<LensCamera, String> {
+        let mut lens = LensSystem { elements };
+        lens.focus(focus_dist)?;
+        let film_radius = 0.5 * (film_size.0.powi(2) + film_size.1.powi(2)).sqrt();
+        let mut center_pupil_area = 0.0;
+        let pupils = (0..PUPIL_SEGMENTS)
+            .map(|i| {
+                let r0 = i as f64 / PUPIL_SEGMENTS as f64 * film_radius;
+                let r1 = (i + 1) as f64 / PUPIL_SEGMENTS as f64 * film_radius;
+                let (bounds, weighted_area) = lens.bound_exit_pupil(r0, r1)?;
+                if i == 0 {
+                    center_pupil_area = weighted_area;
+                }
+                Some(bounds)
+            })
+            .collect();
+        if center_pupil_area == 0.0 {
+            return Err("no light gets through the lens to the center of the film".to_string());
+        }
+        Ok(LensCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            lens,
+            film_size,
+            pupils,
+            center_pupil_area,
+        })
+    }
+}
+
+impl CameraModel for LensCamera {
+    fn generate_ray(&self, u: f64, v: f64, sampler: &mut dyn Sampler) -> Option<Ray> {
+        self.generate_weighted_ray(u, v, sampler)
+            .map(|(ray, _)| ray)
+    }
+
+    // Rays are weighted by the area they were sampled over and cos^4 of
+    // their angle to the axis, the falloff of irradiance on the film
+    fn generate_weighted_ray(
+        &self,
+        u: f64,
+        v: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Ray, f64)> {
+        let (su, sv) = sampler.get_2d();
+        // The lens turns the image upside down
+        let film = Vec3::new(
+            (0.5 - u) * self.film_size.0,
+            (0.5 - v) * self.film_size.1,
+            0.0,
+        );
+        let r = (film.x() * film.x() + film.y() * film.y()).sqrt();
+        let film_radius = 0.5 * (self.film_size.0.powi(2) + self.film_size.1.powi(2)).sqrt();
+        let segment = ((r / film_radius * PUPIL_SEGMENTS as f64) as usize).min(PUPIL_SEGMENTS - 1);
+        let pupil = self.pupils[segment]?;
+
+        // The bounds are for film points along x, so turn them to this one
+        let x = pupil.x.0 + su * (pupil.x.1 - pupil.x.0);
+        let y = pupil.y.0 + sv * (pupil.y.1 - pupil.y.0);
+        let (sin, cos) = if r > 0.0 {
+            (film.y() / r, film.x() / r)
+        } else {
+            (0.0, 1.0)
+        };
+        let target = Vec3::new(cos * x - sin * y, sin * x + cos * y, self.lens.rear_z());
+        let direction = target - film;
+        let ray = self.lens.trace_from_film(Ray::new(film, direction))?;
+
+        let cos_theta = direction.z().abs() / direction.len();
+        let weight = cos_theta.powi(4) * pupil.area() / self.center_pupil_area;
+        let (o, d) = (ray.origin(), ray.direction());
+        Some((
+            Ray::new(
+                self.frame.origin + self.frame.local(o.x(), o.y(), o.z()),
+                self.frame.local(d.x(), d.y(), d.z()),
+            ),
+            weight,
+        ))
+    }
+}
+
+#[test]
+fn lens_focuses() {
+    use crate::sampler::IndependentSampler;
+
+    let elements = parse_lens_data(
+        "# double Gauss, 50 mm f/2\n\
+         29.475 3.76 1.67 25.2\n\
+         84.83 0.12 1 25.2\n\
+         19.275 4.025 1.67 23\n\
+         40.77 3.275 1.699 23\n\
+         12.75 5.705 1 18\n\
+         0 4.5 0 17.1\n\
+         -14.495 1.18 1.603 17\n\
+         40.77 6.065 1.658 20\n\
+         -20.385 0.19 1 20\n\
+         437.065 3.22 1.717 20\n\
+         -39.73 0 1 20\n",
+    )
+    .unwrap();
+    let mut lens = LensSystem { elements };
+    lens.focus(5.0).unwrap();
+    let (f, _, _) = lens.thick_lens().unwrap();
+    assert!((f - 0.05).abs() < 1e-3, "focal length {}", f);
+
+    // Rays from the center of the film through the whole pupil meet again
+    // near the axis at the focus distance
+    let film = Vec3::default();
+    let rear = lens.elements.last().unwrap().aperture_radius;
+    for &(x, y) in &[(0.1, 0.0), (0.0, 0.4), (-0.7, 0.2), (0.5, -0.5)] {
+        let target = Vec3::new(x * rear, y * rear, lens.rear_z());
+        let ray = lens.trace_from_film(Ray::new(film, target - film)).unwrap();
+        let t = (-5.0 - ray.origin().z()) / ray.direction().z();
+        let p = ray.point_at_parameter(t);
+        assert!(
+            (p.x() * p.x() + p.y() * p.y()).sqrt() < 2e-3,
+            "missed by {}",
+            p
+        );
+    }
+
+    // Weights average to one in the middle of the image and fall off
+    // towards the corners
+    let camera = LensCamera::new(
+        Vec3::default(),
+        Vec3::new(0.0, 0.0, -1.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        lens.elements,
+        (0.036, 0.024),
+        5.0,
+    )
+    .unwrap();
+    let mut sampler = IndependentSampler::new(0);
+    let mut mean_weight = |u: f64, v: f64| {
+        let n = 10_000;
+        let total: f64 = (0..n)
+            .filter_map(|_| camera.generate_weighted_ray(u, v, &mut sampler))
+            .map(|(_, weight)| weight)
+            .sum();
+        total / n as f64
+    };
+    let center = mean_weight(0.5, 0.5);
+    assert!((center - 1.0).abs() < 0.02, "center weight {}", center);
+    let corner = mean_weight(0.02, 0.97);
+    assert!(corner < 0.8 * center, "corner weight {}", corner);
+}
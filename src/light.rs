@@ -0,0 +1,348 @@
+use crate::hitable::{Hitable, LightSample};
+use crate::ies::AimedProfile;
+use crate::light_sampler::{LightSampler, LightSamplerKind};
+use crate::ray::Ray;
+use crate::vec3::Vec3;
+use std::collections::HashMap;
+
+// Light arriving at a shading point from a light with no area. `wi` is the
+// unit direction towards the light and `distance` how far along it the light
+// is, infinite for directional lights.
+#[derive(Copy, Clone, Debug)]
+pub struct DeltaSample {
+    pub wi: Vec3,
+    pub distance: f64,
+    pub radiance: Vec3,
+}
+
+// Lights that rays can never hit, so they are only found by shadow rays
+pub trait DeltaLight {
+    fn sample(&self, p: Vec3) -> Option<DeltaSample>;
+}
+
+// Light from infinitely far away, seen by rays that leave the scene
+pub trait Environment {
+    // Radiance arriving along `-direction`
+    fn radiance(&self, direction: Vec3) -> Vec3;
+
+    // Whether light sampling should pick the environment, or leave it to
+    // rays that happen to escape
+    fn importance_sampled(&self) -> bool {
+        false
+    }
+
+    // Samples a unit direction towards the environment, with its solid
+    // angle density
+    fn sample(&self, _u: (f64, f64)) -> Option<(Vec3, f64)> {
+        None
+    }
+
+    fn pdf(&self, _direction: Vec3) -> f64 {
+        0.0
+    }
+}
+
+// White at the horizon fading to blue overhead
+pub struct Gradient;
+
+impl Environment for Gradient {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let unit_dir = Vec3::unit_vector(direction);
+        let t = 0.5 * (unit_dir.y() + 1.0);
+        (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0)
+    }
+}
+
+// A light picked for a shading point: a point on an emitter, which a shadow
+// ray towards it should hit, or a direction to the environment, along which
+// a shadow ray should hit nothing
+pub enum SampledLight {
+    Area(LightSample),
+    Environment { wi: Vec3, pdf: f64 },
+}
+
+// Everything the integrator samples for direct lighting: emissive objects,
+// which are also in the world, the environment, and delta lights, which
+// aren't. Area lights and the environment are picked by a LightSampler,
+// which has to be rebuilt with `set_sampler` after changing them.
+pub struct Lights {
+    area: Vec<Box<dyn Hitable>>,
+    // The area light each emissive world object is, by object id
+    objects: HashMap<usize, usize>,
+    environment: Box<dyn Environment>,
+    sampler: LightSampler,
+    pub delta: Vec<Box<dyn DeltaLight>>,
+}
+
+impl Lights {
+    pub fn new() -> Lights {
+        Lights {
+            area: Vec::new(),
+            objects: HashMap::new(),
+            environment: Box::new(Gradient),
+            sampler: LightSampler::new(LightSamplerKind::Bvh, &[]),
+            delta: Vec::new(),
+        }
+    }
+
+    // `object_id` is where the same object is in the world
+    pub fn add_area(&mut self, light: Box<dyn Hitable>, object_id: usize) {
+        self.objects.insert(object_id, self.area.len());
+        self.area.push(light);
+    }
+
+    pub fn set_environment(&mut self, environment: Box<dyn Environment>) {
+        self.environment = environment;
+    }
+
+    pub fn environment(&self) -> &dyn Environment {
+        self.environment.as_ref()
+    }
+
+    // The environment, if sampled, is the light after the area lights
+    pub fn set_sampler(&mut self, kind: LightSamplerKind) {
+        let mut bounds: Vec<_> = self.area.iter().map(|l| l.light_bounds()).collect();
+        if self.environment.importance_sampled() {
+            bounds.push(None);
+        }
+        self.sampler = LightSampler::new(kind, &bounds);
+    }
+
+    pub fn sampler_kind(&self) -> LightSamplerKind {
+        self.sampler.kind()
+    }
+
+    // Picks a light for a surface at `p` with normal `n` and samples it. The
+    // first dimension of `u` picks the light and is then reused; the pdf
+    // includes the chance of picking it.
+    pub fn sample(&self, p: Vec3, n: Vec3, u: (f64, f64)) -> Option<SampledLight> {
+        let (index, pmf, u0) = self.sampler.sample(p, n, u.0)?;
+        if index == self.area.len() {
+            let (wi, pdf) = self.environment.sample((u0, u.1))?;
+            return Some(SampledLight::Environment { wi, pdf: pmf * pdf });
+        }
+        let light = &self.area[index];
+        let mut sample = light.sample(p, (u0, u.1))?;
+        sample.pdf = pmf * light.pdf(p, sample.p - p);
+        Some(SampledLight::Area(sample))
+    }
+
+    // Solid angle density of `sample` at `p` with normal `n` generating
+    // `direction`, which hits the world object `object_id` first
+    pub fn pdf(&self, p: Vec3, n: Vec3, object_id: usize, direction: Vec3) -> f64 {
+        match self.objects.get(&object_id) {
+            Some(&index) => self.sampler.pmf(p, n, index) * self.area[index].pdf(p, direction),
+            None => 0.0,
+        }
+    }
+
+    // Density of `sample` generating `ray`, which escapes to the environment
+    pub fn environment_pdf(&self, n: Vec3, ray: &Ray) -> f64 {
+        if !self.environment.importance_sampled() {
+            return 0.0;
+        }
+        let pmf = self.sampler.pmf(ray.origin(), n, self.area.len());
+        pmf * self.environment.pdf(ray.direction())
+    }
+}
+
+impl Default for Lights {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Shines `intensity` in every direction from a point. With a profile the
+// intensity is instead the profile's candela times `intensity`.
+pub struct PointLight {
+    position: Vec3,
+    intensity: Vec3,
+    profile: Option<AimedProfile>,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3, intensity: Vec3) -> PointLight {
+        PointLight {
+            position,
+            intensity,
+            profile: None,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: AimedProfile) -> PointLight {
+        self.profile = Some(profile);
+        self
+    }
+}
+
+impl DeltaLight for PointLight {
+    fn sample(&self, p: Vec3) -> Option<DeltaSample> {
+        let to_light = self.position - p;
+        let distance = to_light.len();
+        // A point at the light has no direction to it
+        if distance == 0.0 {
+            return None;
+        }
+        let wi = to_light / distance;
+        let candela = self
+            .profile
+            .as_ref()
+            .map_or(1.0, |profile| profile.candela(-wi));
+        if candela == 0.0 {
+            return None;
+        }
+        Some(DeltaSample {
+            wi,
+            distance,
+            radiance: candela * self.intensity / (distance * distance),
+        })
+    }
+}
+
+// A point light restricted to a cone around `direction`. Intensity is full
+// within `cos_falloff_start` of the axis and fades smoothly to nothing at
+// `cos_total_width`. A profile shapes the light within the cone as for a
+// PointLight.
+pub struct SpotLight {
+    position: Vec3,
+    direction: Vec3,
+    intensity: Vec3,
+    cos_total_width: f64,
+    cos_falloff_start: f64,
+    profile: Option<AimedProfile>,
+}
+
+impl SpotLight {
+    // Angles are in degrees, measured from the axis
+    pub fn new(
+        position: Vec3,
+        look_at: Vec3,
+        intensity: Vec3,
+        total_width: f64,
+        falloff_start: f64,
+    ) -> SpotLight {
+        SpotLight {
+            position,
+            direction: Vec3::unit_vector(look_at - position),
+            intensity,
+            cos_total_width: total_width.to_radians().cos(),
+            cos_falloff_start: falloff_start.min(total_width).to_radians().cos(),
+            profile: None,
+        }
+    }
+
+    pub fn with_profile(mut self, profile: AimedProfile) -> SpotLight {
+        self.profile = Some(profile);
+        self
+    }
+
+    fn falloff(&self, cos_theta: f64) -> f64 {
+        if cos_theta >= self.cos_falloff_start {
+            return 1.0;
+        }
+        if cos_theta <= self.cos_total_width {
+            return 0.0;
+        }
+        let t =
+            (cos_theta - self.cos_total_width) / (self.cos_falloff_start - self.cos_total_width);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+impl DeltaLight for SpotLight {
+    fn sample(&self, p: Vec3) -> Option<DeltaSample> {
+        let to_light = self.position - p;
+        let distance = to_light.len();
+        // A point at the light has no direction to it
+        if distance == 0.0 {
+            return None;
+        }
+        let wi = to_light / distance;
+        let mut falloff = self.falloff(Vec3::dot(-wi, self.direction));
+        if let Some(profile) = &self.profile {
+            falloff *= profile.candela(-wi);
+        }
+        if falloff == 0.0 {
+            return None;
+        }
+        Some(DeltaSample {
+            wi,
+            distance,
+            radiance: falloff * self.intensity / (distance * distance),
+        })
+    }
+}
+
+// Parallel light travelling along `direction` from infinitely far away, like
+// the sun, with `irradiance` on a surface facing it
+pub struct DirectionalLight {
+    direction: Vec3,
+    irradiance: Vec3,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3, irradiance: Vec3) -> DirectionalLight {
+        DirectionalLight {
+            direction: Vec3::unit_vector(direction),
+            irradiance,
+        }
+    }
+}
+
+impl DeltaLight for DirectionalLight {
+    fn sample(&self, _p: Vec3) -> Option<DeltaSample> {
+        Some(DeltaSample {
+            wi: -self.direction,
+            distance: f64::INFINITY,
+            radiance: self.irradiance,
+        })
+    }
+}
+
+#[test]
+fn spot_light_falloff() {
+    let spot = SpotLight::new(
+        Vec3::new(0.0, 2.0, 0.0),
+        Vec3::new(0.0, 0.0, 0.0),
+        Vec3::new(4.0, 4.0, 4.0),
+        30.0,
+        20.0,
+    );
+    let at = |x: f64| spot.sample(Vec3::new(x, 0.0, 0.0));
+    let center = at(0.0).unwrap();
+    assert!((center.wi - Vec3::new(0.0, 1.0, 0.0)).len() < 1e-12);
+    assert!((center.radiance.r() - 1.0).abs() < 1e-12);
+    // Full intensity inside the falloff start, less past it, none outside
+    let inside = at(2.0 * 15f64.to_radians().tan()).unwrap();
+    assert!((inside.radiance.r() * inside.distance.powi(2) - 4.0).abs() < 1e-9);
+    let fading = at(2.0 * 25f64.to_radians().tan()).unwrap();
+    let fading = fading.radiance.r() * fading.distance.powi(2);
+    assert!(fading > 0.0 && fading < 4.0);
+    assert!(at(2.0 * 35f64.to_radians().tan()).is_none());
+    assert!(spot.sample(Vec3::new(0.0, 2.0, 0.0)).is_none());
+}
+
+#[test]
+fn point_and_directional_lights() {
+    // Inverse square falloff from a point light
+    let position = Vec3::new(1.0, 2.0, 3.0);
+    let point = PointLight::new(position, Vec3::new(8.0, 4.0, 2.0));
+    for &distance in &[0.5, 1.0, 4.0] {
+        let p = position - distance * Vec3::new(0.0, 0.6, 0.8);
+        let sample = point.sample(p).unwrap();
+        assert!((sample.distance - distance).abs() < 1e-12);
+        assert!((sample.wi - Vec3::new(0.0, 0.6, 0.8)).len() < 1e-12);
+        let expected = Vec3::new(8.0, 4.0, 2.0) / (distance * distance);
+        assert!((sample.radiance - expected).len() < 1e-9 * expected.len());
+    }
+    assert!(point.sample(position).is_none());
+
+    // The same irradiance everywhere, from against its direction
+    let sun = DirectionalLight::new(Vec3::new(0.0, -2.0, 0.0), Vec3::new(3.0, 2.0, 1.0));
+    for &p in &[Vec3::default(), Vec3::new(100.0, -5.0, 7.0)] {
+        let sample = sun.sample(p).unwrap();
+        assert_eq!(sample.wi, Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(sample.distance, f64::INFINITY);
+        assert_eq!(sample.radiance, Vec3::new(3.0, 2.0, 1.0));
+    }
+}
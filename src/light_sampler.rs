@@ -0,0 +1,630 @@
+use crate::distribution::Distribution1D;
+use crate::sampler::remap;
+use crate::vec3::Vec3;
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+// How next-event estimation picks which area light to sample
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum LightSamplerKind {
+    Uniform,
+    Power,
+    Bvh,
+}
+
+impl FromStr for LightSamplerKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "uniform" => Ok(LightSamplerKind::Uniform),
+            "power" => Ok(LightSamplerKind::Power),
+            "bvh" => Ok(LightSamplerKind::Bvh),
+            _ => Err(()),
+        }
+    }
+}
+
+fn component(v: Vec3, axis: usize) -> f64 {
+    match axis {
+        0 => v.x(),
+        1 => v.y(),
+        _ => v.z(),
+    }
+}
+
+fn safe_sqrt(x: f64) -> f64 {
+    x.max(0.0).sqrt()
+}
+
+// Cosine and sine of angle a minus angle b, taken as zero when b is larger
+fn cos_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 1.0;
+    }
+    cos_a * cos_b + sin_a * sin_b
+}
+
+fn sin_sub_clamped(sin_a: f64, cos_a: f64, sin_b: f64, cos_b: f64) -> f64 {
+    if cos_a > cos_b {
+        return 0.0;
+    }
+    sin_a * cos_b - cos_a * sin_b
+}
+
+// Rotates `v` by `angle` radians about the unit vector `axis`
+fn rotate(v: Vec3, axis: Vec3, angle: f64) -> Vec3 {
+    let (sin, cos) = angle.sin_cos();
+    v * cos + Vec3::cross(axis, v) * sin + axis * (Vec3::dot(axis, v) * (1.0 - cos))
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    // The box spanned by two corners
+    pub fn new(a: Vec3, b: Vec3) -> Aabb {
+        Aabb {
+            min: Vec3::new(a.x().min(b.x()), a.y().min(b.y()), a.z().min(b.z())),
+            max: Vec3::new(a.x().max(b.x()), a.y().max(b.y()), a.z().max(b.z())),
+        }
+    }
+
+    pub fn union(self, other: Aabb) -> Aabb {
+        Aabb::new(
+            Aabb::new(self.min, other.min).min,
+            Aabb::new(self.max, other.max).max,
+        )
+    }
+
+    pub fn include(self, p: Vec3) -> Aabb {
+        self.union(Aabb::new(p, p))
+    }
+
+    fn centroid(self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    fn diagonal(self) -> Vec3 {
+        self.max - self.min
+    }
+
+    fn surface_area(self) -> f64 {
+        let d = self.diagonal();
+        2.0 * (d.x() * d.y() + d.y() * d.z() + d.z() * d.x())
+    }
+}
+
+// The directions within an angle of `w`. A `cos_theta` of -1 is every
+// direction.
+#[derive(Copy, Clone, Debug)]
+pub struct DirectionCone {
+    pub w: Vec3,
+    pub cos_theta: f64,
+}
+
+impl DirectionCone {
+    pub fn new(w: Vec3, cos_theta: f64) -> DirectionCone {
+        DirectionCone {
+            w: Vec3::unit_vector(w),
+            cos_theta,
+        }
+    }
+
+    pub fn entire_sphere() -> DirectionCone {
+        DirectionCone::new(Vec3::new(0.0, 0.0, 1.0), -1.0)
+    }
+
+    // The smallest cone holding both
+    pub fn union(self, other: DirectionCone) -> DirectionCone {
+        let theta_a = self.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_b = other.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_d = Vec3::dot(self.w, other.w).clamp(-1.0, 1.0).acos();
+        if (theta_d + theta_b).min(PI) <= theta_a {
+            return self;
+        }
+        if (theta_d + theta_a).min(PI) <= theta_b {
+            return other;
+        }
+        let theta_o = 0.5 * (theta_a + theta_d + theta_b);
+        if theta_o >= PI {
+            return DirectionCone::entire_sphere();
+        }
+        // Turn `self.w` towards `other.w` until the edges meet
+        let axis = Vec3::cross(self.w, other.w);
+        if axis.squared_len() == 0.0 {
+            return DirectionCone::entire_sphere();
+        }
+        let w = rotate(self.w, Vec3::unit_vector(axis), theta_o - theta_a);
+        DirectionCone::new(w, theta_o.cos())
+    }
+}
+
+// What the light hierarchy knows of a light or a group of them: where it is,
+// its power `phi`, and which way it shines. Surface normals lie within
+// `normals`, and light leaves up to `cos_theta_e` away from the normal.
+#[derive(Copy, Clone, Debug)]
+pub struct LightBounds {
+    pub bounds: Aabb,
+    pub phi: f64,
+    pub normals: DirectionCone,
+    pub cos_theta_e: f64,
+    pub two_sided: bool,
+}
+
+impl LightBounds {
+    // A surface of `area` emitting `radiance` from the front of every point,
+    // with its normals within `normals`
+    pub fn new(bounds: Aabb, normals: DirectionCone, area: f64, radiance: Vec3) -> LightBounds {
+        LightBounds {
+            bounds,
+            phi: PI * area * radiance.luminance(),
+            normals,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        }
+    }
+
+    // The same surface emitting from its back as well
+    pub fn two_sided(mut self, two_sided: bool) -> LightBounds {
+        if two_sided && !self.two_sided {
+            self.phi *= 2.0;
+            self.two_sided = true;
+        }
+        self
+    }
+
+    pub fn union(self, other: LightBounds) -> LightBounds {
+        if self.phi == 0.0 {
+            return other;
+        }
+        if other.phi == 0.0 {
+            return self;
+        }
+        LightBounds {
+            bounds: self.bounds.union(other.bounds),
+            phi: self.phi + other.phi,
+            normals: self.normals.union(other.normals),
+            cos_theta_e: self.cos_theta_e.min(other.cos_theta_e),
+            two_sided: self.two_sided || other.two_sided,
+        }
+    }
+
+    // Conservative guess at how much light could reach a surface at `p` with
+    // normal `n`, zero only if none can
+    fn importance(&self, p: Vec3, n: Vec3) -> f64 {
+        let pc = self.bounds.centroid();
+        let to_p = p - pc;
+        let radius = 0.5 * self.bounds.diagonal().len();
+        let d2 = to_p.squared_len().max(radius * radius);
+        let wi = if to_p.squared_len() > 0.0 {
+            Vec3::unit_vector(to_p)
+        } else {
+            self.normals.w
+        };
+        let mut cos_w = Vec3::dot(wi, self.normals.w);
+        if self.two_sided {
+            cos_w = cos_w.abs();
+        }
+        let sin_w = safe_sqrt(1.0 - cos_w * cos_w);
+        // Half angle the bounds subtend from `p`, everything from inside
+        let cos_b = if to_p.squared_len() <= radius * radius {
+            -1.0
+        } else {
+            safe_sqrt(1.0 - radius * radius / to_p.squared_len())
+        };
+        let sin_b = safe_sqrt(1.0 - cos_b * cos_b);
+        // Smallest angle between `p` and a normal, and then between that
+        // and a direction light can leave in
+        let cos_o = self.normals.cos_theta;
+        let sin_o = safe_sqrt(1.0 - cos_o * cos_o);
+        let cos_x = cos_sub_clamped(sin_w, cos_w, sin_o, cos_o);
+        let sin_x = sin_sub_clamped(sin_w, cos_w, sin_o, cos_o);
+        let cos_p = cos_sub_clamped(sin_x, cos_x, sin_b, cos_b);
+        if cos_p <= self.cos_theta_e {
+            return 0.0;
+        }
+        let mut importance = self.phi * cos_p / d2;
+        if n != Vec3::default() {
+            let cos_i = Vec3::dot(wi, n).abs() / n.len();
+            let sin_i = safe_sqrt(1.0 - cos_i * cos_i);
+            importance *= cos_sub_clamped(sin_i, cos_i, sin_b, cos_b);
+        }
+        importance.max(0.0)
+    }
+
+    // Surface area orientation heuristic for splitting along `axis` of
+    // `parent`, the node being split
+    fn cost(&self, parent: Aabb, axis: usize) -> f64 {
+        let theta_o = self.normals.cos_theta.clamp(-1.0, 1.0).acos();
+        let theta_e = self.cos_theta_e.clamp(-1.0, 1.0).acos();
+        let theta_w = (theta_o + theta_e).min(PI);
+        let (sin_o, cos_o) = theta_o.sin_cos();
+        let m_omega = 2.0 * PI * (1.0 - cos_o)
+            + PI / 2.0
+                * (2.0 * theta_w * sin_o - (theta_o - 2.0 * theta_w).cos() - 2.0 * theta_o * sin_o
+                    + cos_o);
+        let d = parent.diagonal();
+        let extent = component(d, axis);
+        let kr = if extent > 0.0 {
+            d.max_component() / extent
+        } else {
+            1.0
+        };
+        self.phi * m_omega * kr * self.bounds.surface_area()
+    }
+}
+
+const BUCKETS: usize = 12;
+
+#[derive(Copy, Clone, Debug)]
+struct Node {
+    bounds: LightBounds,
+    // For a leaf the light, otherwise the second child; the first child
+    // always directly follows its parent
+    index: usize,
+    leaf: bool,
+}
+
+// Picks area lights for a shading point. Uniform ignores everything about the
+// lights, power picks in proportion to how much each emits, and the BVH
+// descends a tree of light bounds, choosing between children by how much
+// light each could send to the point, so that far away or facing away
+// lights are rarely sampled. Lights without bounds are left out of the
+// tree and each picked as often as the whole tree.
+pub struct LightSampler {
+    kind: LightSamplerKind,
+    count: usize,
+    unbounded: Vec<usize>,
+    // Of the lights with bounds, in order
+    bounded: Vec<usize>,
+    power: Option<Distribution1D>,
+    nodes: Vec<Node>,
+    // The way from the root to each light's leaf, one bit per level with the
+    // first in the lowest bit; 1 means the second child
+    trails: Vec<u64>,
+    // For each light, where it is in `bounded` or `unbounded`
+    slots: Vec<usize>,
+}
+
+impl LightSampler {
+    pub fn new(kind: LightSamplerKind, lights: &[Option<LightBounds>]) -> LightSampler {
+        let mut sampler = LightSampler {
+            kind,
+            count: lights.len(),
+            unbounded: Vec::new(),
+            bounded: Vec::new(),
+            power: None,
+            nodes: Vec::new(),
+            trails: vec![0; lights.len()],
+            slots: vec![0; lights.len()],
+        };
+        if kind == LightSamplerKind::Uniform {
+            return sampler;
+        }
+        let mut bounded = Vec::new();
+        for (index, light) in lights.iter().enumerate() {
+            match light {
+                Some(bounds) => {
+                    sampler.slots[index] = sampler.bounded.len();
+                    sampler.bounded.push(index);
+                    bounded.push((index, *bounds));
+                }
+                None => {
+                    sampler.slots[index] = sampler.unbounded.len();
+                    sampler.unbounded.push(index);
+                }
+            }
+        }
+        if bounded.is_empty() {
+            return sampler;
+        }
+        match kind {
+            LightSamplerKind::Power => {
+                let phi: Vec<f64> = bounded.iter().map(|(_, b)| b.phi).collect();
+                sampler.power = Some(Distribution1D::new(&phi));
+            }
+            _ => {
+                // Lights that emit nothing can never be picked
+                bounded.retain(|(_, b)| b.phi > 0.0);
+                if !bounded.is_empty() {
+                    sampler.build(&mut bounded, 0, 0);
+                }
+            }
+        }
+        sampler
+    }
+
+    pub fn kind(&self) -> LightSamplerKind {
+        self.kind
+    }
+
+    // Chance of picking one of the lights without bounds
+    fn unbounded_probability(&self) -> f64 {
+        let n = self.unbounded.len() as f64;
+        if self.power.is_none() && self.nodes.is_empty() {
+            1.0
+        } else {
+            n / (n + 1.0)
+        }
+    }
+
+    fn build(&mut self, lights: &mut [(usize, LightBounds)], trail: u64, depth: u32) -> usize {
+        let node = self.nodes.len();
+        if lights.len() == 1 {
+            let (index, bounds) = lights[0];
+            self.nodes.push(Node {
+                bounds,
+                index,
+                leaf: true,
+            });
+            self.trails[index] = trail;
+            return node;
+        }
+        let bounds = lights[1..]
+            .iter()
+            .fold(lights[0].1, |b, (_, l)| b.union(*l));
+        let centroids = lights[1..].iter().fold(
+            Aabb::new(lights[0].1.bounds.centroid(), lights[0].1.bounds.centroid()),
+            |b, (_, l)| b.include(l.bounds.centroid()),
+        );
+        let bucket = |light: &LightBounds, axis: usize| {
+            let min = component(centroids.min, axis);
+            let extent = component(centroids.diagonal(), axis);
+            let offset = (component(light.bounds.centroid(), axis) - min) / extent;
+            ((offset * BUCKETS as f64) as usize).min(BUCKETS - 1)
+        };
+        // Halving fits the rest of the tree into ceil(log2(n)) levels. Once
+        // that is all the trail has bits left for, it's the only choice.
+        let levels = usize::BITS - (lights.len() - 1).leading_zeros();
+        let halve = depth + levels >= u64::BITS;
+        // Cheapest split between buckets along any axis
+        let mut best: Option<(f64, usize, usize)> = None;
+        for axis in 0..3 {
+            if halve || component(centroids.diagonal(), axis) <= 0.0 {
+                continue;
+            }
+            let mut buckets: Vec<Option<LightBounds>> = vec![None; BUCKETS];
+            for (_, light) in lights.iter() {
+                let b = &mut buckets[bucket(light, axis)];
+                *b = Some(b.map_or(*light, |b| b.union(*light)));
+            }
+            let union = |part: &[Option<LightBounds>]| {
+                part.iter()
+                    .flatten()
+                    .fold(None, |acc: Option<LightBounds>, b| {
+                        Some(acc.map_or(*b, |acc| acc.union(*b)))
+                    })
+            };
+            for split in 1..BUCKETS {
+                let cost =
+                    |part: Option<LightBounds>| part.map_or(0.0, |p| p.cost(bounds.bounds, axis));
+                let below = union(&buckets[..split]);
+                let above = union(&buckets[split..]);
+                if below.is_none() || above.is_none() {
+                    continue;
+                }
+                let total = cost(below) + cost(above);
+                if best.is_none_or(|(c, _, _)| total < c) {
+                    best = Some((total, axis, split));
+                }
+            }
+        }
+        let mid = match best {
+            Some((_, axis, split)) => {
+                let mut mid = 0;
+                for i in 0..lights.len() {
+                    if bucket(&lights[i].1, axis) < split {
+                        lights.swap(i, mid);
+                        mid += 1;
+                    }
+                }
+                mid
+            }
+            // All centroids coincide, or the tree is as deep as it can go
+            None => lights.len() / 2,
+        };
+        self.nodes.push(Node {
+            bounds,
+            index: 0,
+            leaf: false,
+        });
+        let (first, second) = lights.split_at_mut(mid);
+        self.build(first, trail, depth + 1);
+        let second = self.build(second, trail | (1 << depth), depth + 1);
+        self.nodes[node].index = second;
+        node
+    }
+
+    // Picks a light for a surface at `p` with normal `n`, which may be zero
+    // when there is no surface. Returns the light, the probability it was
+    // picked with and `u` remapped to [0, 1) for reuse.
+    pub fn sample(&self, p: Vec3, n: Vec3, u: f64) -> Option<(usize, f64, f64)> {
+        if self.count == 0 {
+            return None;
+        }
+        if self.kind == LightSamplerKind::Uniform {
+            let count = self.count as f64;
+            let i = ((u * count) as usize).min(self.count - 1);
+            return Some((i, 1.0 / count, remap(u * count, i as f64, 1.0)));
+        }
+        let p_unbounded = self.unbounded_probability();
+        if u < p_unbounded {
+            let count = self.unbounded.len() as f64;
+            let u = u / p_unbounded * count;
+            let i = (u as usize).min(self.unbounded.len() - 1);
+            return Some((
+                self.unbounded[i],
+                p_unbounded / count,
+                remap(u, i as f64, 1.0),
+            ));
+        }
+        let mut u = remap(u, p_unbounded, 1.0 - p_unbounded);
+        let mut pmf = 1.0 - p_unbounded;
+        if let Some(power) = &self.power {
+            let (x, _, i) = power.sample(u);
+            let u = remap(x * power.count() as f64, i as f64, 1.0);
+            return Some((self.bounded[i], pmf * power.pmf(i), u));
+        }
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let mut node = 0;
+        while !self.nodes[node].leaf {
+            let first = node + 1;
+            let second = self.nodes[node].index;
+            let c0 = self.nodes[first].bounds.importance(p, n);
+            let c1 = self.nodes[second].bounds.importance(p, n);
+            if c0 == 0.0 && c1 == 0.0 {
+                return None;
+            }
+            let p0 = c0 / (c0 + c1);
+            if u < p0 {
+                u = remap(u, 0.0, p0);
+                pmf *= p0;
+                node = first;
+            } else {
+                u = remap(u, p0, 1.0 - p0);
+                pmf *= 1.0 - p0;
+                node = second;
+            }
+        }
+        if self.nodes[node].bounds.importance(p, n) == 0.0 {
+            return None;
+        }
+        Some((self.nodes[node].index, pmf, u))
+    }
+
+    // Probability of `sample` picking `light` for the same `p` and `n`
+    pub fn pmf(&self, p: Vec3, n: Vec3, light: usize) -> f64 {
+        if self.kind == LightSamplerKind::Uniform {
+            return 1.0 / self.count as f64;
+        }
+        let p_unbounded = self.unbounded_probability();
+        let slot = self.slots[light];
+        if self.unbounded.get(slot) == Some(&light) {
+            return p_unbounded / self.unbounded.len() as f64;
+        }
+        let mut pmf = 1.0 - p_unbounded;
+        if let Some(power) = &self.power {
+            return pmf * power.pmf(slot);
+        }
+        if self.nodes.is_empty() {
+            return 0.0;
+        }
+        let mut trail = self.trails[light];
+        let mut node = 0;
+        while !self.nodes[node].leaf {
+            let first = node + 1;
+            let second = self.nodes[node].index;
+            let c0 = self.nodes[first].bounds.importance(p, n);
+            let c1 = self.nodes[second].bounds.importance(p, n);
+            if c0 == 0.0 && c1 == 0.0 {
+                return 0.0;
+            }
+            if trail & 1 == 0 {
+                pmf *= c0 / (c0 + c1);
+                node = first;
+            } else {
+                pmf *= c1 / (c0 + c1);
+                node = second;
+            }
+            trail >>= 1;
+        }
+        if self.nodes[node].index != light || self.nodes[node].bounds.importance(p, n) == 0.0 {
+            return 0.0;
+        }
+        pmf
+    }
+}
+
+#[test]
+fn bvh_sampler_favors_nearby_lights() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    // Small panels facing down over a city block
+    let mut rng = StdRng::seed_from_u64(3);
+    let lights: Vec<Option<LightBounds>> = (0..10_000)
+        .map(|_| {
+            let corner = Vec3::new(
+                100.0 * rng.gen::<f64>() - 50.0,
+                0.1 + 5.0 * rng.gen::<f64>(),
+                100.0 * rng.gen::<f64>() - 50.0,
+            );
+            Some(LightBounds::new(
+                Aabb::new(corner, corner + Vec3::new(0.2, 0.0, 0.2)),
+                DirectionCone::new(Vec3::new(0.0, -1.0, 0.0), 1.0),
+                0.04,
+                Vec3::new(1.0, 1.0, 1.0) * (0.5 + rng.gen::<f64>()),
+            ))
+        })
+        .collect();
+    let (p, n) = (Vec3::new(-40.0, 0.0, 10.0), Vec3::new(0.0, 1.0, 0.0));
+    // Irradiance each light gives, treating it as a point
+    let contribution = |i: usize| match lights[i] {
+        Some(light) => {
+            let to_light = light.bounds.centroid() - p;
+            let d2 = to_light.squared_len();
+            light.phi / PI * (to_light.y() / d2.sqrt()).powi(2) / d2
+        }
+        None => 0.0,
+    };
+    let total: f64 = (0..lights.len()).map(contribution).sum();
+    let mut variances = Vec::new();
+    for &kind in &[
+        LightSamplerKind::Uniform,
+        LightSamplerKind::Power,
+        LightSamplerKind::Bvh,
+    ] {
+        let sampler = LightSampler::new(kind, &lights);
+        let pmfs: Vec<f64> = (0..lights.len()).map(|i| sampler.pmf(p, n, i)).collect();
+        assert!((pmfs.iter().sum::<f64>() - 1.0).abs() < 1e-9, "{:?}", kind);
+        for k in 0..100 {
+            let u = (k as f64 + 0.5) / 100.0;
+            let (i, pmf, u) = sampler.sample(p, n, u).unwrap();
+            assert!((pmf - pmfs[i]).abs() <= 1e-12 * pmf, "{:?}", kind);
+            assert!((0.0..1.0).contains(&u));
+        }
+        // Variance of estimating the total from one light
+        let second_moment: f64 = (0..lights.len())
+            .filter(|&i| pmfs[i] > 0.0)
+            .map(|i| contribution(i).powi(2) / pmfs[i])
+            .sum();
+        variances.push(second_moment - total * total);
+    }
+    assert!(
+        variances[2] < 0.2 * variances[0] && variances[2] < 0.2 * variances[1],
+        "{:?}",
+        variances
+    );
+}
+
+#[test]
+fn bvh_sampler_builds_deep_trees() {
+    // Each light four times as far out as the last, so every split only
+    // peels off the farthest one or two and the tree would be deeper than
+    // its trails have bits for
+    let lights: Vec<Option<LightBounds>> = (0..250)
+        .map(|k| {
+            let corner = Vec3::new(4f64.powi(k), 0.0, 0.0);
+            Some(LightBounds::new(
+                Aabb::new(corner, corner + Vec3::new(0.1, 0.1, 0.1)),
+                DirectionCone::entire_sphere(),
+                0.01,
+                Vec3::new(1.0, 1.0, 1.0),
+            ))
+        })
+        .collect();
+    let sampler = LightSampler::new(LightSamplerKind::Bvh, &lights);
+    let (p, n) = (Vec3::new(0.0, 1.0, 0.0), Vec3::default());
+    let pmfs: Vec<f64> = (0..lights.len()).map(|i| sampler.pmf(p, n, i)).collect();
+    assert!((pmfs.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    for k in 0..100 {
+        let (i, pmf, _) = sampler.sample(p, n, (k as f64 + 0.5) / 100.0).unwrap();
+        assert!((pmf - pmfs[i]).abs() <= 1e-12 * pmf);
+    }
+}
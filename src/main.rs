@@ -1,173 +1,247 @@
 extern crate rand;
 
+mod aov;
+mod aperture;
 mod camera;
+mod checkpoint;
+mod denoise;
+mod distribution;
+mod film;
 mod hitable;
+mod ies;
+mod image;
+mod integrator;
+mod lens;
+mod light;
+mod light_sampler;
 mod material;
+mod onb;
+mod options;
+mod output;
+mod progress;
 mod ray;
+mod rect;
+mod render;
+mod sampler;
+mod scene;
+mod sky;
 mod sphere;
+mod stats;
+mod texture;
+mod tonemap;
+mod triangle;
 mod vec3;
 
-use camera::Camera;
-use hitable::{HitRecord, Hitable, HitableList};
-use material::{Dielectric, Lambertian, Metal};
-use rand::{thread_rng, Rng};
-use ray::Ray;
-use sphere::Sphere;
+use aov::{AovBuffers, AovKind};
+use denoise::{DenoiserKind, Guides};
+use film::{Film, Filter};
+use image::Image;
+use integrator::PathTracer;
+use options::{Options, USAGE};
+use progress::Progress;
+use render::{Renderer, SampleSettings};
+use sampler::new_sampler;
+use scene::{Scene, DEFAULT_SCENE};
+use std::time::Instant;
+use tonemap::OutputTransform;
 use vec3::Vec3;
 
-fn generate_random_scene() -> HitableList {
-    let mut rng = thread_rng();
-    let mut world = HitableList::new();
-    world.push(Box::new(Sphere::new(
-        Vec3::new(0.0, -1000.0, 0.0),
-        1000.0,
-        Lambertian::new(Vec3::new(0.5, 0.5, 0.5)),
-    )));
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat = rng.gen::<f64>();
-            let center = Vec3::new(
-                a as f64 + 0.9 * rng.gen::<f64>(),
-                0.2,
-                b as f64 + 0.9 * rng.gen::<f64>(),
-            );
-            if (center - Vec3::new(4.0, 0.2, 0.0)).len() > 0.9 {
-                if choose_mat < 0.8 {
-                    //Lambertian
-                    world.push(Box::new(Sphere::new(
-                        center,
-                        0.2,
-                        Lambertian::new(Vec3::new(
-                            rng.gen::<f64>() * rng.gen::<f64>(),
-                            rng.gen::<f64>() * rng.gen::<f64>(),
-                            rng.gen::<f64>() * rng.gen::<f64>(),
-                        )),
-                    )));
-                } else if choose_mat < 0.95 {
-                    // Metal
-                    world.push(Box::new(Sphere::new(
-                        center,
-                        0.2,
-                        Metal::new(
-                            Vec3::new(
-                                0.5 * (1.0 + rng.gen::<f64>()),
-                                0.5 * (1.0 + rng.gen::<f64>()),
-                                0.5 * (1.0 + rng.gen::<f64>()),
-                            ),
-                            0.5 * rng.gen::<f64>(),
-                        ),
-                    )));
-                } else {
-                    world.push(Box::new(Sphere::new(center, 0.2, Dielectric::new(1.5))))
-                }
-            }
+// Writes a checkpoint and, when rendering to a file, the image so far
+fn save_progress(
+    renderer: &Renderer,
+    path: &str,
+    key: &str,
+    options: &Options,
+    transform: &OutputTransform,
+) {
+    let start = Instant::now();
+    if let Err(err) = renderer.save_checkpoint(path, key) {
+        eprintln!("error: could not write checkpoint {}: {}", path, err);
+    }
+    if let Some(output) = &options.output {
+        let image = Image::from_film(renderer.film());
+        if let Err(err) = output::write_image(Some(output), &image, transform) {
+            eprintln!("error: could not write {}: {}", output, err);
         }
     }
-    world.push(Box::new(Sphere::new(
-        Vec3::new(0.0, 1.0, 0.0),
-        1.0,
-        Dielectric::new(1.5),
-    )));
-    world.push(Box::new(Sphere::new(
-        Vec3::new(-4.0, 1.0, 0.0),
-        1.0,
-        Lambertian::new(Vec3::new(0.4, 0.2, 0.1)),
-    )));
-    world.push(Box::new(Sphere::new(
-        Vec3::new(4.0, 1.0, 0.0),
-        1.0,
-        Metal::new(Vec3::new(0.7, 0.6, 0.5), 0.0),
-    )));
-    //world.rev();
-    world
+    stats::phase("checkpoint", start.elapsed());
 }
 
-fn color(ray: &Ray, world: &HitableList, depth: i16) -> Vec3 {
-    let mut rec = HitRecord::new();
-    if world.hit(ray, 0.001, std::f64::MAX, &mut rec) {
-        let mut scattered: Ray = Default::default();
-        let mut attenuation: Vec3 = Default::default();
-        if depth < 50
-            && rec
-                .material
-                .scatter(ray, &rec, &mut attenuation, &mut scattered)
-        {
-            return attenuation * color(&scattered, world, depth + 1);
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = match Options::parse(args.into_iter()) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let x = options.width;
+    let y = options.height;
+    let s = options.samples;
+    let integrator = PathTracer::new(options.min_depth, options.max_depth);
+    let scene_start = Instant::now();
+    let description = match &options.scene {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) => {
+                eprintln!("error: could not read {}: {}", path, err);
+                std::process::exit(1);
+            }
+        },
+        None => DEFAULT_SCENE.to_string(),
+    };
+    let mut scene = match Scene::parse(&description, x, y, options.seed) {
+        Ok(scene) => scene,
+        Err(err) => {
+            let name = options.scene.as_deref().unwrap_or("scene");
+            eprintln!("error: {}: {}", name, err);
+            std::process::exit(1);
+        }
+    };
+    if scene.lights.sampler_kind() != options.light_sampler {
+        scene.lights.set_sampler(options.light_sampler);
+    }
+    stats::phase("scene", scene_start.elapsed());
+    let settings = SampleSettings {
+        samples: s,
+        min_samples: options.min_samples,
+        max_samples: options.max_samples,
+        noise_threshold: options.noise_threshold,
+        pass_samples: options.pass_samples,
+    };
+    let mut sampler = new_sampler(options.sampler, s, options.seed);
+    let film = Film::new(x, y, Filter::new(options.filter, options.filter_radius));
+    let mut renderer = Renderer::new(film, settings);
+    let transform = OutputTransform::new(options.exposure, options.tone_mapper);
+    // Everything besides the sample counts that a resumed render must share
+    // with the one that wrote the checkpoint. The scene is keyed by its text,
+    // so editing it invalidates the checkpoint; files it refers to are not
+    // covered.
+    let checkpoint_key = format!(
+        "{}x{} scene {:016x} seed {} sampler {:?} lights {:?} filter {:?} {:?} depth {}..{}",
+        x,
+        y,
+        checkpoint::fingerprint(description.as_bytes()),
+        options.seed,
+        options.sampler,
+        options.light_sampler,
+        options.filter,
+        options.filter_radius,
+        options.min_depth,
+        options.max_depth
+    );
+    if let (true, Some(path)) = (options.resume, &options.checkpoint) {
+        if std::path::Path::new(path).exists() {
+            if let Err(err) = renderer.load_checkpoint(path, &checkpoint_key) {
+                eprintln!("error: could not resume from {}: {}", path, err);
+                std::process::exit(1);
+            }
+            eprintln!("resuming {} after pass {}", path, renderer.passes());
         } else {
-            return Vec3::default();
+            eprintln!("no checkpoint at {}, starting a new render", path);
         }
-    } else {
-        let unit_dir = Vec3::unit_vector(ray.direction());
-        let t = 0.5 * (unit_dir.y() + 1.0);
-        return (1.0 - t) * Vec3::new(1.0, 1.0, 1.0) + t * Vec3::new(0.5, 0.7, 1.0);
     }
-}
 
-fn spheres() -> HitableList {
-    let mut world = HitableList::new();
-    world.push(Box::new(Sphere::new(
-        Vec3::new(0.0, 0.0, -1.0),
-        0.5,
-        Lambertian::new(Vec3::new(0.1, 0.2, 0.5)),
-    )));
-    world.push(Box::new(Sphere::new(
-        Vec3::new(0.0, -100.5, -1.0),
-        100.0,
-        Lambertian::new(Vec3::new(0.8, 0.8, 0.0)),
-    )));
-    world.push(Box::new(Sphere::new(
-        Vec3::new(1.0, 0.0, -1.0),
-        0.5,
-        Metal::new(Vec3::new(0.8, 0.6, 0.2), 1.0),
-    )));
-    world.push(Box::new(Sphere::new(
-        Vec3::new(-1.0, 0.0, -1.0),
-        0.5,
-        Dielectric::new(1.5),
-    )));
-    // world.push(Box::new(Sphere::new(
-    //     Vec3::new(-1.0, 0.0, -1.0),
-    //     -0.45,
-    //     Dielectric::new(1.5),
-    // )));
-    world
-}
-fn main() {
-    let x = 1920;
-    let y = 1080;
-    let s = 200;
-    let world = generate_random_scene();
-    let look_from = Vec3::new(13.0, 2.0, 3.0);
-    let look_at = Vec3::new(0.0, 0.0, 0.0);
-    let dist_to_focus = 10.0;
-    let aperture = 0.1;
-    let camera = Camera::new(
-        look_from,
-        look_at,
-        Vec3::new(0.0, 1.0, 0.0),
-        20.0,
-        x as f64 / y as f64,
-        aperture,
-        dist_to_focus,
+    let progress = Progress::new(
+        (s * x * y) as u64,
+        renderer.samples_taken() as u64,
+        !options.quiet,
     );
-    let mut rng = thread_rng();
-    print!("P3\n{} {}\n255\n", x, y);
-    for j in (0..=y - 1).rev() {
-        for i in 0..x {
-            let mut col = Vec3::new(0.0, 0.0, 0.0);
-            for _k in 0..s {
-                let u = ((i as f64) + rng.gen::<f64>()) / x as f64;
-                let v = ((j as f64) + rng.gen::<f64>()) / y as f64;
-                let r = camera.get_ray(u, v);
-                let p = r.point_at_parameter(2.0);
-                col += color(&r, &world, 0);
+    // The denoiser is guided by the albedo and normal passes
+    let mut aov_kinds = options.aovs.clone();
+    if options.denoiser != DenoiserKind::None {
+        for &kind in &[AovKind::Albedo, AovKind::Normal] {
+            if !aov_kinds.contains(&kind) {
+                aov_kinds.push(kind);
+            }
+        }
+    }
+    let mut aovs = AovBuffers::new(&aov_kinds, x, y).with_material_ids(scene.material_ids.clone());
+    let mut sample = |i: usize, j: usize, index: usize| {
+        sampler.start_pixel_sample((i, j), index);
+        let (du, dv) = sampler.get_2d();
+        let (fx, fy) = (i as f64 + du, j as f64 + dv);
+        let (u, v) = (fx / x as f64, fy / y as f64);
+        let ray = scene.camera.generate_weighted_ray(u, v, sampler.as_mut());
+        let (col, first_hit) = match ray {
+            Some((r, weight)) => {
+                let (col, first_hit) =
+                    integrator.color(&r, &scene.world, &scene.lights, sampler.as_mut());
+                (scene.exposure * weight * col, first_hit)
             }
-            col /= s as f64;
-            col = Vec3::new(col.r().sqrt(), col.g().sqrt(), col.b().sqrt());
-            let ir = (255.99 * col.r()) as u16;
-            let ig = (255.99 * col.g()) as u16;
-            let ib = (255.99 * col.b()) as u16;
-            println!("{} {} {}", ir, ig, ib)
+            None => (Vec3::default(), None),
+        };
+        if !aovs.is_empty() {
+            aovs.add(i, j, first_hit);
         }
+        progress.tick();
+        ((fx, fy), col)
+    };
+    let mut last_checkpoint = Instant::now();
+    loop {
+        progress.start_pass(renderer.passes() + 1);
+        let pass_start = Instant::now();
+        let rendered = renderer.render_pass(&mut sample);
+        stats::phase("render", pass_start.elapsed());
+        if !rendered {
+            break;
+        }
+        if let Some(path) = &options.checkpoint {
+            if last_checkpoint.elapsed().as_secs_f64() >= options.checkpoint_interval {
+                save_progress(&renderer, path, &checkpoint_key, &options, &transform);
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
+    progress.finish();
+    if let Some(path) = &options.checkpoint {
+        save_progress(&renderer, path, &checkpoint_key, &options, &transform);
+    }
+
+    if let Some(path) = &options.sample_map {
+        if let Err(err) = render::write_sample_map(path, x, y, renderer.pixels()) {
+            eprintln!("error: could not write {}: {}", path, err);
+        }
+    }
+    let mut image = Image::from_film(renderer.film());
+    if options.denoiser != DenoiserKind::None {
+        let denoise_start = Instant::now();
+        let variance: Vec<f64> = renderer.pixels().iter().map(|p| p.variance()).collect();
+        let guides = Guides {
+            albedo: &aovs.image(AovKind::Albedo).unwrap(),
+            normal: &aovs.image(AovKind::Normal).unwrap(),
+            variance: &variance,
+        };
+        image = denoise::denoise(options.denoiser, &image, &guides);
+        stats::phase("denoise", denoise_start.elapsed());
+    }
+    if let Some(path) = &options.reference {
+        match output::read_pfm(path) {
+            Ok(reference) if (reference.width(), reference.height()) == (x, y) => {
+                eprintln!("mse against {}: {:e}", path, image.mse(&reference))
+            }
+            Ok(_) => eprintln!("error: {} has a different image size", path),
+            Err(err) => eprintln!("error: could not read {}: {}", path, err),
+        }
+    }
+    let output_start = Instant::now();
+    if let Err(err) = output::write_image(options.output.as_deref(), &image, &transform) {
+        eprintln!("error: could not write image: {}", err);
+        std::process::exit(1);
+    }
+    if !options.aovs.is_empty() {
+        if let Err(err) = aovs.write(&options.aov_prefix(), &options.aovs) {
+            eprintln!("error: could not write AOVs: {}", err);
+            std::process::exit(1);
+        }
+    }
+    stats::phase("output", output_start.elapsed());
+    if let Some(format) = options.stats {
+        eprintln!("{}", stats::snapshot().format(format));
     }
 }
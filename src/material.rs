@@ -1,22 +1,99 @@
 use crate::hitable::HitRecord;
 use crate::ray::Ray;
-use crate::sphere;
+use crate::sampler::sample_cosine_hemisphere;
+use crate::texture::{ConstantTexture, Texture};
 use crate::vec3::Vec3;
-use rand::{thread_rng, Rng};
+use std::f64::consts::PI;
+use std::ops::BitOr;
 use std::rc::Rc;
 
+// Kinds of lobe a BSDF scatters into
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BsdfFlags(u8);
+
+impl BsdfFlags {
+    pub const NONE: BsdfFlags = BsdfFlags(0);
+    pub const REFLECTION: BsdfFlags = BsdfFlags(1);
+    pub const TRANSMISSION: BsdfFlags = BsdfFlags(1 << 1);
+    pub const DIFFUSE: BsdfFlags = BsdfFlags(1 << 2);
+    pub const GLOSSY: BsdfFlags = BsdfFlags(1 << 3);
+    pub const SPECULAR: BsdfFlags = BsdfFlags(1 << 4);
+
+    pub fn contains(self, other: BsdfFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    // True when every lobe is specular, so `eval` and `pdf` are always zero
+    pub fn is_specular(self) -> bool {
+        self.contains(BsdfFlags::SPECULAR)
+            && !self.contains(BsdfFlags::DIFFUSE)
+            && !self.contains(BsdfFlags::GLOSSY)
+    }
+}
+
+impl BitOr for BsdfFlags {
+    type Output = BsdfFlags;
+
+    fn bitor(self, other: BsdfFlags) -> BsdfFlags {
+        BsdfFlags(self.0 | other.0)
+    }
+}
+
+// For specular samples `f` and `pdf` follow the usual delta convention:
+// `f * |cos| / pdf` is the weight of the path, neither is meaningful alone.
+#[derive(Copy, Clone, Debug)]
+pub struct BsdfSample {
+    pub wi: Vec3,
+    pub f: Vec3,
+    pub pdf: f64,
+    pub flags: BsdfFlags,
+}
+
+// Directions are in the local shading frame, with the surface normal along
+// +z, and both `wo` and `wi` point away from the surface. `sample` draws on
+// one 1D sample `uc`, used to choose between lobes, and one 2D sample `u`.
 pub trait Material {
-    fn scatter(
-        &self,
-        ray_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool;
+    fn sample(&self, wo: Vec3, uc: f64, u: (f64, f64)) -> Option<BsdfSample>;
+
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3;
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f64;
+
+    // Union of the lobes `sample` can return
+    fn flags(&self) -> BsdfFlags;
+
+    // Overall surface color, for AOVs and denoising guides
+    fn albedo(&self) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
+
+    fn emitted(&self, _ray_in: &Ray, _hit_record: &HitRecord) -> Vec3 {
+        Vec3::default()
+    }
+
+    // Average radiance `emitted` from the front, for estimating light power
+    fn emission(&self) -> Vec3 {
+        Vec3::default()
+    }
+
+    // Whether `emitted` is the same from the back as from the front
+    fn two_sided(&self) -> bool {
+        false
+    }
+
+    // Where over the surface's (u, v) `emitted` is bright, as for
+    // Texture::importance
+    fn emission_importance(&self) -> Option<(Vec<f64>, usize, usize)> {
+        None
+    }
+}
+
+fn same_hemisphere(a: Vec3, b: Vec3) -> bool {
+    a.z() * b.z() > 0.0
 }
 
 fn schlick(cosine: f64, refractive_idx: f64) -> f64 {
-    let r0 = (1.0 - refractive_idx / 1.0 + refractive_idx).powi(2);
+    let r0 = ((1.0 - refractive_idx) / (1.0 + refractive_idx)).powi(2);
     r0 + (1.0 - r0) * (1.0 - cosine).powi(5)
 }
 
@@ -24,15 +101,15 @@ fn reflect(v: Vec3, n: Vec3) -> Vec3 {
     v - 2.0 * Vec3::dot(v, n) * n
 }
 
-fn refract(v: &Vec3, n: &Vec3, ni_over_t: f64, refracted: &mut Vec3) -> bool {
+pub fn refract(v: &Vec3, n: &Vec3, ni_over_t: f64, refracted: &mut Vec3) -> bool {
     let uv = Vec3::unit_vector(*v);
     let dt = Vec3::dot(uv, *n);
     let discriminant = 1.0 - ni_over_t.powi(2) * (1.0 - dt * dt);
     if discriminant > 0.0 {
         *refracted = ni_over_t * (uv - (*n) * dt) - (*n) * discriminant.sqrt();
-        return true;
+        true
     } else {
-        return false;
+        false
     }
 }
 
@@ -43,55 +120,265 @@ pub struct Lambertian {
 
 impl Lambertian {
     pub fn new(albedo: Vec3) -> Rc<Lambertian> {
-        Rc::new(Lambertian { albedo: albedo })
+        Rc::new(Lambertian { albedo })
     }
 }
 
 impl Material for Lambertian {
-    fn scatter(
-        &self,
-        _ray_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
-        let target = hit_record.p + hit_record.normal + sphere::random_in_unit_sphere();
-        *scattered = Ray::new(hit_record.p, target - hit_record.p);
-        *attenuation = self.albedo;
-        return true;
+    fn sample(&self, wo: Vec3, _uc: f64, u: (f64, f64)) -> Option<BsdfSample> {
+        // Cosine distributed about whichever side of the surface `wo` is on
+        let (x, y, z) = sample_cosine_hemisphere(u);
+        let wi = Vec3::new(x, y, z.max(1e-9).copysign(wo.z()));
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, wi),
+            pdf: self.pdf(wo, wi),
+            flags: BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION,
+        })
     }
+
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if same_hemisphere(wo, wi) {
+            self.albedo / PI
+        } else {
+            Vec3::default()
+        }
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if same_hemisphere(wo, wi) {
+            wi.z().abs() / PI
+        } else {
+            0.0
+        }
+    }
+
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::DIFFUSE | BsdfFlags::REFLECTION
+    }
+
+    fn albedo(&self) -> Vec3 {
+        self.albedo
+    }
+}
+
+// Fresnel reflectance of a conductor with complex index of refraction
+// `eta` + i `k` for light arriving at `cos_theta` to the normal from air
+fn fresnel_conductor(cos_theta: f64, eta: f64, k: f64) -> f64 {
+    let cos2 = cos_theta.clamp(0.0, 1.0).powi(2);
+    let sin2 = 1.0 - cos2;
+    let t0 = eta * eta - k * k - sin2;
+    let a2_plus_b2 = (t0 * t0 + 4.0 * eta * eta * k * k).sqrt();
+    let t1 = a2_plus_b2 + cos2;
+    let a = (0.5 * (a2_plus_b2 + t0)).max(0.0).sqrt();
+    let t2 = 2.0 * cos2.sqrt() * a;
+    let rs = (t1 - t2) / (t1 + t2);
+    let t3 = cos2 * a2_plus_b2 + sin2 * sin2;
+    let t4 = t2 * sin2;
+    let rp = rs * (t3 - t4) / (t3 + t4);
+    0.5 * (rs + rp)
 }
 
+// Measured n and k of some metals at 650, 550 and 450 nm
+pub fn metal_preset(name: &str) -> Option<(Vec3, Vec3)> {
+    match name {
+        "gold" => Some((
+            Vec3::new(0.143, 0.374, 1.442),
+            Vec3::new(3.983, 2.385, 1.603),
+        )),
+        "copper" => Some((
+            Vec3::new(0.200, 0.924, 1.102),
+            Vec3::new(3.912, 2.452, 2.142),
+        )),
+        "aluminum" => Some((
+            Vec3::new(1.657, 0.880, 0.521),
+            Vec3::new(9.224, 6.270, 4.837),
+        )),
+        _ => None,
+    }
+}
+
+// Below this roughness the surface is treated as a perfect mirror
+const SMOOTH_ROUGHNESS: f64 = 1e-3;
+
+// A conductor with GGX (Trowbridge-Reitz) microfacets and Smith's height
+// correlated shadowing-masking. `alpha_x` and `alpha_y` are the roughness
+// along the x and y axes of the shading frame.
 #[derive(Clone)]
 pub struct Metal {
-    albedo: Vec3,
-    fuzz: f64,
+    eta: Vec3,
+    k: Vec3,
+    alpha_x: f64,
+    alpha_y: f64,
 }
 
 impl Metal {
-    pub fn new(albedo: Vec3, fuzz: f64) -> Rc<Metal> {
+    // A metal reflecting `albedo` at normal incidence, made of a conductor
+    // whose n is 1 so that k alone sets the reflectance
+    pub fn new(albedo: Vec3, roughness: (f64, f64)) -> Rc<Metal> {
+        let k = |r: f64| {
+            let r = r.clamp(0.0, 1.0 - 1e-9);
+            2.0 * (r / (1.0 - r)).sqrt()
+        };
+        Metal::conductor(
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(k(albedo.r()), k(albedo.g()), k(albedo.b())),
+            roughness,
+        )
+    }
+
+    pub fn conductor(eta: Vec3, k: Vec3, roughness: (f64, f64)) -> Rc<Metal> {
         Rc::new(Metal {
-            albedo: albedo,
-            fuzz: fuzz,
+            eta,
+            k,
+            alpha_x: roughness.0.max(0.0),
+            alpha_y: roughness.1.max(0.0),
         })
     }
+
+    fn is_smooth(&self) -> bool {
+        self.alpha_x.max(self.alpha_y) < SMOOTH_ROUGHNESS
+    }
+
+    fn fresnel(&self, cos_theta: f64) -> Vec3 {
+        let f = |eta: f64, k: f64| fresnel_conductor(cos_theta, eta, k);
+        Vec3::new(
+            f(self.eta.r(), self.k.r()),
+            f(self.eta.g(), self.k.g()),
+            f(self.eta.b(), self.k.b()),
+        )
+    }
+
+    // Density of microfacet normals `wm`, in the upper hemisphere
+    fn d(&self, wm: Vec3) -> f64 {
+        let cos2 = wm.z() * wm.z();
+        if cos2 == 0.0 {
+            return 0.0;
+        }
+        let (x, y) = (wm.x() / self.alpha_x, wm.y() / self.alpha_y);
+        let e = (x * x + y * y) / cos2;
+        1.0 / (PI * self.alpha_x * self.alpha_y * cos2 * cos2 * (1.0 + e).powi(2))
+    }
+
+    // Smith's auxiliary function, the projected area of back facing
+    // microfacets relative to front facing ones seen from `w`
+    fn lambda(&self, w: Vec3) -> f64 {
+        let cos2 = w.z() * w.z();
+        if cos2 == 0.0 {
+            return f64::INFINITY;
+        }
+        let (x, y) = (w.x() * self.alpha_x, w.y() * self.alpha_y);
+        ((1.0 + (x * x + y * y) / cos2).sqrt() - 1.0) / 2.0
+    }
+
+    fn g1(&self, w: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    fn g(&self, wo: Vec3, wi: Vec3) -> f64 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    // Density of the normals visible from `w`, both in the upper hemisphere
+    fn visible_d(&self, w: Vec3, wm: Vec3) -> f64 {
+        self.g1(w) / w.z() * self.d(wm) * Vec3::dot(w, wm).abs()
+    }
+
+    // Samples the normals visible from `w` (Heitz 2018)
+    fn sample_visible_normal(&self, w: Vec3, u: (f64, f64)) -> Vec3 {
+        // Stretch to the hemisphere configuration of unit roughness
+        let wh = Vec3::unit_vector(Vec3::new(self.alpha_x * w.x(), self.alpha_y * w.y(), w.z()));
+        let t1 = if wh.z() < 0.99999 {
+            Vec3::unit_vector(Vec3::cross(Vec3::new(0.0, 0.0, 1.0), wh))
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = Vec3::cross(wh, t1);
+        // Uniform point on the disk, squeezed onto the visible half of it
+        let r = u.0.sqrt();
+        let phi = 2.0 * PI * u.1;
+        let (p1, p2) = (r * phi.cos(), r * phi.sin());
+        let h = (1.0 - p1 * p1).sqrt();
+        let s = 0.5 * (1.0 + wh.z());
+        let p2 = (1.0 - s) * h + s * p2;
+        let pz = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        let nh = p1 * t1 + p2 * t2 + pz * wh;
+        // And back to the ellipsoid
+        Vec3::unit_vector(Vec3::new(
+            self.alpha_x * nh.x(),
+            self.alpha_y * nh.y(),
+            nh.z().max(1e-6),
+        ))
+    }
+}
+
+// Directions below the surface are mirrored above it, so the metal looks the
+// same from both sides
+fn upper(w: Vec3) -> Vec3 {
+    Vec3::new(w.x(), w.y(), w.z().abs())
 }
 
 impl Material for Metal {
-    fn scatter(
-        &self,
-        ray_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
-        let reflected = reflect(Vec3::unit_vector(ray_in.direction()), hit_record.normal);
-        *scattered = Ray::new(
-            hit_record.p,
-            reflected + self.fuzz * sphere::random_in_unit_sphere(),
-        );
-        *attenuation = self.albedo;
-        return Vec3::dot(scattered.direction(), hit_record.normal) > 0.0;
+    fn sample(&self, wo: Vec3, _uc: f64, u: (f64, f64)) -> Option<BsdfSample> {
+        if wo.z() == 0.0 {
+            return None;
+        }
+        let side = wo.z().signum();
+        let wo_up = upper(wo);
+        if self.is_smooth() {
+            let wi = Vec3::new(-wo.x(), -wo.y(), wo.z());
+            return Some(BsdfSample {
+                wi,
+                f: self.fresnel(wi.z().abs()) / wi.z().abs(),
+                pdf: 1.0,
+                flags: BsdfFlags::SPECULAR | BsdfFlags::REFLECTION,
+            });
+        }
+        let wm = self.sample_visible_normal(wo_up, u);
+        let wi = reflect(-wo_up, wm);
+        if wi.z() <= 0.0 {
+            return None;
+        }
+        let cos_o_m = Vec3::dot(wo_up, wm);
+        let pdf = self.visible_d(wo_up, wm) / (4.0 * cos_o_m.abs());
+        let f = self.d(wm) * self.g(wo_up, wi) / (4.0 * wo_up.z() * wi.z()) * self.fresnel(cos_o_m);
+        Some(BsdfSample {
+            wi: Vec3::new(wi.x(), wi.y(), side * wi.z()),
+            f,
+            pdf,
+            flags: BsdfFlags::GLOSSY | BsdfFlags::REFLECTION,
+        })
+    }
+
+    fn eval(&self, wo: Vec3, wi: Vec3) -> Vec3 {
+        if self.is_smooth() || !same_hemisphere(wo, wi) {
+            return Vec3::default();
+        }
+        let (wo, wi) = (upper(wo), upper(wi));
+        let wm = Vec3::unit_vector(wo + wi);
+        self.d(wm) * self.g(wo, wi) / (4.0 * wo.z() * wi.z()) * self.fresnel(Vec3::dot(wo, wm))
+    }
+
+    fn pdf(&self, wo: Vec3, wi: Vec3) -> f64 {
+        if self.is_smooth() || !same_hemisphere(wo, wi) {
+            return 0.0;
+        }
+        let (wo, wi) = (upper(wo), upper(wi));
+        let wm = Vec3::unit_vector(wo + wi);
+        self.visible_d(wo, wm) / (4.0 * Vec3::dot(wo, wm).abs())
+    }
+
+    fn flags(&self) -> BsdfFlags {
+        if self.is_smooth() {
+            BsdfFlags::SPECULAR | BsdfFlags::REFLECTION
+        } else {
+            BsdfFlags::GLOSSY | BsdfFlags::REFLECTION
+        }
+    }
+
+    // Reflectance at normal incidence
+    fn albedo(&self) -> Vec3 {
+        self.fresnel(1.0)
     }
 }
 
@@ -102,54 +389,231 @@ pub struct Dielectric {
 
 impl Dielectric {
     pub fn new(refractive_idx: f64) -> Rc<Dielectric> {
-        Rc::new(Dielectric {
-            refractive_idx: refractive_idx,
-        })
+        Rc::new(Dielectric { refractive_idx })
     }
 }
 
 impl Material for Dielectric {
-    fn scatter(
-        &self,
-        ray_in: &Ray,
-        hit_record: &HitRecord,
-        attenuation: &mut Vec3,
-        scattered: &mut Ray,
-    ) -> bool {
+    fn sample(&self, wo: Vec3, uc: f64, _u: (f64, f64)) -> Option<BsdfSample> {
         let outward_normal: Vec3;
-        let reflected = reflect(ray_in.direction(), hit_record.normal);
         let ni_over_t: f64;
-        *attenuation = Vec3::new(1.0, 1.0, 1.0);
-        let mut refracted: Vec3 = Default::default();
-        let mut rng = thread_rng();
-        let cosine = if Vec3::dot(ray_in.direction(), hit_record.normal) > 0.0 {
-            outward_normal = -hit_record.normal;
+        let cosine = if wo.z() < 0.0 {
+            outward_normal = Vec3::new(0.0, 0.0, -1.0);
             ni_over_t = self.refractive_idx;
-            /*self.refractive_idx * Vec3::dot(ray_in.direction(), hit_record.normal) / ray_in.direction().len()*/
-            let a = Vec3::dot(ray_in.direction(), hit_record.normal) / ray_in.direction().len();
-            (1.0 - self.refractive_idx.powi(2) * (1.0 - a.powi(2))).sqrt()
+            (1.0 - self.refractive_idx.powi(2) * (1.0 - wo.z().powi(2)))
+                .max(0.0)
+                .sqrt()
         } else {
-            outward_normal = hit_record.normal;
+            outward_normal = Vec3::new(0.0, 0.0, 1.0);
             ni_over_t = 1.0 / self.refractive_idx;
-            -1.0 * Vec3::dot(ray_in.direction(), hit_record.normal) / ray_in.direction().len()
+            wo.z()
         };
 
-        let reflect_prob = if refract(
-            &ray_in.direction(),
-            &outward_normal,
-            ni_over_t,
-            &mut refracted,
-        ) {
+        let mut refracted: Vec3 = Default::default();
+        let reflect_prob = if refract(&-wo, &outward_normal, ni_over_t, &mut refracted) {
             schlick(cosine, self.refractive_idx)
         } else {
             1.0
         };
-        *scattered = if rng.gen::<f64>() < reflect_prob {
-            Ray::new(hit_record.p, reflected.clone())
+        let (wi, pdf, flags) = if uc < reflect_prob {
+            (
+                reflect(-wo, outward_normal),
+                reflect_prob,
+                BsdfFlags::SPECULAR | BsdfFlags::REFLECTION,
+            )
         } else {
-            Ray::new(hit_record.p, refracted.clone())
+            (
+                Vec3::unit_vector(refracted),
+                1.0 - reflect_prob,
+                BsdfFlags::SPECULAR | BsdfFlags::TRANSMISSION,
+            )
         };
+        Some(BsdfSample {
+            wi,
+            f: pdf / wi.z().abs() * Vec3::new(1.0, 1.0, 1.0),
+            pdf,
+            flags,
+        })
+    }
+
+    fn eval(&self, _wo: Vec3, _wi: Vec3) -> Vec3 {
+        Vec3::default()
+    }
+
+    fn pdf(&self, _wo: Vec3, _wi: Vec3) -> f64 {
+        0.0
+    }
+
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::SPECULAR | BsdfFlags::REFLECTION | BsdfFlags::TRANSMISSION
+    }
+}
+
+// Emits `scale` times its texture, from the front of a surface only unless
+// it is two sided
+#[derive(Clone)]
+pub struct DiffuseLight {
+    texture: Rc<dyn Texture>,
+    scale: Vec3,
+    two_sided: bool,
+}
+
+impl DiffuseLight {
+    pub fn new(emit: Vec3) -> Rc<DiffuseLight> {
+        DiffuseLight::textured(
+            Rc::new(ConstantTexture::new(emit)),
+            Vec3::new(1.0, 1.0, 1.0),
+            false,
+        )
+    }
+
+    pub fn textured(texture: Rc<dyn Texture>, scale: Vec3, two_sided: bool) -> Rc<DiffuseLight> {
+        Rc::new(DiffuseLight {
+            texture,
+            scale,
+            two_sided,
+        })
+    }
+}
+
+impl Material for DiffuseLight {
+    fn sample(&self, _wo: Vec3, _uc: f64, _u: (f64, f64)) -> Option<BsdfSample> {
+        None
+    }
+
+    fn eval(&self, _wo: Vec3, _wi: Vec3) -> Vec3 {
+        Vec3::default()
+    }
+
+    fn pdf(&self, _wo: Vec3, _wi: Vec3) -> f64 {
+        0.0
+    }
+
+    fn flags(&self) -> BsdfFlags {
+        BsdfFlags::NONE
+    }
+
+    fn emitted(&self, ray_in: &Ray, hit_record: &HitRecord) -> Vec3 {
+        if self.two_sided || Vec3::dot(ray_in.direction(), hit_record.normal) < 0.0 {
+            self.scale * self.texture.value(hit_record.uv)
+        } else {
+            Vec3::default()
+        }
+    }
+
+    fn emission(&self) -> Vec3 {
+        self.scale * self.texture.average()
+    }
+
+    fn two_sided(&self) -> bool {
+        self.two_sided
+    }
+
+    fn emission_importance(&self) -> Option<(Vec<f64>, usize, usize)> {
+        self.texture.importance()
+    }
+}
+
+// Monte Carlo estimate of the directional albedo for light leaving along `wo`
+#[cfg(test)]
+fn albedo_estimate(material: &dyn Material, wo: Vec3, n: usize) -> Vec3 {
+    use crate::sampler::{IndependentSampler, Sampler};
+    let mut sampler = IndependentSampler::new(0);
+    let mut sum = Vec3::default();
+    for index in 0..n {
+        sampler.start_pixel_sample((0, 0), index);
+        let uc = sampler.get_1d();
+        if let Some(bs) = material.sample(wo, uc, sampler.get_2d()) {
+            if bs.pdf > 0.0 {
+                sum += bs.f * bs.wi.z().abs() / bs.pdf;
+            }
+        }
+    }
+    sum / n as f64
+}
+
+#[test]
+fn bsdf_energy_conservation() {
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let materials: Vec<Rc<dyn Material>> = vec![
+        Lambertian::new(white),
+        Metal::new(white, (0.0, 0.0)),
+        Metal::new(white, (0.5, 0.5)),
+        Metal::conductor(white, Vec3::default(), (0.05, 0.8)),
+        Dielectric::new(1.5),
+    ];
+    for material in &materials {
+        for &cos_theta in &[1.0, 0.7, 0.2, -0.5, -0.9] {
+            let sin_theta: f64 = 1.0 - cos_theta * cos_theta;
+            let wo = Vec3::new(sin_theta.sqrt(), 0.0, cos_theta);
+            let albedo = albedo_estimate(material.as_ref(), wo, 20_000);
+            for &c in &[albedo.r(), albedo.g(), albedo.b()] {
+                assert!(c <= 1.0 + 1e-3, "albedo {} for wo = {}", c, wo);
+            }
+        }
+    }
+
+    // Non-specular lobes must also integrate to at most one through `eval`
+    use crate::sampler::sample_uniform_sphere;
+    let lambertian = Lambertian::new(white);
+    let wo = Vec3::new(0.0, 0.6, 0.8);
+    let n = 200_000;
+    let mut sum = Vec3::default();
+    for i in 0..n {
+        let u = ((i as f64 + 0.5) / n as f64, (i as f64 * 0.618_034).fract());
+        let (x, y, z) = sample_uniform_sphere(u);
+        let wi = Vec3::new(x, y, z);
+        sum += lambertian.eval(wo, wi) * wi.z().abs() * 4.0 * PI;
+    }
+    let albedo = sum / n as f64;
+    assert!((albedo.r() - 1.0).abs() < 0.02, "albedo {}", albedo);
+}
+
+#[test]
+fn microfacet_sampling_matches_eval() {
+    use crate::sampler::{sample_uniform_sphere, IndependentSampler, Sampler};
+
+    let (eta, k) = metal_preset("gold").unwrap();
+    let gold = Metal::conductor(eta, k, (0.0, 0.0));
+    assert!((gold.albedo().r() - 0.967).abs() < 1e-3);
+    assert!(gold.flags().is_specular());
+
+    // Anisotropic and viewed from below, where it mirrors the upper side
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let metal = Metal::conductor(white, 1e9 * white, (0.3, 0.1));
+    let wo = Vec3::new(0.5, 0.3, -0.6);
+    let wo = Vec3::unit_vector(wo);
+    let mut sampler = IndependentSampler::new(0);
+    for index in 0..1000 {
+        sampler.start_pixel_sample((0, 0), index);
+        let uc = sampler.get_1d();
+        if let Some(bs) = metal.sample(wo, uc, sampler.get_2d()) {
+            assert!(bs.wi.z() < 0.0);
+            let (f, pdf) = (metal.eval(wo, bs.wi), metal.pdf(wo, bs.wi));
+            assert!((bs.pdf - pdf).abs() < 1e-6 * pdf, "{} vs {}", bs.pdf, pdf);
+            assert!((bs.f - f).len() < 1e-6 * f.len());
+        }
+    }
 
-        return true;
+    // Estimates of the albedo by sampling and by integrating `eval` agree,
+    // and the density integrates to one less the samples that go below
+    let sampled = albedo_estimate(metal.as_ref(), wo, 100_000);
+    let n = 400_000;
+    let (mut albedo, mut total) = (Vec3::default(), 0.0);
+    for i in 0..n {
+        let u = ((i as f64 + 0.5) / n as f64, (i as f64 * 0.618_034).fract());
+        let (x, y, z) = sample_uniform_sphere(u);
+        let wi = Vec3::new(x, y, z);
+        albedo += metal.eval(wo, wi) * wi.z().abs() * 4.0 * PI;
+        total += metal.pdf(wo, wi) * 4.0 * PI;
     }
+    albedo /= n as f64;
+    total /= n as f64;
+    assert!(
+        (albedo.r() - sampled.r()).abs() < 0.02,
+        "{} vs {}",
+        albedo,
+        sampled
+    );
+    assert!(total <= 1.01 && total > 0.9, "pdf integrates to {}", total);
 }
@@ -0,0 +1,39 @@
+use crate::vec3::Vec3;
+
+// Orthonormal basis with `w` along a given direction
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    pub fn build_from_w(n: Vec3) -> Onb {
+        let w = Vec3::unit_vector(n);
+        let a = if w.x().abs() > 0.9 {
+            Vec3::new(0.0, 1.0, 0.0)
+        } else {
+            Vec3::new(1.0, 0.0, 0.0)
+        };
+        let v = Vec3::unit_vector(Vec3::cross(w, a));
+        let u = Vec3::cross(w, v);
+        Onb { u, v, w }
+    }
+
+    pub fn local(self, a: f64, b: f64, c: f64) -> Vec3 {
+        a * self.u + b * self.v + c * self.w
+    }
+
+    pub fn to_world(self, a: Vec3) -> Vec3 {
+        self.local(a.x(), a.y(), a.z())
+    }
+
+    pub fn to_local(self, a: Vec3) -> Vec3 {
+        Vec3::new(
+            Vec3::dot(a, self.u),
+            Vec3::dot(a, self.v),
+            Vec3::dot(a, self.w),
+        )
+    }
+}
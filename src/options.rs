@@ -0,0 +1,237 @@
+use crate::aov::{AovKind, ALL_AOVS};
+use crate::denoise::DenoiserKind;
+use crate::film::FilterKind;
+use crate::light_sampler::LightSamplerKind;
+use crate::sampler::SamplerKind;
+use crate::stats::StatsFormat;
+use crate::tonemap::ToneMapper;
+use std::str::FromStr;
+
+pub const USAGE: &str = "usage: ray_tracer [options] > image.ppm
+
+options:
+    -o, --output <file> write to file instead of stdout; .pfm files hold
+                        linear radiance, anything else is written as PPM
+    --scene <file>      scene description to render, in the format of
+                        docs/scene-format.md (default: random spheres)
+    --aovs <list>       comma separated auxiliary passes to write: depth,
+                        normal, albedo, position, material_id, object_id,
+                        or all
+    --aov-prefix <path> AOVs go to <path>.<name>.pfm (default: the output
+                        path without its extension, or \"render\")
+    --denoise <name>    denoise the final image with none, atrous or
+                        bilateral, guided by albedo and normals (default none)
+    --reference <file>  print the mean squared error of the final linear
+                        image against this PFM
+    --exposure <stops>  exposure adjustment for PPM output (default 0)
+    --tonemap <name>    tone mapper for PPM output: none, reinhard, aces,
+                        hable or agx (default none)
+    --width <px>        image width (default 1920)
+    --height <px>       image height (default 1080)
+    --samples <n>       samples per pixel, or the average budget per pixel
+                        when sampling adaptively (default 200)
+    --noise-threshold <t>
+                        sample adaptively until each pixel's relative
+                        standard error is below t (default 0, disabled)
+    --min-samples <n>   adaptive: samples every pixel gets (default 16)
+    --max-samples <n>   adaptive: most samples any pixel gets (default 1024)
+    --sample-map <file> write the per-pixel sample counts as a PGM image
+    --pass-samples <n>  samples per pixel in each progressive pass (default 1)
+    --checkpoint <file> periodically save the render state to this file
+    --checkpoint-interval <s>
+                        seconds between checkpoints (default 60)
+    --resume            continue from --checkpoint if it exists; --samples
+                        may be raised to add samples. Not with --denoise
+                        or --aovs
+    --sampler <name>    independent, stratified, halton, sobol or bluenoise
+                        (default sobol)
+    --filter <name>     pixel filter: box, tent, gaussian, mitchell or lanczos
+                        (default box)
+    --filter-radius <px>
+                        filter radius (default 0.5, 1, 1.5, 2 and 3 for the
+                        filters above)
+    --light-sampler <name>
+                        how to pick among many lights: uniform, power or bvh,
+                        which favors lights close to and facing each point
+                        (default bvh)
+    --seed <n>          seed for all random numbers (default 0)
+    --min-depth <n>     bounces before Russian roulette starts (default 3)
+    --max-depth <n>     maximum number of bounces (default 50)
+    -q, --quiet         don't report progress on stderr
+    --stats <format>    print render statistics to stderr as text or json
+    -h, --help          print this message";
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub output: Option<String>,
+    pub scene: Option<String>,
+    pub aovs: Vec<AovKind>,
+    pub aov_prefix: Option<String>,
+    pub denoiser: DenoiserKind,
+    pub reference: Option<String>,
+    pub exposure: f64,
+    pub tone_mapper: ToneMapper,
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    pub min_samples: usize,
+    pub max_samples: usize,
+    pub noise_threshold: f64,
+    pub sample_map: Option<String>,
+    pub pass_samples: usize,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: f64,
+    pub resume: bool,
+    pub sampler: SamplerKind,
+    pub light_sampler: LightSamplerKind,
+    pub seed: u64,
+    pub filter: FilterKind,
+    pub filter_radius: Option<f64>,
+    pub min_depth: u32,
+    pub max_depth: u32,
+    pub quiet: bool,
+    pub stats: Option<StatsFormat>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            output: None,
+            scene: None,
+            aovs: Vec::new(),
+            aov_prefix: None,
+            denoiser: DenoiserKind::None,
+            reference: None,
+            exposure: 0.0,
+            tone_mapper: ToneMapper::None,
+            width: 1920,
+            height: 1080,
+            samples: 200,
+            min_samples: 16,
+            max_samples: 1024,
+            noise_threshold: 0.0,
+            sample_map: None,
+            pass_samples: 1,
+            checkpoint: None,
+            checkpoint_interval: 60.0,
+            resume: false,
+            sampler: SamplerKind::Sobol,
+            light_sampler: LightSamplerKind::Bvh,
+            seed: 0,
+            filter: FilterKind::Box,
+            filter_radius: None,
+            min_depth: 3,
+            max_depth: 50,
+            quiet: false,
+            stats: None,
+        }
+    }
+}
+
+fn value<T: FromStr, I: Iterator<Item = String>>(flag: &str, args: &mut I) -> Result<T, String> {
+    let arg = args
+        .next()
+        .ok_or_else(|| format!("missing value for {}", flag))?;
+    arg.parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, arg))
+}
+
+fn aov_list(list: &str) -> Result<Vec<AovKind>, String> {
+    if list == "all" {
+        return Ok(ALL_AOVS.to_vec());
+    }
+    // Each kind once, in the order first given
+    let mut kinds = Vec::new();
+    for name in list.split(',') {
+        let kind = name
+            .trim()
+            .parse()
+            .map_err(|_| format!("unknown AOV: {}", name))?;
+        if !kinds.contains(&kind) {
+            kinds.push(kind);
+        }
+    }
+    Ok(kinds)
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+        let mut options = Options::default();
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "-o" | "--output" => options.output = Some(value(&flag, &mut args)?),
+                "--scene" => options.scene = Some(value(&flag, &mut args)?),
+                "--aovs" => options.aovs = aov_list(&value::<String, _>(&flag, &mut args)?)?,
+                "--aov-prefix" => options.aov_prefix = Some(value(&flag, &mut args)?),
+                "--denoise" => options.denoiser = value(&flag, &mut args)?,
+                "--reference" => options.reference = Some(value(&flag, &mut args)?),
+                "--exposure" => options.exposure = value(&flag, &mut args)?,
+                "--tonemap" => options.tone_mapper = value(&flag, &mut args)?,
+                "--width" => options.width = value(&flag, &mut args)?,
+                "--height" => options.height = value(&flag, &mut args)?,
+                "--samples" => options.samples = value(&flag, &mut args)?,
+                "--min-samples" => options.min_samples = value(&flag, &mut args)?,
+                "--max-samples" => options.max_samples = value(&flag, &mut args)?,
+                "--noise-threshold" => options.noise_threshold = value(&flag, &mut args)?,
+                "--sample-map" => options.sample_map = Some(value(&flag, &mut args)?),
+                "--pass-samples" => options.pass_samples = value(&flag, &mut args)?,
+                "--checkpoint" => options.checkpoint = Some(value(&flag, &mut args)?),
+                "--checkpoint-interval" => options.checkpoint_interval = value(&flag, &mut args)?,
+                "--resume" => options.resume = true,
+                "--sampler" => options.sampler = value(&flag, &mut args)?,
+                "--light-sampler" => options.light_sampler = value(&flag, &mut args)?,
+                "--seed" => options.seed = value(&flag, &mut args)?,
+                "--filter" => options.filter = value(&flag, &mut args)?,
+                "--filter-radius" => options.filter_radius = Some(value(&flag, &mut args)?),
+                "--min-depth" => options.min_depth = value(&flag, &mut args)?,
+                "--max-depth" => options.max_depth = value(&flag, &mut args)?,
+                "-q" | "--quiet" => options.quiet = true,
+                "--stats" => options.stats = Some(value(&flag, &mut args)?),
+                _ => return Err(format!("unknown option: {}", flag)),
+            }
+        }
+        if options.width == 0 || options.height == 0 {
+            return Err("image size must be non-zero".to_string());
+        }
+        if options.samples == 0 {
+            return Err("--samples must be at least 1".to_string());
+        }
+        if options.pass_samples == 0 {
+            return Err("--pass-samples must be at least 1".to_string());
+        }
+        if options.resume && options.checkpoint.is_none() {
+            return Err("--resume needs --checkpoint".to_string());
+        }
+        // AOVs, including the denoiser's guides, aren't checkpointed, so after
+        // a resume they would only cover the new samples
+        if options.resume && options.denoiser != DenoiserKind::None {
+            return Err("--denoise can't be used with --resume".to_string());
+        }
+        if options.resume && !options.aovs.is_empty() {
+            return Err("--aovs can't be used with --resume".to_string());
+        }
+        if options.filter_radius.is_some_and(|r| r <= 0.0) {
+            return Err("--filter-radius must be positive".to_string());
+        }
+        if options.noise_threshold < 0.0 {
+            return Err("--noise-threshold must not be negative".to_string());
+        }
+        if options.min_samples > options.max_samples {
+            return Err("--min-samples must not exceed --max-samples".to_string());
+        }
+        Ok(options)
+    }
+
+    pub fn aov_prefix(&self) -> String {
+        if let Some(prefix) = &self.aov_prefix {
+            return prefix.clone();
+        }
+        match &self.output {
+            Some(output) => match output.rfind('.') {
+                Some(dot) if !output[dot..].contains('/') => output[..dot].to_string(),
+                _ => output.clone(),
+            },
+            None => "render".to_string(),
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use crate::image::Image;
+use crate::tonemap::OutputTransform;
+use crate::vec3::Vec3;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+
+// Display transformed 8-bit PPM, top row first
+pub fn write_ppm<W: Write>(
+    out: &mut W,
+    image: &Image,
+    transform: &OutputTransform,
+) -> io::Result<()> {
+    let (width, height) = (image.width(), image.height());
+    write!(out, "P3\n{} {}\n255\n", width, height)?;
+    for j in (0..height).rev() {
+        for i in 0..width {
+            let col = transform.apply(image.pixel(i, j));
+            let ir = (255.99 * col.r()) as u16;
+            let ig = (255.99 * col.g()) as u16;
+            let ib = (255.99 * col.b()) as u16;
+            writeln!(out, "{} {} {}", ir, ig, ib)?;
+        }
+    }
+    Ok(())
+}
+
+// Little-endian PFM, bottom row first as the format wants. With one
+// channel only the red component is written.
+pub fn write_pfm<W: Write>(out: &mut W, image: &Image, channels: usize) -> io::Result<()> {
+    let tag = if channels == 1 { "Pf" } else { "PF" };
+    write!(out, "{}\n{} {}\n-1.0\n", tag, image.width(), image.height())?;
+    for j in 0..image.height() {
+        for i in 0..image.width() {
+            let col = image.pixel(i, j);
+            for &c in [col.r(), col.g(), col.b()].iter().take(channels) {
+                out.write_all(&(c as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// Reads a PFM of either byte order. One channel images are replicated
+// into all three components.
+pub fn read_pfm(path: &str) -> io::Result<Image> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut header = Vec::new();
+    while header.len() < 3 {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Err(invalid("truncated PFM header"));
+        }
+        header.extend(line.split_whitespace().map(str::to_string));
+    }
+    let channels = match header[0].as_str() {
+        "PF" => 3,
+        "Pf" => 1,
+        _ => return Err(invalid("not a PFM file")),
+    };
+    let size: Vec<usize> = header[1..3]
+        .iter()
+        .map(|s| s.parse().map_err(|_| invalid("bad PFM size")))
+        .collect::<io::Result<_>>()?;
+    let scale: f64 = match header.get(3) {
+        Some(scale) => scale.parse().map_err(|_| invalid("bad PFM scale"))?,
+        None => {
+            let mut line = String::new();
+            input.read_line(&mut line)?;
+            line.trim().parse().map_err(|_| invalid("bad PFM scale"))?
+        }
+    };
+    let mut image = Image::new(size[0], size[1]);
+    let mut bytes = [0; 4];
+    for j in 0..image.height() {
+        for i in 0..image.width() {
+            let mut c = [0.0; 3];
+            for value in c.iter_mut().take(channels) {
+                input.read_exact(&mut bytes)?;
+                *value = if scale < 0.0 {
+                    f32::from_le_bytes(bytes)
+                } else {
+                    f32::from_be_bytes(bytes)
+                } as f64;
+            }
+            if channels == 1 {
+                c = [c[0]; 3];
+            }
+            image.set(i, j, Vec3::new(c[0], c[1], c[2]));
+        }
+    }
+    Ok(image)
+}
+
+// Reads a PGM or PPM in ASCII or binary form, scaled to [0, 1]. Values
+// are returned as stored, without undoing any display encoding.
+pub fn read_pnm(path: &str) -> io::Result<Image> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    // Header fields are separated by whitespace and may be followed by
+    // comments; binary data starts after the single whitespace byte that
+    // ends the header
+    let mut pos = 0;
+    let next_token = |pos: &mut usize| -> io::Result<String> {
+        loop {
+            while *pos < bytes.len() && bytes[*pos].is_ascii_whitespace() {
+                *pos += 1;
+            }
+            if *pos < bytes.len() && bytes[*pos] == b'#' {
+                while *pos < bytes.len() && bytes[*pos] != b'\n' {
+                    *pos += 1;
+                }
+            } else {
+                break;
+            }
+        }
+        let start = *pos;
+        while *pos < bytes.len() && !bytes[*pos].is_ascii_whitespace() {
+            *pos += 1;
+        }
+        if start == *pos {
+            return Err(invalid("truncated PNM file"));
+        }
+        Ok(String::from_utf8_lossy(&bytes[start..*pos]).into_owned())
+    };
+    let (channels, binary) = match next_token(&mut pos)?.as_str() {
+        "P2" => (1, false),
+        "P3" => (3, false),
+        "P5" => (1, true),
+        "P6" => (3, true),
+        _ => return Err(invalid("not a PGM or PPM file")),
+    };
+    let number = |pos: &mut usize| -> io::Result<usize> {
+        next_token(pos)?
+            .parse()
+            .map_err(|_| invalid("bad number in PNM file"))
+    };
+    let width = number(&mut pos)?;
+    let height = number(&mut pos)?;
+    let max = number(&mut pos)?;
+    if max == 0 || max > 65535 {
+        return Err(invalid("bad PNM maximum value"));
+    }
+    pos += 1;
+    let mut image = Image::new(width, height);
+    let sample = |pos: &mut usize| -> io::Result<f64> {
+        let value = if !binary {
+            number(pos)?
+        } else if max < 256 {
+            let value = *bytes
+                .get(*pos)
+                .ok_or_else(|| invalid("truncated PNM file"))?;
+            *pos += 1;
+            value as usize
+        } else {
+            let pair = bytes
+                .get(*pos..*pos + 2)
+                .ok_or_else(|| invalid("truncated PNM file"))?;
+            *pos += 2;
+            (pair[0] as usize) << 8 | pair[1] as usize
+        };
+        Ok(value as f64 / max as f64)
+    };
+    for row in 0..height {
+        for i in 0..width {
+            let col = if channels == 1 {
+                let v = sample(&mut pos)?;
+                Vec3::new(v, v, v)
+            } else {
+                Vec3::new(sample(&mut pos)?, sample(&mut pos)?, sample(&mut pos)?)
+            };
+            image.set(i, height - 1 - row, col);
+        }
+    }
+    Ok(image)
+}
+
+// PFM files by extension, anything else as PGM or PPM
+pub fn read_image(path: &str) -> io::Result<Image> {
+    if path.to_lowercase().ends_with(".pfm") {
+        read_pfm(path)
+    } else {
+        read_pnm(path)
+    }
+}
+
+// Picks the format from the extension of `path`: `.pfm` is written linear,
+// anything else as a display transformed PPM. Without a path the PPM goes
+// to stdout.
+pub fn write_image(
+    path: Option<&str>,
+    image: &Image,
+    transform: &OutputTransform,
+) -> io::Result<()> {
+    match path {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(path)?);
+            if path.to_lowercase().ends_with(".pfm") {
+                write_pfm(&mut out, image, 3)?;
+            } else {
+                write_ppm(&mut out, image, transform)?;
+            }
+            out.flush()
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut out = BufWriter::new(stdout.lock());
+            write_ppm(&mut out, image, transform)?;
+            out.flush()
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// Prints the state of a render to stderr at most a few times a second.
+// Methods take &self so the sample closure and the pass loop can share it.
+pub struct Progress {
+    enabled: bool,
+    total: u64,
+    done: Cell<u64>,
+    done_at_start: u64,
+    pass: Cell<usize>,
+    start: Instant,
+    last_print: Cell<Instant>,
+}
+
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{}h {:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m {:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+impl Progress {
+    // `total` samples are expected, of which `done` were already taken
+    pub fn new(total: u64, done: u64, enabled: bool) -> Progress {
+        let now = Instant::now();
+        Progress {
+            enabled,
+            total,
+            done: Cell::new(done),
+            done_at_start: done,
+            pass: Cell::new(0),
+            start: now,
+            last_print: Cell::new(now),
+        }
+    }
+
+    pub fn start_pass(&self, pass: usize) {
+        self.pass.set(pass);
+    }
+
+    // Counts one finished sample
+    pub fn tick(&self) {
+        self.done.set(self.done.get() + 1);
+        if self.enabled && self.last_print.get().elapsed() >= Duration::from_millis(250) {
+            self.last_print.set(Instant::now());
+            self.print();
+        }
+    }
+
+    fn print(&self) {
+        let done = self.done.get();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = (done - self.done_at_start) as f64 / elapsed.max(1e-9);
+        let remaining = self.total.saturating_sub(done);
+        let eta = if rate > 0.0 {
+            format_duration(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            "?".to_string()
+        };
+        eprint!(
+            "\rpass {}  {:5.1}%  {}/{} samples  {:.0} samples/s  ETA {}    ",
+            self.pass.get(),
+            100.0 * done as f64 / self.total.max(1) as f64,
+            done,
+            self.total,
+            rate,
+            eta
+        );
+    }
+
+    pub fn finish(&self) {
+        if self.enabled {
+            self.print();
+            eprintln!("\ndone in {}", format_duration(self.start.elapsed()));
+        }
+    }
+}
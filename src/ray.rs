@@ -8,7 +8,7 @@ pub struct Ray {
 
 impl Ray {
     pub fn new(org: Vec3, dir: Vec3) -> Ray {
-        Ray { org: org, dir: dir }
+        Ray { org, dir }
     }
 
     pub fn origin(self) -> Vec3 {
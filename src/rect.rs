@@ -0,0 +1,119 @@
+use crate::distribution::Distribution2D;
+use crate::hitable::{area_to_solid_angle, HitRecord, Hitable, LightSample};
+use crate::light_sampler::{Aabb, DirectionCone, LightBounds};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::stats;
+use crate::vec3::Vec3;
+use std::rc::Rc;
+
+// Parallelogram spanned by `edge_u` and `edge_v` from `corner`, facing along
+// their cross product
+#[derive(Clone)]
+pub struct Rect {
+    corner: Vec3,
+    edge_u: Vec3,
+    edge_v: Vec3,
+    normal: Vec3,
+    area: f64,
+    material: Rc<dyn Material>,
+    // Where a textured emitter is bright, over the coordinates along the
+    // edges
+    emission: Option<Rc<Distribution2D>>,
+}
+
+impl Rect {
+    pub fn new(corner: Vec3, edge_u: Vec3, edge_v: Vec3, material: Rc<dyn Material>) -> Rect {
+        let cross = Vec3::cross(edge_u, edge_v);
+        Rect {
+            corner,
+            edge_u,
+            edge_v,
+            normal: Vec3::unit_vector(cross),
+            area: cross.len(),
+            emission: material
+                .emission_importance()
+                .map(|(func, width, height)| Rc::new(Distribution2D::new(&func, width, height))),
+            material,
+        }
+    }
+
+    // hit without counting an intersection test, for pdf
+    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let denom = Vec3::dot(self.normal, ray.direction());
+        if denom == 0.0 {
+            return None;
+        }
+        let t = Vec3::dot(self.normal, self.corner - ray.origin()) / denom;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        // Coordinates of the hit along the edges, from 0 to 1 inside
+        let p = ray.point_at_parameter(t);
+        let d = p - self.corner;
+        let cross = self.area * self.normal;
+        let a = Vec3::dot(Vec3::cross(d, self.edge_v), cross) / (self.area * self.area);
+        let b = Vec3::dot(Vec3::cross(self.edge_u, d), cross) / (self.area * self.area);
+        if !(0.0..=1.0).contains(&a) || !(0.0..=1.0).contains(&b) {
+            return None;
+        }
+        Some(HitRecord {
+            t,
+            p,
+            normal: self.normal,
+            uv: (a, b),
+            material: self.material.as_ref(),
+            object_id: 0,
+        })
+    }
+}
+
+impl Hitable for Rect {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        stats::intersection_test();
+        self.intersect(ray, t_min, t_max)
+    }
+
+    // Samples the rectangle uniformly by area, or by the brightness of its
+    // texture
+    fn sample(&self, origin: Vec3, u: (f64, f64)) -> Option<LightSample> {
+        let ((a, b), pdf) = match &self.emission {
+            Some(emission) => emission.sample(u),
+            None => (u, 1.0),
+        };
+        let p = self.corner + a * self.edge_u + b * self.edge_v;
+        Some(LightSample {
+            p,
+            normal: self.normal,
+            pdf: area_to_solid_angle(pdf / self.area, origin, p, self.normal),
+            two_sided: self.material.two_sided(),
+        })
+    }
+
+    fn pdf(&self, origin: Vec3, direction: Vec3) -> f64 {
+        let rec = match self.intersect(&Ray::new(origin, direction), 0.001, f64::MAX) {
+            Some(rec) => rec,
+            None => return 0.0,
+        };
+        let pdf = self
+            .emission
+            .as_ref()
+            .map_or(1.0, |emission| emission.pdf(rec.uv));
+        area_to_solid_angle(pdf / self.area, origin, rec.p, self.normal)
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let far = self.corner + self.edge_u + self.edge_v;
+        Some(
+            LightBounds::new(
+                Aabb::new(self.corner, far)
+                    .include(self.corner + self.edge_u)
+                    .include(self.corner + self.edge_v),
+                DirectionCone::new(self.normal, 1.0),
+                self.area,
+                self.material.emission(),
+            )
+            .two_sided(self.material.two_sided()),
+        )
+    }
+}
@@ -0,0 +1,366 @@
+use crate::checkpoint::{self, read_f64, read_str, read_u64, write_f64, write_str, write_u64};
+use crate::film::Film;
+use crate::vec3::Vec3;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+// Running mean and variance of a pixel's samples (Welford's algorithm).
+// Variance is tracked on luminance only.
+#[derive(Copy, Clone, Default, Debug)]
+pub struct PixelStats {
+    n: usize,
+    mean: f64,
+    m2: f64,
+}
+
+impl PixelStats {
+    pub fn add(&mut self, col: Vec3) {
+        self.n += 1;
+        let lum = col.luminance();
+        let delta = lum - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (lum - self.mean);
+    }
+
+    pub fn samples(&self) -> usize {
+        self.n
+    }
+
+    // Variance of the mean luminance; infinite with fewer than two samples
+    pub fn variance(&self) -> f64 {
+        if self.n < 2 {
+            return f64::INFINITY;
+        }
+        self.m2.max(0.0) / (self.n - 1) as f64 / self.n as f64
+    }
+
+    // Standard error of the mean luminance, relative to that luminance
+    pub fn relative_error(&self) -> f64 {
+        self.variance().sqrt() / self.mean.max(1e-3)
+    }
+}
+
+// With a zero `noise_threshold` every pixel gets exactly `samples`.
+// Otherwise `samples` is the average budget per pixel: every pixel gets
+// `min_samples`, then the rest goes to the noisiest pixels until they drop
+// below the threshold or reach `max_samples`.
+#[derive(Copy, Clone, Debug)]
+pub struct SampleSettings {
+    pub samples: usize,
+    pub min_samples: usize,
+    pub max_samples: usize,
+    pub noise_threshold: f64,
+    // Most samples a pixel gets in one pass
+    pub pass_samples: usize,
+}
+
+impl SampleSettings {
+    fn adaptive(&self) -> bool {
+        self.noise_threshold > 0.0
+    }
+
+    fn min_samples(&self) -> usize {
+        if self.adaptive() {
+            self.min_samples.max(2).min(self.max_samples)
+        } else {
+            self.samples
+        }
+    }
+}
+
+// Progressive renderer: each pass refines the whole image a little. All
+// state lives in the film and the pixel statistics, so it can be
+// checkpointed between passes and resumed with identical results.
+pub struct Renderer {
+    film: Film,
+    pixels: Vec<PixelStats>,
+    settings: SampleSettings,
+    passes: usize,
+}
+
+impl Renderer {
+    pub fn new(film: Film, settings: SampleSettings) -> Renderer {
+        let pixels = vec![PixelStats::default(); film.width() * film.height()];
+        Renderer {
+            film,
+            pixels,
+            settings,
+            passes: 0,
+        }
+    }
+
+    pub fn film(&self) -> &Film {
+        &self.film
+    }
+
+    pub fn pixels(&self) -> &[PixelStats] {
+        &self.pixels
+    }
+
+    pub fn samples_taken(&self) -> usize {
+        self.pixels.iter().map(|p| p.samples()).sum()
+    }
+
+    pub fn passes(&self) -> usize {
+        self.passes
+    }
+
+    // Runs one pass, calling `sample(i, j, index)` for sample `index` of the
+    // pixel in column `i`, row `j` (counted from the bottom). `sample`
+    // returns the film position it chose within the pixel and the radiance.
+    // Returns false, without sampling, once the image is finished.
+    pub fn render_pass<F: FnMut(usize, usize, usize) -> ((f64, f64), Vec3)>(
+        &mut self,
+        sample: &mut F,
+    ) -> bool {
+        let settings = self.settings;
+        let min_samples = settings.min_samples();
+        let taken = self.samples_taken();
+        let mut budget = if settings.adaptive() {
+            (settings.samples * self.pixels.len()).saturating_sub(taken)
+        } else {
+            usize::MAX
+        };
+
+        // Pixels still short of `min_samples` come first, then the noisiest
+        let mut active: Vec<(f64, usize)> = self
+            .pixels
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| {
+                p.samples() < min_samples
+                    || (settings.adaptive()
+                        && p.samples() < settings.max_samples
+                        && p.relative_error() > settings.noise_threshold)
+            })
+            .map(|(index, p)| {
+                if p.samples() < min_samples {
+                    (f64::INFINITY, index)
+                } else {
+                    (p.relative_error(), index)
+                }
+            })
+            .collect();
+        if active.is_empty() || budget == 0 {
+            return false;
+        }
+        if settings.adaptive() {
+            active.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap().then(a.1.cmp(&b.1)));
+        }
+
+        let width = self.film.width();
+        for (_, index) in active {
+            let pixel = &mut self.pixels[index];
+            let limit = if pixel.samples() < min_samples {
+                min_samples
+            } else {
+                settings.max_samples
+            };
+            let n = settings
+                .pass_samples
+                .min(limit - pixel.samples())
+                .min(budget);
+            let (i, j) = (index % width, index / width);
+            for _ in 0..n {
+                let ((x, y), col) = sample(i, j, pixel.samples());
+                pixel.add(col);
+                self.film.add_sample(x, y, col);
+            }
+            budget -= n;
+            if budget == 0 {
+                break;
+            }
+        }
+        self.passes += 1;
+        true
+    }
+
+    // `key` describes everything that must match for a checkpoint to be
+    // resumed (image size, seed, sampler, ...)
+    pub fn save_checkpoint(&self, path: &str, key: &str) -> io::Result<()> {
+        // Write to a temporary file first so a crash mid-write can't destroy
+        // the previous checkpoint
+        let tmp = format!("{}.tmp", path);
+        {
+            let mut out = BufWriter::new(File::create(&tmp)?);
+            out.write_all(checkpoint::MAGIC)?;
+            write_str(&mut out, key)?;
+            write_u64(&mut out, self.film.width() as u64)?;
+            write_u64(&mut out, self.film.height() as u64)?;
+            write_u64(&mut out, self.passes as u64)?;
+            for p in &self.pixels {
+                write_u64(&mut out, p.n as u64)?;
+                write_f64(&mut out, p.mean)?;
+                write_f64(&mut out, p.m2)?;
+            }
+            self.film.save(&mut out)?;
+            out.flush()?;
+        }
+        fs::rename(&tmp, path)
+    }
+
+    // Restores the state saved by `save_checkpoint` into this renderer,
+    // which must have been created for the same image and `key`
+    pub fn load_checkpoint(&mut self, path: &str, key: &str) -> io::Result<()> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != checkpoint::MAGIC {
+            return Err(checkpoint::invalid("not a checkpoint file"));
+        }
+        let saved_key = read_str(&mut input)?;
+        if saved_key != key {
+            return Err(checkpoint::invalid(&format!(
+                "checkpoint was made with different settings ({})",
+                saved_key
+            )));
+        }
+        let width = read_u64(&mut input)? as usize;
+        let height = read_u64(&mut input)? as usize;
+        if width != self.film.width() || height != self.film.height() {
+            return Err(checkpoint::invalid("checkpoint has a different image size"));
+        }
+        self.passes = read_u64(&mut input)? as usize;
+        for p in &mut self.pixels {
+            p.n = read_u64(&mut input)? as usize;
+            p.mean = read_f64(&mut input)?;
+            p.m2 = read_f64(&mut input)?;
+        }
+        self.film.load(&mut input)
+    }
+}
+
+// Writes the number of samples each pixel took as a grayscale PGM, scaled
+// so the busiest pixel is white
+pub fn write_sample_map(
+    path: &str,
+    width: usize,
+    height: usize,
+    pixels: &[PixelStats],
+) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let max = pixels.iter().map(|p| p.samples()).max().unwrap_or(0).max(1);
+    writeln!(out, "P2\n{} {}\n255", width, height)?;
+    for j in (0..height).rev() {
+        for i in 0..width {
+            writeln!(out, "{}", 255 * pixels[j * width + i].samples() / max)?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn resumed_render_matches_uninterrupted() {
+    use crate::film::{Filter, FilterKind};
+    use crate::sampler::{new_sampler, SamplerKind};
+
+    let settings = SampleSettings {
+        samples: 12,
+        min_samples: 4,
+        max_samples: 32,
+        noise_threshold: 0.05,
+        pass_samples: 2,
+    };
+    let mut sampler = new_sampler(SamplerKind::Sobol, settings.samples, 3);
+    let mut sample = |i: usize, j: usize, index: usize| {
+        sampler.start_pixel_sample((i, j), index);
+        let (du, dv) = sampler.get_2d();
+        let (x, y) = (i as f64 + du, j as f64 + dv);
+        let v = if (x - 4.0).powi(2) + (y - 3.0).powi(2) < 6.0 {
+            sampler.get_1d()
+        } else {
+            0.25
+        };
+        ((x, y), Vec3::new(v, v * 0.5, 1.0 - v))
+    };
+    let new_renderer = || {
+        let filter = Filter::new(FilterKind::Mitchell, None);
+        Renderer::new(Film::new(8, 6, filter), settings)
+    };
+
+    let mut uninterrupted = new_renderer();
+    while uninterrupted.render_pass(&mut sample) {}
+
+    let path = std::env::temp_dir().join(format!("ray_tracer_test_{}.ckpt", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut interrupted = new_renderer();
+    for _ in 0..3 {
+        interrupted.render_pass(&mut sample);
+    }
+    interrupted.save_checkpoint(path, "test").unwrap();
+    let mut resumed = new_renderer();
+    resumed.load_checkpoint(path, "test").unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(resumed.passes(), 3);
+    while resumed.render_pass(&mut sample) {}
+
+    assert_eq!(resumed.passes(), uninterrupted.passes());
+    for j in 0..6 {
+        for i in 0..8 {
+            assert_eq!(resumed.film().pixel(i, j), uninterrupted.film().pixel(i, j));
+        }
+    }
+}
+
+#[test]
+fn adaptive_sampling() {
+    use crate::film::{Filter, FilterKind};
+    use crate::sampler::{IndependentSampler, Sampler};
+
+    // Flat gray on the left half of the image, uniform noise of the same
+    // mean on the right
+    let (width, height) = (8, 6);
+    let render = |samples: usize, noise_threshold: f64| {
+        let settings = SampleSettings {
+            samples,
+            min_samples: 4,
+            max_samples: 256,
+            noise_threshold,
+            pass_samples: 4,
+        };
+        let mut sampler = IndependentSampler::new(1);
+        let mut sample = |i: usize, j: usize, index: usize| {
+            sampler.start_pixel_sample((i, j), index);
+            let v = if i < width / 2 { 0.5 } else { sampler.get_1d() };
+            ((i as f64 + 0.5, j as f64 + 0.5), Vec3::new(v, v, v))
+        };
+        let filter = Filter::new(FilterKind::Box, None);
+        let mut renderer = Renderer::new(Film::new(width, height, filter), settings);
+        while renderer.render_pass(&mut sample) {}
+        renderer
+    };
+    let noisy = |index: usize| index % width >= width / 2;
+
+    // Without a threshold every pixel gets the same
+    let fixed = render(6, 0.0);
+    assert!(fixed.pixels().iter().all(|p| p.samples() == 6));
+
+    // With plenty of budget, flat pixels stop at the minimum and noisy ones
+    // once they are below the threshold. The relative error of the mean of
+    // n uniform samples is 0.58 / sqrt(n), so that takes about 34 on
+    // average, though a few samples that happen to agree can stop early.
+    let settled = render(64, 0.1);
+    assert!(settled.samples_taken() < 64 * width * height);
+    let mut noisy_samples = 0;
+    for (index, p) in settled.pixels().iter().enumerate() {
+        if noisy(index) {
+            assert!(p.relative_error() <= 0.1);
+            noisy_samples += p.samples();
+        } else {
+            assert_eq!(p.samples(), 4);
+        }
+    }
+    let average = noisy_samples as f64 / (width * height / 2) as f64;
+    assert!(average > 25.0 && average < 45.0, "{}", average);
+
+    // A tight budget is spent exactly, and the extra spread evenly over the
+    // noisy pixels still above the threshold
+    let tight = render(8, 0.1);
+    assert_eq!(tight.samples_taken(), 8 * width * height);
+    let counts: Vec<usize> = (0..width * height)
+        .filter(|&index| noisy(index) && tight.pixels()[index].relative_error() > 0.1)
+        .map(|index| tight.pixels()[index].samples())
+        .collect();
+    let (min, max) = (counts.iter().min().unwrap(), counts.iter().max().unwrap());
+    assert!(*min >= 8 && max - min <= 4, "{:?}", counts);
+}
@@ -0,0 +1,582 @@
+use std::f64::consts::PI;
+use std::str::FromStr;
+
+// Source of the random numbers for one pixel sample. Each call hands out the
+// next dimension of the sample vector; `start_pixel_sample` rewinds to the
+// first dimension of the given sample, so the same (pixel, index) always
+// produces the same numbers.
+pub trait Sampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize);
+
+    fn get_1d(&mut self) -> f64;
+
+    fn get_2d(&mut self) -> (f64, f64);
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+    BlueNoise,
+}
+
+impl FromStr for SamplerKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            "bluenoise" => Ok(SamplerKind::BlueNoise),
+            _ => Err(()),
+        }
+    }
+}
+
+pub fn new_sampler(kind: SamplerKind, samples_per_pixel: usize, seed: u64) -> Box<dyn Sampler> {
+    match kind {
+        SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+        SamplerKind::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel, seed)),
+        SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+        SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        SamplerKind::BlueNoise => Box::new(BlueNoiseSampler::new(seed)),
+    }
+}
+
+const ONE_MINUS_EPSILON: f64 = 1.0 - f64::EPSILON / 2.0;
+
+// 64-bit finalizer from MurmurHash3
+fn mix_bits(mut v: u64) -> u64 {
+    v ^= v >> 31;
+    v = v.wrapping_mul(0x7fb5_d329_728e_a185);
+    v ^= v >> 27;
+    v = v.wrapping_mul(0x81da_def4_bc2d_d44d);
+    v ^= v >> 33;
+    v
+}
+
+fn hash(a: u64, b: u64) -> u64 {
+    mix_bits(a ^ mix_bits(b.wrapping_add(0x9e37_79b9_7f4a_7c15)))
+}
+
+fn to_unit(bits: u64) -> f64 {
+    (bits >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+fn pixel_hash(seed: u64, pixel: (usize, usize)) -> u64 {
+    hash(hash(seed, pixel.0 as u64), pixel.1 as u64)
+}
+
+// Element `i` of a random permutation of 0..l chosen by `p` (Kensler 2013)
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    (i.wrapping_add(p)) % l
+}
+
+// Nested uniform (Owen) scrambling of a base-2 fixed point number
+// (Burley 2020, "Practical Hash-based Owen Scrambling")
+fn owen_scramble(v: u32, seed: u32) -> u32 {
+    let mut x = v.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+// First two dimensions of the Sobol sequence, as 0.32 fixed point
+fn sobol_2d(index: u32) -> (u32, u32) {
+    let mut y = 0;
+    let mut v: u32 = 1 << 31;
+    let mut i = index;
+    while i != 0 {
+        if i & 1 != 0 {
+            y ^= v;
+        }
+        v ^= v >> 1;
+        i >>= 1;
+    }
+    (index.reverse_bits(), y)
+}
+
+// Owen scrambled 2D Sobol point with its index shuffled by `seed`, so that
+// each seed gives an independent padding of the 2D sequence
+fn shuffled_sobol_2d(index: u64, seed: u64) -> (f64, f64) {
+    let index = owen_scramble(index as u32, hash(seed, 0) as u32);
+    let (x, y) = sobol_2d(index);
+    let x = owen_scramble(x, hash(seed, 1) as u32);
+    let y = owen_scramble(y, hash(seed, 2) as u32);
+    (
+        (x as f64 / 4_294_967_296.0).min(ONE_MINUS_EPSILON),
+        (y as f64 / 4_294_967_296.0).min(ONE_MINUS_EPSILON),
+    )
+}
+
+const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+// Radical inverse of `a` in `base` with every digit permuted by a hash of
+// the digits below it, i.e. Owen scrambling in that base
+fn owen_scrambled_radical_inverse(base: u64, mut a: u64, seed: u64) -> f64 {
+    let inv_base = 1.0 / base as f64;
+    let mut inv_base_m = 1.0;
+    let mut reversed_digits: u64 = 0;
+    while 1.0 - inv_base_m < 1.0 {
+        let next = a / base;
+        let digit = a - next * base;
+        let digit_hash = mix_bits(seed ^ reversed_digits) as u32;
+        let digit = permutation_element(digit as u32, base as u32, digit_hash) as u64;
+        reversed_digits = reversed_digits * base + digit;
+        inv_base_m *= inv_base;
+        a = next;
+    }
+    (reversed_digits as f64 * inv_base_m).min(ONE_MINUS_EPSILON)
+}
+
+// Uniformly random numbers, derived by hashing (pixel, index, dimension)
+pub struct IndependentSampler {
+    seed: u64,
+    state: u64,
+    dimension: u64,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u64) -> IndependentSampler {
+        IndependentSampler {
+            seed,
+            state: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.state = hash(pixel_hash(self.seed, pixel), index as u64);
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.dimension += 1;
+        to_unit(hash(self.state, self.dimension))
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// Jittered stratification of every dimension into `samples_per_pixel`
+// strata (a near-square grid of them for 2D), visited in an order that is
+// shuffled per pixel and dimension. Samples past `samples_per_pixel` fall
+// back to independent jitter.
+pub struct StratifiedSampler {
+    samples_per_pixel: usize,
+    x_strata: usize,
+    y_strata: usize,
+    seed: u64,
+    pixel: u64,
+    index: usize,
+    dimension: u64,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: usize, seed: u64) -> StratifiedSampler {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        let x_strata = (samples_per_pixel as f64).sqrt().ceil() as usize;
+        let y_strata = samples_per_pixel.div_ceil(x_strata);
+        StratifiedSampler {
+            samples_per_pixel,
+            x_strata,
+            y_strata,
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn next_hash(&mut self) -> u64 {
+        self.dimension += 1;
+        hash(self.pixel, self.dimension)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.pixel = pixel_hash(self.seed, pixel);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let h = self.next_hash();
+        let jitter = to_unit(hash(h, self.index as u64));
+        if self.index >= self.samples_per_pixel {
+            return jitter;
+        }
+        let n = self.samples_per_pixel as u32;
+        let stratum = permutation_element(self.index as u32, n, h as u32);
+        ((stratum as f64 + jitter) / n as f64).min(ONE_MINUS_EPSILON)
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        let h = self.next_hash();
+        let jx = to_unit(hash(h, 2 * self.index as u64));
+        let jy = to_unit(hash(h, 2 * self.index as u64 + 1));
+        if self.index >= self.samples_per_pixel {
+            return (jx, jy);
+        }
+        let cells = (self.x_strata * self.y_strata) as u32;
+        let cell = permutation_element(self.index as u32, cells, h as u32) as usize;
+        let x = (cell % self.x_strata) as f64;
+        let y = (cell / self.x_strata) as f64;
+        (
+            ((x + jx) / self.x_strata as f64).min(ONE_MINUS_EPSILON),
+            ((y + jy) / self.y_strata as f64).min(ONE_MINUS_EPSILON),
+        )
+    }
+}
+
+// Owen scrambled Halton sequence, one prime base per dimension, scrambled
+// independently for every pixel. Dimensions past the table of primes are
+// filled with independent random numbers.
+pub struct HaltonSampler {
+    seed: u64,
+    pixel: u64,
+    index: u64,
+    dimension: usize,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> HaltonSampler {
+        HaltonSampler {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.pixel = pixel_hash(self.seed, pixel);
+        self.index = index as u64;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let h = hash(self.pixel, dimension as u64);
+        if dimension < PRIMES.len() {
+            owen_scrambled_radical_inverse(PRIMES[dimension], self.index, h)
+        } else {
+            to_unit(hash(h, self.index))
+        }
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        (self.get_1d(), self.get_1d())
+    }
+}
+
+// Owen scrambled Sobol (0,2)-sequence, padded to higher dimensions by
+// giving every pair of dimensions its own shuffle and scramble
+pub struct SobolSampler {
+    seed: u64,
+    pixel: u64,
+    index: u64,
+    dimension: u64,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> SobolSampler {
+        SobolSampler {
+            seed,
+            pixel: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.pixel = pixel_hash(self.seed, pixel);
+        self.index = index as u64;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.get_2d().0
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        self.dimension += 1;
+        shuffled_sobol_2d(self.index, hash(self.pixel, self.dimension))
+    }
+}
+
+const BLUE_NOISE_SIZE: usize = 64;
+
+// Ranks of a void-and-cluster blue noise mask (Ulichney 1993), normalized
+// to [0, 1). Phase three simply keeps filling the largest void.
+fn blue_noise_mask(seed: u64) -> Vec<f64> {
+    let n = BLUE_NOISE_SIZE;
+    let count = n * n;
+    let sigma = 1.5;
+    let kernel: Vec<f64> = (0..count)
+        .map(|i| {
+            let dx = (i % n).min(n - i % n) as f64;
+            let dy = (i / n).min(n - i / n) as f64;
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let mut energy = vec![0.0; count];
+    let mut ones = vec![false; count];
+    let splat = |energy: &mut Vec<f64>, p: usize, sign: f64| {
+        let (px, py) = (p % n, p / n);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % n + n - px) % n;
+            let dy = (q / n + n - py) % n;
+            *e += sign * kernel[dy * n + dx];
+        }
+    };
+    let tightest_cluster = |energy: &[f64], ones: &[bool]| {
+        (0..count)
+            .filter(|&p| ones[p])
+            .max_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+            .unwrap()
+    };
+    let largest_void = |energy: &[f64], ones: &[bool]| {
+        (0..count)
+            .filter(|&p| !ones[p])
+            .min_by(|&a, &b| energy[a].partial_cmp(&energy[b]).unwrap())
+            .unwrap()
+    };
+
+    let initial = count / 10;
+    let mut placed = 0;
+    let mut i = 0;
+    while placed < initial {
+        let p = (hash(seed, i) % count as u64) as usize;
+        i += 1;
+        if !ones[p] {
+            ones[p] = true;
+            splat(&mut energy, p, 1.0);
+            placed += 1;
+        }
+    }
+    for _ in 0..count {
+        let cluster = tightest_cluster(&energy, &ones);
+        ones[cluster] = false;
+        splat(&mut energy, cluster, -1.0);
+        let void = largest_void(&energy, &ones);
+        ones[void] = true;
+        splat(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; count];
+    let prototype = (ones.clone(), energy.clone());
+    for rank in (0..initial).rev() {
+        let cluster = tightest_cluster(&energy, &ones);
+        ones[cluster] = false;
+        splat(&mut energy, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+    let (mut ones, mut energy) = prototype;
+    for rank in initial..count {
+        let void = largest_void(&energy, &ones);
+        ones[void] = true;
+        splat(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+    ranks
+        .into_iter()
+        .map(|rank| (rank as f64 + 0.5) / count as f64)
+        .collect()
+}
+
+// Every pixel shares one padded Sobol sequence, toroidally shifted by a
+// blue noise mask. Each dimension reads the mask at its own offset, so the
+// error left at low sample counts is spread as blue noise over the image.
+pub struct BlueNoiseSampler {
+    seed: u64,
+    mask: Vec<f64>,
+    pixel: (usize, usize),
+    index: u64,
+    dimension: u64,
+}
+
+impl BlueNoiseSampler {
+    pub fn new(seed: u64) -> BlueNoiseSampler {
+        BlueNoiseSampler {
+            seed,
+            mask: blue_noise_mask(seed),
+            pixel: (0, 0),
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn offset(&self, h: u64) -> f64 {
+        let n = BLUE_NOISE_SIZE;
+        let x = (self.pixel.0 + (h % n as u64) as usize) % n;
+        let y = (self.pixel.1 + ((h >> 32) % n as u64) as usize) % n;
+        self.mask[y * n + x]
+    }
+}
+
+impl Sampler for BlueNoiseSampler {
+    fn start_pixel_sample(&mut self, pixel: (usize, usize), index: usize) {
+        self.pixel = pixel;
+        self.index = index as u64;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f64 {
+        self.get_2d().0
+    }
+
+    fn get_2d(&mut self) -> (f64, f64) {
+        self.dimension += 1;
+        let h = hash(self.seed, self.dimension);
+        let (x, y) = shuffled_sobol_2d(self.index, h);
+        let (ox, oy) = (self.offset(hash(h, 1)), self.offset(hash(h, 2)));
+        ((x + ox).fract(), (y + oy).fract())
+    }
+}
+
+// Stretches `u`, which a choice found in [start, start + width), back over
+// [0, 1) so the same dimension can drive the next decision. Picking one of
+// n equal parts is remap(u * n, i, 1.0).
+pub fn remap(u: f64, start: f64, width: f64) -> f64 {
+    ((u - start) / width).clamp(0.0, ONE_MINUS_EPSILON)
+}
+
+// Maps the unit square to the unit disk, preserving stratification
+// (Shirley and Chiu 1997)
+pub fn sample_concentric_disk(u: (f64, f64)) -> (f64, f64) {
+    let (a, b) = (2.0 * u.0 - 1.0, 2.0 * u.1 - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0);
+    }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
+}
+
+// Cosine weighted direction about +z
+pub fn sample_cosine_hemisphere(u: (f64, f64)) -> (f64, f64, f64) {
+    let (x, y) = sample_concentric_disk(u);
+    (x, y, (1.0 - x * x - y * y).max(0.0).sqrt())
+}
+
+#[cfg(test)]
+pub fn sample_uniform_sphere(u: (f64, f64)) -> (f64, f64, f64) {
+    let z = 1.0 - 2.0 * u.0;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u.1;
+    (r * phi.cos(), r * phi.sin(), z)
+}
+
+// Root mean square error, over many pixels, of estimating the area of the
+// quarter disk with `n` samples from dimensions `dimension` and
+// `dimension + 1`
+#[cfg(test)]
+fn quarter_disk_rmse(sampler: &mut dyn Sampler, n: usize, dimension: usize) -> f64 {
+    let pixels = 64;
+    let mut squared_error = 0.0;
+    for p in 0..pixels {
+        let mut inside = 0;
+        for index in 0..n {
+            sampler.start_pixel_sample((p % 8, p / 8), index);
+            for _ in 0..dimension / 2 {
+                sampler.get_2d();
+            }
+            let (x, y) = sampler.get_2d();
+            if x * x + y * y < 1.0 {
+                inside += 1;
+            }
+        }
+        let estimate = inside as f64 / n as f64;
+        squared_error += (estimate - PI / 4.0).powi(2);
+    }
+    (squared_error / pixels as f64).sqrt()
+}
+
+#[test]
+fn sampler_convergence() {
+    let n = 256;
+    for &dimension in &[0, 6] {
+        let independent = quarter_disk_rmse(&mut IndependentSampler::new(1), n, dimension);
+        for &kind in &[
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+            SamplerKind::BlueNoise,
+        ] {
+            let rmse = quarter_disk_rmse(new_sampler(kind, n, 1).as_mut(), n, dimension);
+            assert!(
+                rmse < 0.5 * independent,
+                "{:?} rmse {} vs independent {} in dimension {}",
+                kind,
+                rmse,
+                independent,
+                dimension
+            );
+        }
+    }
+}
+
+#[test]
+fn remapping_a_used_dimension() {
+    assert_eq!(remap(0.25, 0.0, 0.5), 0.5);
+    assert_eq!(remap(0.75, 0.5, 0.25), 1.0 - f64::EPSILON / 2.0);
+    // The part of one of three that was picked
+    assert!((remap(0.7 * 3.0, 2.0, 1.0) - 0.1).abs() < 1e-12);
+    assert_eq!(remap(0.5, 0.5, 0.5), 0.0);
+}
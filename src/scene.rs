@@ -0,0 +1,977 @@
+use crate::aov::MaterialIds;
+use crate::aperture::{Aperture, ApertureShape};
+use crate::camera::{
+    film_size, CameraModel, CubemapCamera, EquirectangularCamera, FisheyeCamera, FisheyeProjection,
+    OrthographicCamera, PerspectiveCamera, PhysicalSettings, StereoCamera, StereoLayout,
+};
+use crate::hitable::{Hitable, HitableList};
+use crate::ies::{self, AimedProfile};
+use crate::lens::{self, LensCamera};
+use crate::light::{DirectionalLight, Lights, PointLight, SpotLight};
+use crate::light_sampler::LightSamplerKind;
+use crate::material::{self, Dielectric, DiffuseLight, Lambertian, Material, Metal};
+use crate::output;
+use crate::rect::Rect;
+use crate::sky::Sky;
+use crate::sphere::Sphere;
+use crate::texture::{ConstantTexture, ImageTexture, Texture};
+use crate::triangle::{self, Mesh, Triangle};
+use crate::vec3::Vec3;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Scene descriptions are plain text with one statement per line, a keyword
+// followed by keys and their values:
+//
+//     camera type perspective look_from 13 2 3 look_at 0 0 0 vfov 20
+//
+// docs/scene-format.md describes every statement and key.
+pub const DEFAULT_SCENE: &str = "\
+camera type perspective look_from 13 2 3 look_at 0 0 0 vfov 20 aperture 0.1 focus_distance 10
+random_spheres
+";
+
+pub struct Scene {
+    pub world: HitableList,
+    pub lights: Lights,
+    pub camera: Box<dyn CameraModel>,
+    // Scale from scene radiance to film values
+    pub exposure: f64,
+    pub material_ids: MaterialIds,
+}
+
+// Values are numbers only if finite, so `nan` or `inf` can't slip into the
+// geometry
+fn number(token: &str) -> Option<f64> {
+    token.parse::<f64>().ok().filter(|x| x.is_finite())
+}
+
+// One line of a scene description
+struct Statement {
+    line: usize,
+    keyword: String,
+    params: Vec<(String, Vec<String>)>,
+}
+
+impl Statement {
+    fn parse(line: usize, text: &str) -> Result<Option<Statement>, String> {
+        let text = text.split('#').next().unwrap();
+        let mut tokens = text.split_whitespace().peekable();
+        let keyword = match tokens.next() {
+            Some(keyword) => keyword.to_string(),
+            None => return Ok(None),
+        };
+        let is_number = |token: &&str| number(token).is_some();
+        let mut params: Vec<(String, Vec<String>)> = Vec::new();
+        while let Some(key) = tokens.next() {
+            if is_number(&key) {
+                return Err(format!("line {}: expected a key, found {}", line, key));
+            }
+            if params.iter().any(|(k, _)| k == key) {
+                return Err(format!("line {}: {} given twice", line, key));
+            }
+            let mut values = Vec::new();
+            while let Some(token) = tokens.peek() {
+                if !is_number(token) {
+                    break;
+                }
+                values.push(tokens.next().unwrap().to_string());
+            }
+            if values.is_empty() {
+                match tokens.next() {
+                    Some(word) => values.push(word.to_string()),
+                    None => return Err(format!("line {}: missing value for {}", line, key)),
+                }
+            }
+            params.push((key.to_string(), values));
+        }
+        Ok(Some(Statement {
+            line,
+            keyword,
+            params,
+        }))
+    }
+
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {}", self.line, message)
+    }
+
+    // Fails on any key not in `allowed`
+    fn check(&self, allowed: &[&str]) -> Result<(), String> {
+        match self
+            .params
+            .iter()
+            .find(|(k, _)| !allowed.contains(&k.as_str()))
+        {
+            Some((key, _)) => {
+                Err(self.error(&format!("unknown key for {}: {}", self.keyword, key)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn values(&self, key: &str) -> Option<&[String]> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, values)| values.as_slice())
+    }
+
+    fn numbers(&self, key: &str, n: usize) -> Result<Option<Vec<f64>>, String> {
+        let values = match self.values(key) {
+            Some(values) => values,
+            None => return Ok(None),
+        };
+        let numbers: Vec<f64> = values.iter().filter_map(|v| number(v)).collect();
+        if numbers.len() != values.len() || numbers.len() != n {
+            return Err(self.error(&format!("{} needs {} number(s)", key, n)));
+        }
+        Ok(Some(numbers))
+    }
+
+    fn f64(&self, key: &str, default: Option<f64>) -> Result<f64, String> {
+        match self.numbers(key, 1)? {
+            Some(numbers) => Ok(numbers[0]),
+            None => default.ok_or_else(|| self.error(&format!("{} needs {}", self.keyword, key))),
+        }
+    }
+
+    fn vec3(&self, key: &str, default: Option<Vec3>) -> Result<Vec3, String> {
+        match self.numbers(key, 3)? {
+            Some(n) => Ok(Vec3::new(n[0], n[1], n[2])),
+            None => default.ok_or_else(|| self.error(&format!("{} needs {}", self.keyword, key))),
+        }
+    }
+
+    fn word(&self, key: &str, default: Option<&str>) -> Result<String, String> {
+        match self.values(key) {
+            Some([word]) if number(word).is_none() => Ok(word.clone()),
+            Some(_) => Err(self.error(&format!("{} needs a name", key))),
+            None => default
+                .map(str::to_string)
+                .ok_or_else(|| self.error(&format!("{} needs {}", self.keyword, key))),
+        }
+    }
+}
+
+fn camera(
+    statement: &Statement,
+    width: usize,
+    height: usize,
+    world: &dyn Hitable,
+) -> Result<(Box<dyn CameraModel>, f64), String> {
+    let aspect = width as f64 / height as f64;
+    let model = statement.word("type", None)?;
+    let look_from = statement.vec3("look_from", Some(Vec3::new(0.0, 0.0, 0.0)))?;
+    let look_at = statement.vec3("look_at", Some(Vec3::new(0.0, 0.0, -1.0)))?;
+    let up = statement.vec3("up", Some(Vec3::new(0.0, 1.0, 0.0)))?;
+    let stereo = match statement.values("stereo") {
+        None => None,
+        Some(_) => match statement.word("stereo", None)?.as_str() {
+            "side_by_side" => Some(StereoLayout::SideBySide),
+            "over_under" => Some(StereoLayout::OverUnder),
+            other => return Err(statement.error(&format!("unknown stereo layout: {}", other))),
+        },
+    };
+    let mut common = vec!["type", "look_from", "look_at", "up"];
+    if stereo.is_some() {
+        common.extend(&["stereo", "ipd", "convergence"]);
+    }
+    let allow = |extra: &[&str]| statement.check(&[&common[..], extra].concat());
+    let aspect = stereo.map_or(aspect, |layout| layout.eye_aspect(aspect));
+    let eye_offset = 0.5 * statement.f64("ipd", Some(0.064))?;
+    let stereo_pair = |left, right| -> Box<dyn CameraModel> {
+        Box::new(StereoCamera::new(left, right, stereo.unwrap()))
+    };
+    let mut exposure = 1.0;
+    let camera: Box<dyn CameraModel> = match model.as_str() {
+        // A focal length makes it photographic, in place of vfov and aperture
+        "perspective" => {
+            allow(&[
+                "vfov",
+                "aperture",
+                "focus_distance",
+                "aperture_blades",
+                "aperture_rotation",
+                "aperture_image",
+                "vignetting",
+                "shift",
+                "tilt",
+                "swing",
+                "autofocus",
+                "focal_length",
+                "sensor",
+                "f_number",
+                "shutter",
+                "iso",
+            ])?;
+            let focus_distance =
+                statement.f64("focus_distance", Some((look_at - look_from).len()))?;
+            let physical = physical_settings(statement)?;
+            let shift = statement.numbers("shift", 2)?.unwrap_or(vec![0.0, 0.0]);
+            let tilt = statement.f64("tilt", Some(0.0))?;
+            let swing = statement.f64("swing", Some(0.0))?;
+            if tilt.abs() >= 90.0 || swing.abs() >= 90.0 {
+                return Err(statement.error("tilt and swing must be within 90 degrees"));
+            }
+            let camera = match &physical {
+                Some(settings) => {
+                    exposure = settings.exposure();
+                    PerspectiveCamera::physical(
+                        look_from,
+                        look_at,
+                        up,
+                        settings,
+                        aspect,
+                        focus_distance,
+                    )
+                }
+                None => PerspectiveCamera::new(
+                    look_from,
+                    look_at,
+                    up,
+                    statement.f64("vfov", Some(90.0))?,
+                    aspect,
+                    statement.f64("aperture", Some(0.0))?,
+                    focus_distance,
+                ),
+            };
+            let mut camera = camera
+                .with_aperture(aperture(statement)?)
+                .shifted(shift[0], shift[1])
+                .tilted(tilt, swing);
+            if let Some(pixel) = statement.numbers("autofocus", 2)? {
+                // Pixel centers, with rows counted from the top
+                let u = (pixel[0] + 0.5) / width as f64;
+                let v = 1.0 - (pixel[1] + 0.5) / height as f64;
+                camera = camera
+                    .focus_on(world, u, v)
+                    .ok_or_else(|| statement.error("autofocus pixel sees nothing"))?;
+            }
+            if stereo.is_some() {
+                let convergence = statement.f64("convergence", Some(camera.focus_dist()))?;
+                stereo_pair(
+                    Box::new(camera.eye(-eye_offset, convergence)),
+                    Box::new(camera.eye(eye_offset, convergence)),
+                )
+            } else {
+                Box::new(camera)
+            }
+        }
+        "equirectangular" => {
+            allow(&[])?;
+            if stereo.is_some() {
+                let convergence = match statement.values("convergence") {
+                    Some(_) => Some(statement.f64("convergence", None)?),
+                    None => None,
+                };
+                let eye = |offset| {
+                    Box::new(EquirectangularCamera::ods(
+                        look_from,
+                        look_at,
+                        up,
+                        offset,
+                        convergence,
+                    ))
+                };
+                stereo_pair(eye(-eye_offset), eye(eye_offset))
+            } else {
+                Box::new(EquirectangularCamera::new(look_from, look_at, up))
+            }
+        }
+        _ if stereo.is_some() => {
+            return Err(statement.error("stereo needs a perspective or equirectangular camera"))
+        }
+        "orthographic" => {
+            allow(&["height"])?;
+            Box::new(OrthographicCamera::new(
+                look_from,
+                look_at,
+                up,
+                statement.f64("height", None)?,
+                aspect,
+            ))
+        }
+        "fisheye" => {
+            allow(&["projection", "fov"])?;
+            let projection = match statement.word("projection", Some("equidistant"))?.as_str() {
+                "equidistant" => FisheyeProjection::Equidistant,
+                "equisolid" => FisheyeProjection::Equisolid,
+                other => return Err(statement.error(&format!("unknown projection: {}", other))),
+            };
+            let fov = statement.f64("fov", Some(180.0))?;
+            if projection == FisheyeProjection::Equisolid && fov > 360.0 {
+                return Err(statement.error("an equisolid fisheye covers at most 360 degrees"));
+            }
+            Box::new(FisheyeCamera::new(
+                look_from, look_at, up, projection, fov, aspect,
+            ))
+        }
+        // Traced through a prescription of surfaces front to back, in mm
+        "lens" => {
+            allow(&["lens_data", "sensor", "focus_distance", "aperture_stop"])?;
+            let path = statement.word("lens_data", None)?;
+            let mut elements = std::fs::read_to_string(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| lens::parse_lens_data(&text))
+                .map_err(|err| statement.error(&format!("{}: {}", path, err)))?;
+            if let Some(diameter) = statement.numbers("aperture_stop", 1)? {
+                let stop = elements
+                    .iter_mut()
+                    .find(|e| e.radius == 0.0)
+                    .ok_or_else(|| statement.error("lens has no aperture stop"))?;
+                stop.aperture_radius = diameter[0] / 2000.0;
+            }
+            let sensor = statement.numbers("sensor", 2)?.unwrap_or(vec![36.0, 24.0]);
+            let (width, height) = film_size((sensor[0], sensor[1]), aspect);
+            Box::new(
+                LensCamera::new(
+                    look_from,
+                    look_at,
+                    up,
+                    elements,
+                    (width / 1000.0, height / 1000.0),
+                    statement.f64("focus_distance", Some((look_at - look_from).len()))?,
+                )
+                .map_err(|err| statement.error(&err))?,
+            )
+        }
+        "cubemap" => {
+            allow(&[])?;
+            Box::new(CubemapCamera::new(look_from, look_at, up))
+        }
+        other => return Err(statement.error(&format!("unknown camera type: {}", other))),
+    };
+    Ok((camera, exposure))
+}
+
+// Photographic settings, if the camera has a focal length instead of a field
+// of view
+fn physical_settings(statement: &Statement) -> Result<Option<PhysicalSettings>, String> {
+    let keys = ["focal_length", "sensor", "f_number", "shutter", "iso"];
+    if statement.values("focal_length").is_none() {
+        return match keys.iter().find(|key| statement.values(key).is_some()) {
+            Some(key) => Err(statement.error(&format!("{} needs focal_length", key))),
+            None => Ok(None),
+        };
+    }
+    if statement.values("vfov").is_some() || statement.values("aperture").is_some() {
+        return Err(statement.error("focal_length replaces vfov and aperture"));
+    }
+    let sensor = statement.numbers("sensor", 2)?.unwrap_or(vec![36.0, 24.0]);
+    let settings = PhysicalSettings {
+        focal_length: statement.f64("focal_length", None)?,
+        sensor: (sensor[0], sensor[1]),
+        f_number: statement.f64("f_number", Some(8.0))?,
+        shutter: statement.f64("shutter", Some(1.0 / 125.0))?,
+        iso: statement.f64("iso", Some(100.0))?,
+    };
+    if [
+        settings.focal_length,
+        settings.sensor.0,
+        settings.sensor.1,
+        settings.f_number,
+        settings.shutter,
+        settings.iso,
+    ]
+    .iter()
+    .any(|&x| x <= 0.0)
+    {
+        return Err(statement.error("photographic settings must be positive"));
+    }
+    Ok(Some(settings))
+}
+
+// The light's IES profile with its nadir along `nadir`, if it has one
+fn ies_profile(statement: &Statement, nadir: Vec3) -> Result<Option<AimedProfile>, String> {
+    if statement.values("ies").is_none() {
+        return match ["lumens", "rotate"]
+            .iter()
+            .find(|key| statement.values(key).is_some())
+        {
+            Some(key) => Err(statement.error(&format!("{} needs ies", key))),
+            None => Ok(None),
+        };
+    }
+    let lumens = match statement.values("lumens") {
+        Some(_) => Some(statement.f64("lumens", None)?),
+        None => None,
+    };
+    if lumens.is_some_and(|lumens| lumens <= 0.0) {
+        return Err(statement.error("lumens must be positive"));
+    }
+    let path = statement.word("ies", None)?;
+    let mut profile = std::fs::read_to_string(&path)
+        .map_err(|err| err.to_string())
+        .and_then(|text| ies::parse_ies(&text))
+        .map_err(|err| statement.error(&format!("{}: {}", path, err)))?;
+    if let Some(lumens) = lumens {
+        profile = profile
+            .with_lumens(lumens)
+            .map_err(|err| statement.error(&format!("{}: {}", path, err)))?;
+    }
+    let rotation = statement.f64("rotate", Some(0.0))?;
+    Ok(Some(AimedProfile::new(profile, nadir, rotation)))
+}
+
+fn aperture(statement: &Statement) -> Result<Aperture, String> {
+    let blades = statement.f64("aperture_blades", Some(0.0))?;
+    let shape = match statement.values("aperture_image") {
+        Some(_) if blades != 0.0 => {
+            return Err(statement.error("aperture_blades and aperture_image don't mix"))
+        }
+        Some(_) => {
+            let path = statement.word("aperture_image", None)?;
+            let image = output::read_image(&path)
+                .map_err(|err| statement.error(&format!("could not read {}: {}", path, err)))?;
+            ApertureShape::from_image(&image)
+        }
+        None if blades == 0.0 => ApertureShape::Circle,
+        None if blades < 3.0 || blades.fract() != 0.0 => {
+            return Err(statement.error("aperture_blades must be a whole number of at least 3"))
+        }
+        None => ApertureShape::Polygon {
+            blades: blades as u32,
+            rotation: statement.f64("aperture_rotation", Some(0.0))?.to_radians(),
+        },
+    };
+    Ok(Aperture::new(
+        shape,
+        statement.f64("vignetting", Some(0.0))?,
+    ))
+}
+
+// Returns the material and whether it emits light
+fn material(statement: &Statement) -> Result<(Rc<dyn Material>, bool), String> {
+    let kind = statement.word("type", None)?;
+    let common = ["name", "type"];
+    let allow = |extra: &[&str]| statement.check(&[&common[..], extra].concat());
+    Ok(match kind.as_str() {
+        "lambertian" => {
+            allow(&["albedo"])?;
+            (Lambertian::new(statement.vec3("albedo", None)?), false)
+        }
+        "metal" => {
+            allow(&[
+                "albedo",
+                "preset",
+                "eta",
+                "k",
+                "roughness",
+                "roughness_u",
+                "roughness_v",
+            ])?;
+            // Roughness is the GGX alpha, optionally per tangent direction,
+            // and the color comes from exactly one of the three keys below
+            let roughness = statement.f64("roughness", Some(0.0))?;
+            let roughness = (
+                statement.f64("roughness_u", Some(roughness))?,
+                statement.f64("roughness_v", Some(roughness))?,
+            );
+            if roughness.0 < 0.0 || roughness.1 < 0.0 {
+                return Err(statement.error("roughness must not be negative"));
+            }
+            let given = |key: &str| statement.values(key).is_some();
+            match ["albedo", "preset", "eta"]
+                .iter()
+                .filter(|key| given(key))
+                .count()
+            {
+                0 => return Err(statement.error("metal needs albedo, preset or eta and k")),
+                1 => {}
+                _ => return Err(statement.error("albedo, preset and eta don't mix")),
+            }
+            if given("k") && !given("eta") {
+                return Err(statement.error("k needs eta"));
+            }
+            let metal = if given("preset") {
+                let name = statement.word("preset", None)?;
+                let (eta, k) = material::metal_preset(&name)
+                    .ok_or_else(|| statement.error(&format!("unknown metal preset: {}", name)))?;
+                Metal::conductor(eta, k, roughness)
+            } else if given("eta") {
+                let (eta, k) = (statement.vec3("eta", None)?, statement.vec3("k", None)?);
+                Metal::conductor(eta, k, roughness)
+            } else {
+                Metal::new(statement.vec3("albedo", None)?, roughness)
+            };
+            (metal, false)
+        }
+        "dielectric" => {
+            allow(&["ior"])?;
+            (Dielectric::new(statement.f64("ior", None)?), false)
+        }
+        "light" => {
+            allow(&["emit", "texture", "scale", "two_sided"])?;
+            // With a texture `emit` tints it, otherwise it is the color
+            let (texture, tint): (Rc<dyn Texture>, Vec3) = match statement.values("texture") {
+                Some(_) => {
+                    let path = statement.word("texture", None)?;
+                    let image = output::read_image(&path).map_err(|err| {
+                        statement.error(&format!("could not read {}: {}", path, err))
+                    })?;
+                    // Only PFM files hold linear values
+                    let srgb = !path.to_lowercase().ends_with(".pfm");
+                    (
+                        Rc::new(ImageTexture::new(image, srgb)),
+                        statement.vec3("emit", Some(Vec3::new(1.0, 1.0, 1.0)))?,
+                    )
+                }
+                None => (
+                    Rc::new(ConstantTexture::new(statement.vec3("emit", None)?)),
+                    Vec3::new(1.0, 1.0, 1.0),
+                ),
+            };
+            let two_sided = match statement.word("two_sided", Some("false"))?.as_str() {
+                "true" => true,
+                "false" => false,
+                _ => return Err(statement.error("two_sided must be true or false")),
+            };
+            let scale = statement.f64("scale", Some(1.0))?;
+            (
+                DiffuseLight::textured(texture, scale * tint, two_sided),
+                true,
+            )
+        }
+        other => return Err(statement.error(&format!("unknown material type: {}", other))),
+    })
+}
+
+// The material named by an object's `material` key and whether it emits
+fn object_material(
+    statement: &Statement,
+    materials: &HashMap<String, (Rc<dyn Material>, bool)>,
+) -> Result<(Rc<dyn Material>, bool), String> {
+    let name = statement.word("material", None)?;
+    materials
+        .get(&name)
+        .cloned()
+        .ok_or_else(|| statement.error(&format!("unknown material: {}", name)))
+}
+
+// Adds an object to the world, and to the lights if it emits
+fn add_object<T: Hitable + Clone + 'static>(
+    world: &mut HitableList,
+    lights: &mut Lights,
+    object: T,
+    emits: bool,
+) {
+    if emits {
+        lights.add_area(Box::new(object.clone()), world.len());
+    }
+    world.push(Box::new(object));
+}
+
+impl Scene {
+    // `width` and `height` are the image size in pixels and `seed` the
+    // default for anything random in the scene
+    pub fn parse(text: &str, width: usize, height: usize, seed: u64) -> Result<Scene, String> {
+        let mut world = HitableList::new();
+        let mut lights = Lights::new();
+        let mut material_ids = MaterialIds::default();
+        let mut camera = None;
+        let mut materials: HashMap<String, (Rc<dyn Material>, bool)> = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let statement = match Statement::parse(index + 1, line)? {
+                Some(statement) => statement,
+                None => continue,
+            };
+            match statement.keyword.as_str() {
+                "camera" => {
+                    if camera.is_some() {
+                        return Err(statement.error("only one camera is allowed"));
+                    }
+                    // Built last, since autofocus needs the objects
+                    camera = Some(statement);
+                }
+                "material" => {
+                    let name = statement.word("name", None)?;
+                    let (material, emits) = material(&statement)?;
+                    material_ids.add(material.as_ref());
+                    materials.insert(name, (material, emits));
+                }
+                "sphere" => {
+                    statement.check(&["center", "radius", "material"])?;
+                    let (material, emits) = object_material(&statement, &materials)?;
+                    let sphere = Sphere::new(
+                        statement.vec3("center", None)?,
+                        statement.f64("radius", None)?,
+                        material,
+                    );
+                    add_object(&mut world, &mut lights, sphere, emits);
+                }
+                "rect" => {
+                    statement.check(&["corner", "edge_u", "edge_v", "material"])?;
+                    let (material, emits) = object_material(&statement, &materials)?;
+                    let rect = Rect::new(
+                        statement.vec3("corner", None)?,
+                        statement.vec3("edge_u", None)?,
+                        statement.vec3("edge_v", None)?,
+                        material,
+                    );
+                    add_object(&mut world, &mut lights, rect, emits);
+                }
+                "triangle" => {
+                    statement.check(&["vertices", "material"])?;
+                    let (material, emits) = object_material(&statement, &materials)?;
+                    let v = statement
+                        .numbers("vertices", 9)?
+                        .ok_or_else(|| statement.error("triangle needs vertices"))?;
+                    let triangle = Triangle::new(
+                        Vec3::new(v[0], v[1], v[2]),
+                        Vec3::new(v[3], v[4], v[5]),
+                        Vec3::new(v[6], v[7], v[8]),
+                        material,
+                    );
+                    add_object(&mut world, &mut lights, triangle, emits);
+                }
+                "mesh" => {
+                    statement.check(&["file", "material"])?;
+                    let (material, emits) = object_material(&statement, &materials)?;
+                    let path = statement.word("file", None)?;
+                    let (vertices, faces) = std::fs::read_to_string(&path)
+                        .map_err(|err| err.to_string())
+                        .and_then(|text| triangle::parse_obj(&text))
+                        .map_err(|err| statement.error(&format!("{}: {}", path, err)))?;
+                    let mesh = Mesh::new(&vertices, &faces, material);
+                    add_object(&mut world, &mut lights, mesh, emits);
+                }
+                "point_light" => {
+                    statement.check(&[
+                        "position",
+                        "intensity",
+                        "ies",
+                        "look_at",
+                        "rotate",
+                        "lumens",
+                    ])?;
+                    // A profile is aimed straight down unless given look_at
+                    let position = statement.vec3("position", None)?;
+                    let nadir = statement
+                        .vec3("look_at", Some(position - Vec3::new(0.0, 1.0, 0.0)))?
+                        - position;
+                    let profile = ies_profile(&statement, nadir)?;
+                    if profile.is_none() && statement.values("look_at").is_some() {
+                        return Err(statement.error("look_at needs ies"));
+                    }
+                    let intensity = match profile {
+                        Some(_) => statement.vec3("intensity", Some(Vec3::new(1.0, 1.0, 1.0)))?,
+                        None => statement.vec3("intensity", None)?,
+                    };
+                    let mut light = PointLight::new(position, intensity);
+                    if let Some(profile) = profile {
+                        light = light.with_profile(profile);
+                    }
+                    lights.delta.push(Box::new(light));
+                }
+                "spot_light" => {
+                    statement.check(&[
+                        "position",
+                        "look_at",
+                        "intensity",
+                        "cone_angle",
+                        "cone_delta",
+                        "ies",
+                        "rotate",
+                        "lumens",
+                    ])?;
+                    let position = statement.vec3("position", None)?;
+                    let look_at = statement.vec3("look_at", None)?;
+                    let profile = ies_profile(&statement, look_at - position)?;
+                    let intensity = match profile {
+                        Some(_) => statement.vec3("intensity", Some(Vec3::new(1.0, 1.0, 1.0)))?,
+                        None => statement.vec3("intensity", None)?,
+                    };
+                    // Full intensity up to cone_delta inside the cone's edge
+                    let cone_angle = statement.f64("cone_angle", Some(30.0))?;
+                    let cone_delta = statement.f64("cone_delta", Some(5.0))?;
+                    let mut light = SpotLight::new(
+                        position,
+                        look_at,
+                        intensity,
+                        cone_angle,
+                        cone_angle - cone_delta,
+                    );
+                    if let Some(profile) = profile {
+                        light = light.with_profile(profile);
+                    }
+                    lights.delta.push(Box::new(light));
+                }
+                "directional_light" => {
+                    statement.check(&["direction", "irradiance"])?;
+                    lights.delta.push(Box::new(DirectionalLight::new(
+                        statement.vec3("direction", None)?,
+                        statement.vec3("irradiance", None)?,
+                    )));
+                }
+                "sky" => {
+                    statement.check(&[
+                        "elevation",
+                        "azimuth",
+                        "turbidity",
+                        "ground_albedo",
+                        "scale",
+                    ])?;
+                    // Sun angles in degrees, azimuth from -z towards +x
+                    let elevation = statement.f64("elevation", Some(45.0))?;
+                    if !(0.0..=90.0).contains(&elevation) {
+                        return Err(statement.error("elevation must be from 0 to 90 degrees"));
+                    }
+                    let turbidity = statement.f64("turbidity", Some(3.0))?;
+                    if !(1.7..=10.0).contains(&turbidity) {
+                        return Err(statement.error("turbidity must be from 1.7 to 10"));
+                    }
+                    lights.set_environment(Box::new(Sky::new(
+                        elevation,
+                        statement.f64("azimuth", Some(0.0))?,
+                        turbidity,
+                        statement.f64("ground_albedo", Some(0.3))?,
+                        statement.f64("scale", Some(1.0))?,
+                    )));
+                }
+                "random_spheres" => {
+                    statement.check(&["seed"])?;
+                    let seed = statement.f64("seed", Some(seed as f64))? as u64;
+                    random_spheres(&mut world, &mut material_ids, seed);
+                }
+                "random_lights" => {
+                    statement.check(&["count", "size", "seed"])?;
+                    let count = statement.f64("count", Some(10000.0))? as usize;
+                    let size = statement.f64("size", Some(100.0))?;
+                    let seed = statement.f64("seed", Some(seed as f64))? as u64;
+                    random_lights(
+                        &mut world,
+                        &mut lights,
+                        &mut material_ids,
+                        count,
+                        size,
+                        seed,
+                    );
+                }
+                other => return Err(statement.error(&format!("unknown statement: {}", other))),
+            }
+        }
+        lights.set_sampler(LightSamplerKind::Bvh);
+        let camera = camera.ok_or("scene has no camera")?;
+        let (camera, exposure) = self::camera(&camera, width, height, &world)?;
+        Ok(Scene {
+            world,
+            lights,
+            camera,
+            exposure,
+            material_ids,
+        })
+    }
+}
+
+// Small random spheres around three big ones
+fn random_spheres(world: &mut HitableList, material_ids: &mut MaterialIds, seed: u64) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut sphere = |center: Vec3, radius: f64, material: Rc<dyn Material>| {
+        material_ids.add(material.as_ref());
+        Sphere::new(center, radius, material)
+    };
+    world.push(Box::new(sphere(
+        Vec3::new(0.0, -1000.0, 0.0),
+        1000.0,
+        Lambertian::new(Vec3::new(0.5, 0.5, 0.5)),
+    )));
+    for a in -11..11 {
+        for b in -11..11 {
+            let choose_mat = rng.gen::<f64>();
+            let center = Vec3::new(
+                a as f64 + 0.9 * rng.gen::<f64>(),
+                0.2,
+                b as f64 + 0.9 * rng.gen::<f64>(),
+            );
+            if (center - Vec3::new(4.0, 0.2, 0.0)).len() > 0.9 {
+                if choose_mat < 0.8 {
+                    //Lambertian
+                    world.push(Box::new(sphere(
+                        center,
+                        0.2,
+                        Lambertian::new(Vec3::new(
+                            rng.gen::<f64>() * rng.gen::<f64>(),
+                            rng.gen::<f64>() * rng.gen::<f64>(),
+                            rng.gen::<f64>() * rng.gen::<f64>(),
+                        )),
+                    )));
+                } else if choose_mat < 0.95 {
+                    // Metal
+                    let albedo = Vec3::new(
+                        0.5 * (1.0 + rng.gen::<f64>()),
+                        0.5 * (1.0 + rng.gen::<f64>()),
+                        0.5 * (1.0 + rng.gen::<f64>()),
+                    );
+                    let roughness = 0.5 * rng.gen::<f64>();
+                    world.push(Box::new(sphere(
+                        center,
+                        0.2,
+                        Metal::new(albedo, (roughness, roughness)),
+                    )));
+                } else {
+                    world.push(Box::new(sphere(center, 0.2, Dielectric::new(1.5))))
+                }
+            }
+        }
+    }
+    world.push(Box::new(sphere(
+        Vec3::new(0.0, 1.0, 0.0),
+        1.0,
+        Dielectric::new(1.5),
+    )));
+    world.push(Box::new(sphere(
+        Vec3::new(-4.0, 1.0, 0.0),
+        1.0,
+        Lambertian::new(Vec3::new(0.4, 0.2, 0.1)),
+    )));
+    world.push(Box::new(sphere(
+        Vec3::new(4.0, 1.0, 0.0),
+        1.0,
+        Metal::new(Vec3::new(0.7, 0.6, 0.5), (0.0, 0.0)),
+    )));
+}
+
+// A ground with `count` small warm lamps above it, like a city at night
+fn random_lights(
+    world: &mut HitableList,
+    lights: &mut Lights,
+    material_ids: &mut MaterialIds,
+    count: usize,
+    size: f64,
+    seed: u64,
+) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let ground: Rc<dyn Material> = Lambertian::new(Vec3::new(0.4, 0.4, 0.4));
+    material_ids.add(ground.as_ref());
+    world.push(Box::new(Rect::new(
+        Vec3::new(-0.5 * size, 0.0, 0.5 * size),
+        Vec3::new(size, 0.0, 0.0),
+        Vec3::new(0.0, 0.0, -size),
+        ground,
+    )));
+    for i in 0..count {
+        let position = Vec3::new(
+            size * (rng.gen::<f64>() - 0.5),
+            0.1 + 4.9 * rng.gen::<f64>(),
+            size * (rng.gen::<f64>() - 0.5),
+        );
+        let warmth = rng.gen::<f64>();
+        let emit = 20.0 * Vec3::new(1.0, 0.6 + 0.3 * warmth, 0.2 + 0.5 * warmth);
+        let material: Rc<dyn Material> = DiffuseLight::new(emit);
+        material_ids.add(material.as_ref());
+        if i % 2 == 0 {
+            let lamp = Sphere::new(position, 0.05, material);
+            add_object(world, lights, lamp, true);
+        } else {
+            let panel = Rect::new(
+                position,
+                Vec3::new(0.2, 0.0, 0.0),
+                Vec3::new(0.0, 0.0, 0.2),
+                material,
+            );
+            add_object(world, lights, panel, true);
+        }
+    }
+}
+
+#[test]
+fn scene_description_parsing() {
+    use crate::hitable::Hitable;
+    use crate::light::SampledLight;
+    use crate::sampler::IndependentSampler;
+
+    let scene = Scene::parse(
+        "# a lit sphere\n\
+         material name lamp type light emit 4 4 4\n\
+         material name red type lambertian albedo 0.8 0.1 0.1\n\
+         sphere center 0 0 -3 radius 1 material red\n\
+         sphere center 0 5 0 radius 0.5 material lamp\n\
+         camera type fisheye projection equisolid fov 180 up 0 1 0\n",
+        1,
+        1,
+        0,
+    )
+    .unwrap();
+    // The middle of the image looks straight ahead, the corners see nothing
+    let mut sampler = IndependentSampler::new(0);
+    let ray = scene.camera.generate_ray(0.5, 0.5, &mut sampler).unwrap();
+    assert!((Vec3::unit_vector(ray.direction()) - Vec3::new(0.0, 0.0, -1.0)).len() < 1e-9);
+    let rec = scene.world.hit(&ray, 1e-3, f64::MAX).unwrap();
+    assert!((rec.t - 2.0).abs() < 1e-9);
+    match scene
+        .lights
+        .sample(Vec3::default(), Vec3::default(), (0.5, 0.5))
+    {
+        Some(SampledLight::Area(light)) => {
+            assert!((light.p - Vec3::new(0.0, 5.0, 0.0)).len() <= 0.5 + 1e-9)
+        }
+        _ => panic!("expected the lamp"),
+    }
+    assert!(scene.camera.generate_ray(0.0, 0.0, &mut sampler).is_none());
+
+    for (text, error) in &[
+        ("random_spheres", "scene has no camera"),
+        (
+            "camera type pinhole",
+            "line 1: unknown camera type: pinhole",
+        ),
+        ("camera type orthographic", "line 1: camera needs height"),
+        (
+            "camera type perspective vfov 1 2",
+            "line 1: vfov needs 1 number(s)",
+        ),
+        (
+            "sphere center 0 0 0 radius 1 material x",
+            "line 1: unknown material: x",
+        ),
+        (
+            "material name m type lambertian albedo 1 1 1\n\
+             sphere center 0 0 0 radius nan material m",
+            "line 2: radius needs 1 number(s)",
+        ),
+        (
+            "material name m type metal preset gold albedo 1 1 1",
+            "line 1: albedo, preset and eta don't mix",
+        ),
+        (
+            "point_light position 0 1 0 lumens 800",
+            "line 1: lumens needs ies",
+        ),
+        (
+            "point_light position 0 1 0 ies lamp.ies lumens 0",
+            "line 1: lumens must be positive",
+        ),
+        (
+            "camera type perspective iso 400",
+            "line 1: iso needs focal_length",
+        ),
+        (
+            "camera type perspective autofocus 0 0",
+            "line 1: autofocus pixel sees nothing",
+        ),
+    ] {
+        assert_eq!(Scene::parse(text, 1, 1, 0).err().as_deref(), Some(*error));
+    }
+}
+
+#[test]
+fn lamp_scene() {
+    use crate::light::SampledLight;
+
+    // The default scene has nothing to sample; the lamp scene adds one lamp
+    // to the same spheres
+    let default = Scene::parse(DEFAULT_SCENE, 1, 1, 0).unwrap();
+    let lamp = Scene::parse(include_str!("../scenes/spheres_lamp.txt"), 1, 1, 0).unwrap();
+    assert_eq!(lamp.world.len(), default.world.len() + 1);
+    let (p, n) = (Vec3::default(), Vec3::new(0.0, 1.0, 0.0));
+    assert!(default.lights.sample(p, n, (0.5, 0.5)).is_none());
+    match lamp.lights.sample(p, n, (0.5, 0.5)) {
+        Some(SampledLight::Area(light)) => {
+            assert!((light.p - Vec3::new(2.0, 3.0, -1.5)).len() <= 0.25 + 1e-9)
+        }
+        _ => panic!("expected the lamp"),
+    }
+}
@@ -0,0 +1,302 @@
+use crate::distribution::Distribution2D;
+use crate::light::Environment;
+use crate::onb::Onb;
+use crate::sampler::remap;
+use crate::vec3::Vec3;
+use std::f64::consts::PI;
+
+// Angular radius of the sun's disk
+const SUN_RADIUS: f64 = 0.004_65;
+// Luminance of the sun above the atmosphere, in cd/m^2
+const SUN_LUMINANCE: f64 = 1.88e9;
+// Resolution of the latitude-longitude map the sky is sampled from
+const MAP_WIDTH: usize = 256;
+const MAP_HEIGHT: usize = 128;
+
+// Coefficients A to E of the Perez sky luminance distribution for
+// luminance Y and chromaticities x and y at turbidity `t`
+fn perez_coefficients(t: f64) -> [[f64; 5]; 3] {
+    [
+        [
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703,
+        ],
+        [
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452,
+        ],
+        [
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529,
+        ],
+    ]
+}
+
+// Relative sky value at zenith angle acos(`cos_theta`), `gamma` from the sun
+fn perez(c: &[f64; 5], cos_theta: f64, gamma: f64) -> f64 {
+    (1.0 + c[0] * (c[1] / cos_theta).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * gamma.cos().powi(2))
+}
+
+// Luminance in cd/m^2 and chromaticity at the zenith, with the sun
+// `theta_s` radians from it
+fn zenith(t: f64, theta_s: f64) -> [f64; 3] {
+    let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_s);
+    let luminance = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+    let powers = [theta_s.powi(3), theta_s.powi(2), theta_s, 1.0];
+    let poly = |c: [[f64; 4]; 3]| {
+        let row = |r: [f64; 4]| r.iter().zip(&powers).map(|(a, b)| a * b).sum::<f64>();
+        t * t * row(c[0]) + t * row(c[1]) + row(c[2])
+    };
+    let x = poly([
+        [0.00166, -0.00375, 0.00209, 0.0],
+        [-0.02903, 0.06377, -0.03202, 0.00394],
+        [0.11693, -0.21196, 0.06052, 0.25886],
+    ]);
+    let y = poly([
+        [0.00275, -0.00610, 0.00317, 0.0],
+        [-0.04214, 0.08970, -0.04153, 0.00516],
+        [0.15346, -0.26756, 0.06670, 0.26688],
+    ]);
+    [1000.0 * luminance, x, y]
+}
+
+// Linear sRGB from luminance and xy chromaticity
+fn xyy_to_rgb(luminance: f64, x: f64, y: f64) -> Vec3 {
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    Vec3::new(
+        (3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z).max(0.0),
+        (-0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z).max(0.0),
+        (0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z).max(0.0),
+    )
+}
+
+// Fraction of sunlight at wavelength `lambda` in micrometers that gets
+// through air mass `m` by Rayleigh and aerosol scattering, the terms that
+// color the sun
+fn sun_transmittance(lambda: f64, t: f64, m: f64) -> f64 {
+    let rayleigh = (-0.008735 * lambda.powf(-4.08) * m).exp();
+    let beta = 0.04608 * t - 0.04586;
+    let aerosol = (-beta * lambda.powf(-1.3) * m).exp();
+    rayleigh * aerosol
+}
+
+// Unit direction for a point of the latitude-longitude map, with the zenith
+// at v = 0
+fn map_direction(u: f64, v: f64) -> Vec3 {
+    let (sin_theta, cos_theta) = (PI * v).sin_cos();
+    let (sin_phi, cos_phi) = (2.0 * PI * u).sin_cos();
+    Vec3::new(sin_theta * cos_phi, cos_theta, sin_theta * sin_phi)
+}
+
+// Daylight from the Preetham et al. analytic sky model, with the sun as a
+// disk of matching color. Radiance is in cd/m^2 times `scale`, so it suits
+// a camera with physical exposure. Below the horizon is a uniform ground
+// reflecting `ground_albedo` of the light falling on it.
+pub struct Sky {
+    sun: Vec3,
+    perez: [[f64; 5]; 3],
+    // Zenith values divided by the Perez function there
+    zenith: [f64; 3],
+    sun_radiance: Vec3,
+    cos_sun_radius: f64,
+    ground: Vec3,
+    scale: f64,
+    map: Distribution2D,
+    // Chance of sampling the sun rather than the map
+    sun_probability: f64,
+}
+
+impl Sky {
+    // The sun is `elevation` degrees above the horizon and `azimuth` degrees
+    // around from -z towards +x. `turbidity` runs from 1.7 for a very clear sky to
+    // 10 for haze.
+    pub fn new(
+        elevation: f64,
+        azimuth: f64,
+        turbidity: f64,
+        ground_albedo: f64,
+        scale: f64,
+    ) -> Sky {
+        let (elevation, azimuth) = (elevation.to_radians(), azimuth.to_radians());
+        let sun = Vec3::new(
+            elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+            -elevation.cos() * azimuth.cos(),
+        );
+        let theta_s = PI / 2.0 - elevation;
+        let coefficients = perez_coefficients(turbidity);
+        let mut zenith = zenith(turbidity, theta_s);
+        for (z, c) in zenith.iter_mut().zip(&coefficients) {
+            *z /= perez(c, 1.0, theta_s);
+        }
+        // Air mass after Kasten
+        let m = 1.0 / (theta_s.cos() + 0.15 * (93.885 - theta_s.to_degrees()).powf(-1.253));
+        let sun_radiance = SUN_LUMINANCE
+            * Vec3::new(
+                sun_transmittance(0.65, turbidity, m),
+                sun_transmittance(0.55, turbidity, m),
+                sun_transmittance(0.45, turbidity, m),
+            );
+        let cos_sun_radius = SUN_RADIUS.cos();
+        let mut sky = Sky {
+            sun,
+            perez: coefficients,
+            zenith,
+            sun_radiance,
+            cos_sun_radius,
+            ground: Vec3::default(),
+            scale,
+            map: Distribution2D::new(&[1.0], 1, 1),
+            sun_probability: 0.0,
+        };
+        // Light on the ground from the sky, summed over the upper half of
+        // the map, and from the sun
+        let sun_solid_angle = 2.0 * PI * (1.0 - cos_sun_radius);
+        let cell = (PI / MAP_HEIGHT as f64) * (2.0 * PI / MAP_WIDTH as f64);
+        let mut irradiance = sun_radiance * sun_solid_angle * sun.y();
+        for row in 0..MAP_HEIGHT / 2 {
+            for col in 0..MAP_WIDTH {
+                let v = (row as f64 + 0.5) / MAP_HEIGHT as f64;
+                let d = map_direction((col as f64 + 0.5) / MAP_WIDTH as f64, v);
+                irradiance += sky.sky_radiance(d) * d.y() * (PI * v).sin() * cell;
+            }
+        }
+        sky.ground = ground_albedo / PI * irradiance;
+        let mut func = Vec::with_capacity(MAP_WIDTH * MAP_HEIGHT);
+        for row in 0..MAP_HEIGHT {
+            for col in 0..MAP_WIDTH {
+                let v = (row as f64 + 0.5) / MAP_HEIGHT as f64;
+                let d = map_direction((col as f64 + 0.5) / MAP_WIDTH as f64, v);
+                func.push(sky.sky_radiance(d).luminance() * (PI * v).sin());
+            }
+        }
+        // Pick the sun in proportion to the light it gives
+        let sky_power = func.iter().sum::<f64>() * cell;
+        let sun_power = sun_radiance.luminance() * sun_solid_angle;
+        sky.sun_probability = sun_power / (sun_power + sky_power);
+        sky.map = Distribution2D::new(&func, MAP_WIDTH, MAP_HEIGHT);
+        sky
+    }
+
+    // Unscaled radiance of the sky or ground alone along unit direction `d`
+    fn sky_radiance(&self, d: Vec3) -> Vec3 {
+        if d.y() < 0.0 {
+            return self.ground;
+        }
+        let gamma = Vec3::dot(d, self.sun).clamp(-1.0, 1.0).acos();
+        let [luminance, x, y] =
+            [0, 1, 2].map(|i| self.zenith[i] * perez(&self.perez[i], d.y(), gamma));
+        xyy_to_rgb(luminance, x, y)
+    }
+
+    fn map_pdf(&self, d: Vec3) -> f64 {
+        let theta = d.y().clamp(-1.0, 1.0).acos();
+        let sin_theta = theta.sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        let phi = d.z().atan2(d.x()).rem_euclid(2.0 * PI);
+        self.map.pdf((phi / (2.0 * PI), theta / PI)) / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+impl Environment for Sky {
+    fn radiance(&self, direction: Vec3) -> Vec3 {
+        let d = Vec3::unit_vector(direction);
+        let mut radiance = self.sky_radiance(d);
+        if Vec3::dot(d, self.sun) >= self.cos_sun_radius {
+            radiance += self.sun_radiance;
+        }
+        self.scale * radiance
+    }
+
+    fn importance_sampled(&self) -> bool {
+        true
+    }
+
+    // Samples the sun's disk uniformly or the map by luminance
+    fn sample(&self, u: (f64, f64)) -> Option<(Vec3, f64)> {
+        let wi = if u.0 < self.sun_probability {
+            let u0 = remap(u.0, 0.0, self.sun_probability);
+            let cos_theta = 1.0 + u0 * (self.cos_sun_radius - 1.0);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = 2.0 * PI * u.1;
+            Onb::build_from_w(self.sun).local(
+                phi.cos() * sin_theta,
+                phi.sin() * sin_theta,
+                cos_theta,
+            )
+        } else {
+            let u0 = remap(u.0, self.sun_probability, 1.0 - self.sun_probability);
+            let ((x, y), _) = self.map.sample((u0, u.1));
+            map_direction(x, y)
+        };
+        let pdf = self.pdf(wi);
+        if pdf == 0.0 {
+            return None;
+        }
+        Some((wi, pdf))
+    }
+
+    fn pdf(&self, direction: Vec3) -> f64 {
+        let d = Vec3::unit_vector(direction);
+        let mut pdf = (1.0 - self.sun_probability) * self.map_pdf(d);
+        if Vec3::dot(d, self.sun) >= self.cos_sun_radius {
+            pdf += self.sun_probability / (2.0 * PI * (1.0 - self.cos_sun_radius));
+        }
+        pdf
+    }
+}
+
+#[test]
+fn sky_sampling_matches_pdf() {
+    use crate::sampler::{Sampler, SobolSampler};
+
+    let sky = Sky::new(30.0, 40.0, 3.0, 0.3, 1.0);
+    // A clear sky is brighter near the sun than opposite it, and the sun is
+    // yellower than it would be overhead
+    let toward = sky.radiance(Vec3::new(0.6, 0.5, -0.6)).luminance();
+    let away = sky.radiance(Vec3::new(-0.6, 0.5, 0.6)).luminance();
+    assert!(toward > away && away > 0.0);
+    let sun = sky.radiance(sky.sun);
+    assert!(sun.b() < sun.g() && sun.g() < sun.r());
+    // Luminance summed over the sphere, by importance sampling and by
+    // brute force with the sun added separately
+    let n = 1 << 16;
+    let mut sampler = SobolSampler::new(0);
+    let mut sampled = 0.0;
+    for index in 0..n {
+        sampler.start_pixel_sample((0, 0), index);
+        let (wi, pdf) = sky.sample(sampler.get_2d()).unwrap();
+        assert!((sky.pdf(wi) - pdf).abs() <= 1e-9 * pdf);
+        sampled += sky.radiance(wi).luminance() / pdf;
+    }
+    sampled /= n as f64;
+    let (width, height) = (1024, 512);
+    let mut expected = sky.sun_radiance.luminance() * 2.0 * PI * (1.0 - sky.cos_sun_radius);
+    for row in 0..height {
+        for col in 0..width {
+            let v = (row as f64 + 0.5) / height as f64;
+            let d = map_direction((col as f64 + 0.5) / width as f64, v);
+            expected += sky.sky_radiance(d).luminance() * (PI * v).sin() * 2.0 * PI * PI
+                / (width * height) as f64;
+        }
+    }
+    assert!(
+        (sampled - expected).abs() < 0.01 * expected,
+        "{} vs {}",
+        sampled,
+        expected
+    );
+}
@@ -1,31 +1,81 @@
-use crate::hitable::{HitRecord, Hitable};
+use crate::distribution::Distribution2D;
+use crate::hitable::{area_to_solid_angle, HitRecord, Hitable, LightSample};
+use crate::light_sampler::{Aabb, DirectionCone, LightBounds};
 use crate::material::{Lambertian, Material};
+use crate::onb::Onb;
 use crate::ray::Ray;
+use crate::stats;
 use crate::vec3::Vec3;
-use rand::{thread_rng, Rng};
+use std::f64::consts::PI;
 use std::rc::Rc;
+
+// Surface coordinates of the point with unit `normal`: u goes once around
+// the y axis starting from -x, v from the bottom pole to the top one
+pub fn sphere_uv(normal: Vec3) -> (f64, f64) {
+    let theta = (-normal.y()).clamp(-1.0, 1.0).acos();
+    let phi = (-normal.z()).atan2(normal.x()) + PI;
+    (phi / (2.0 * PI), theta / PI)
+}
+
+// Unit normal at surface coordinates `uv`, the inverse of sphere_uv
+fn sphere_normal(uv: (f64, f64)) -> Vec3 {
+    let (sin_theta, cos_theta) = (uv.1 * PI).sin_cos();
+    let (sin_phi, cos_phi) = (uv.0 * 2.0 * PI - PI).sin_cos();
+    Vec3::new(sin_theta * cos_phi, -cos_theta, -sin_theta * sin_phi)
+}
+
+#[derive(Clone)]
 pub struct Sphere {
     center: Vec3,
     radius: f64,
     material: Rc<dyn Material>,
+    // Where a textured emitter is bright over (u, v), weighted by the area
+    // of the surface at each v
+    emission: Option<Rc<Distribution2D>>,
 }
 
 impl Sphere {
     pub fn new(center: Vec3, radius: f64, material: Rc<dyn Material>) -> Sphere {
+        let emission = material
+            .emission_importance()
+            .map(|(mut func, width, height)| {
+                for (j, row) in func.chunks_mut(width).enumerate() {
+                    let sin_theta = ((j as f64 + 0.5) / height as f64 * PI).sin();
+                    row.iter_mut().for_each(|f| *f *= sin_theta);
+                }
+                Rc::new(Distribution2D::new(&func, width, height))
+            });
         Sphere {
-            center: center,
-            radius: radius,
-            material: material,
+            center,
+            radius,
+            material,
+            emission,
         }
     }
-}
-impl Default for Sphere {
-    fn default() -> Self {
-        Self::new(Vec3::default(), 0.0, Lambertian::new(Vec3::default()))
+
+    // Density over area of `emission` sampling the point at `uv`
+    fn emission_pdf(&self, emission: &Distribution2D, uv: (f64, f64)) -> f64 {
+        let sin_theta = (uv.1 * PI).sin();
+        if sin_theta == 0.0 {
+            return 0.0;
+        }
+        emission.pdf(uv) / (2.0 * PI * PI * self.radius * self.radius * sin_theta)
     }
-}
-impl Hitable for Sphere {
-    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64, rec: &mut HitRecord) -> bool {
+
+    // Cosine of the half angle of the cone the sphere subtends from `origin`,
+    // or None when `origin` is inside the sphere
+    fn cos_theta_max(&self, origin: Vec3) -> Option<f64> {
+        let dist_squared = (self.center - origin).squared_len();
+        let radius_squared = self.radius * self.radius;
+        if dist_squared <= radius_squared {
+            return None;
+        }
+        Some((1.0 - radius_squared / dist_squared).sqrt())
+    }
+
+    // The hit itself. sample and pdf call this rather than hit, so that
+    // light sampling doesn't show up in the intersection test count.
+    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
         let oc = ray.origin() - self.center;
         let a = Vec3::dot(ray.direction(), ray.direction());
         let b = Vec3::dot(oc, ray.direction());
@@ -34,31 +84,108 @@ impl Hitable for Sphere {
         if discriminant > 0.0 {
             let mut temp = (-b - discriminant.sqrt()) / a;
             if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = ray.point_at_parameter(rec.t);
-                rec.normal = (rec.p - self.center) / self.radius;
-                rec.material = self.material.clone();
-                return true;
+                let p = ray.point_at_parameter(temp);
+                let normal = (p - self.center) / self.radius;
+                return Some(HitRecord {
+                    t: temp,
+                    p,
+                    normal,
+                    uv: sphere_uv(normal),
+                    material: self.material.as_ref(),
+                    object_id: 0,
+                });
             }
             temp = (-b + discriminant.sqrt()) / a;
             if temp < t_max && temp > t_min {
-                rec.t = temp;
-                rec.p = ray.point_at_parameter(rec.t);
-                rec.normal = (rec.p - self.center) / self.radius;
-                rec.material = self.material.clone();
-                return true;
+                let p = ray.point_at_parameter(temp);
+                let normal = (p - self.center) / self.radius;
+                return Some(HitRecord {
+                    t: temp,
+                    p,
+                    normal,
+                    uv: sphere_uv(normal),
+                    material: self.material.as_ref(),
+                    object_id: 0,
+                });
             }
         }
-        return false;
+        None
+    }
+}
+impl Default for Sphere {
+    fn default() -> Self {
+        Self::new(Vec3::default(), 0.0, Lambertian::new(Vec3::default()))
     }
 }
-pub fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = thread_rng();
-    loop {
-        let p = 2.0 * Vec3::new(rng.gen::<f64>(), rng.gen::<f64>(), rng.gen::<f64>())
-            - Vec3::new(1.0, 1.0, 1.0);
-        if p.squared_len() >= 1.0 {
-            return p;
+impl Hitable for Sphere {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        stats::intersection_test();
+        self.intersect(ray, t_min, t_max)
+    }
+
+    // Samples the cone of directions subtended by the sphere, or a textured
+    // sphere's surface by the brightness of the texture. Points on the far
+    // side of it are hidden and so give nothing.
+    fn sample(&self, origin: Vec3, u: (f64, f64)) -> Option<LightSample> {
+        if let Some(emission) = &self.emission {
+            let (uv, _) = emission.sample(u);
+            let normal = sphere_normal(uv);
+            let p = self.center + self.radius * normal;
+            let pdf = self.emission_pdf(emission, uv);
+            return Some(LightSample {
+                p,
+                normal,
+                pdf: area_to_solid_angle(pdf, origin, p, normal),
+                two_sided: self.material.two_sided(),
+            });
+        }
+        let cos_theta_max = self.cos_theta_max(origin)?;
+        let cos_theta = 1.0 + u.0 * (cos_theta_max - 1.0);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = 2.0 * PI * u.1;
+        let uvw = Onb::build_from_w(self.center - origin);
+        let direction = uvw.local(phi.cos() * sin_theta, phi.sin() * sin_theta, cos_theta);
+        let rec = self.intersect(&Ray::new(origin, direction), 0.0, f64::MAX)?;
+        Some(LightSample {
+            p: rec.p,
+            normal: rec.normal,
+            pdf: 1.0 / (2.0 * PI * (1.0 - cos_theta_max)),
+            two_sided: self.material.two_sided(),
+        })
+    }
+
+    fn pdf(&self, origin: Vec3, direction: Vec3) -> f64 {
+        if let Some(emission) = &self.emission {
+            return match self.intersect(&Ray::new(origin, direction), 0.001, f64::MAX) {
+                Some(rec) => {
+                    let pdf = self.emission_pdf(emission, rec.uv);
+                    area_to_solid_angle(pdf, origin, rec.p, rec.normal)
+                }
+                None => 0.0,
+            };
         }
+        let cos_theta_max = match self.cos_theta_max(origin) {
+            Some(cos_theta_max) => cos_theta_max,
+            None => return 0.0,
+        };
+        let ray = Ray::new(origin, direction);
+        if self.intersect(&ray, 0.0, f64::MAX).is_some() {
+            1.0 / (2.0 * PI * (1.0 - cos_theta_max))
+        } else {
+            0.0
+        }
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        let r = Vec3::new(self.radius, self.radius, self.radius);
+        Some(
+            LightBounds::new(
+                Aabb::new(self.center - r, self.center + r),
+                DirectionCone::entire_sphere(),
+                4.0 * PI * self.radius * self.radius,
+                self.material.emission(),
+            )
+            .two_sided(self.material.two_sided()),
+        )
     }
 }
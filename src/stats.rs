@@ -0,0 +1,238 @@
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+// Render statistics, gathered per thread through the free functions below
+#[derive(Clone, Default, Debug)]
+pub struct Stats {
+    // Rays traced along paths, indexed by bounce depth (0 is camera rays)
+    pub rays_by_depth: Vec<u64>,
+    pub shadow_rays: u64,
+    // Ray-primitive intersection tests
+    pub intersection_tests: u64,
+    pub paths: u64,
+    // Total rays over all paths, excluding shadow rays
+    pub path_rays: u64,
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+thread_local! {
+    static STATS: RefCell<Stats> = RefCell::new(Stats::default());
+}
+
+pub fn ray(depth: usize) {
+    STATS.with(|s| {
+        let mut s = s.borrow_mut();
+        if s.rays_by_depth.len() <= depth {
+            s.rays_by_depth.resize(depth + 1, 0);
+        }
+        s.rays_by_depth[depth] += 1;
+    })
+}
+
+pub fn shadow_ray() {
+    STATS.with(|s| s.borrow_mut().shadow_rays += 1)
+}
+
+pub fn intersection_test() {
+    STATS.with(|s| s.borrow_mut().intersection_tests += 1)
+}
+
+// A finished path that traced `rays` rays
+pub fn path(rays: usize) {
+    STATS.with(|s| {
+        let mut s = s.borrow_mut();
+        s.paths += 1;
+        s.path_rays += rays as u64;
+    })
+}
+
+// Adds `duration` to the time spent in phase `name`
+pub fn phase(name: &'static str, duration: Duration) {
+    STATS.with(|s| {
+        let mut s = s.borrow_mut();
+        match s.phases.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, total)) => *total += duration,
+            None => s.phases.push((name, duration)),
+        }
+    })
+}
+
+pub fn snapshot() -> Stats {
+    STATS.with(|s| s.borrow().clone())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StatsFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for StatsFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(StatsFormat::Text),
+            "json" => Ok(StatsFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Stats {
+    pub fn total_rays(&self) -> u64 {
+        self.rays_by_depth.iter().sum::<u64>() + self.shadow_rays
+    }
+
+    pub fn average_path_length(&self) -> f64 {
+        if self.paths == 0 {
+            return 0.0;
+        }
+        self.path_rays as f64 / self.paths as f64
+    }
+
+    pub fn format(&self, format: StatsFormat) -> String {
+        match format {
+            StatsFormat::Text => self.text(),
+            StatsFormat::Json => self.json(),
+        }
+    }
+
+    fn text(&self) -> String {
+        let mut s = String::new();
+        writeln!(s, "render statistics").unwrap();
+        writeln!(s, "  total rays          {}", self.total_rays()).unwrap();
+        writeln!(s, "  shadow rays         {}", self.shadow_rays).unwrap();
+        writeln!(s, "  intersection tests  {}", self.intersection_tests).unwrap();
+        writeln!(s, "  paths               {}", self.paths).unwrap();
+        writeln!(s, "  average path length {:.3}", self.average_path_length()).unwrap();
+        writeln!(s, "  rays per depth").unwrap();
+        for (depth, rays) in self.rays_by_depth.iter().enumerate() {
+            writeln!(s, "    {:>3}  {}", depth, rays).unwrap();
+        }
+        writeln!(s, "  time per phase").unwrap();
+        for (name, duration) in &self.phases {
+            writeln!(s, "    {:<10}  {:.3}s", name, duration.as_secs_f64()).unwrap();
+        }
+        s
+    }
+
+    fn json(&self) -> String {
+        let rays_by_depth: Vec<String> = self.rays_by_depth.iter().map(|r| r.to_string()).collect();
+        let phases: Vec<String> = self
+            .phases
+            .iter()
+            .map(|(name, duration)| format!("\"{}\": {:.6}", name, duration.as_secs_f64()))
+            .collect();
+        format!(
+            "{{\"total_rays\": {}, \"shadow_rays\": {}, \"intersection_tests\": {}, \
+             \"paths\": {}, \"average_path_length\": {:.6}, \"rays_by_depth\": [{}], \
+             \"phase_seconds\": {{{}}}}}",
+            self.total_rays(),
+            self.shadow_rays,
+            self.intersection_tests,
+            self.paths,
+            self.average_path_length(),
+            rays_by_depth.join(", "),
+            phases.join(", ")
+        )
+    }
+}
+
+#[test]
+fn stats_counters_and_output() {
+    // Each test runs on its own thread, so it starts from nothing
+    assert_eq!(snapshot().total_rays(), 0);
+    assert_eq!(snapshot().average_path_length(), 0.0);
+
+    ray(0);
+    ray(0);
+    ray(2);
+    for _ in 0..3 {
+        shadow_ray();
+    }
+    for _ in 0..5 {
+        intersection_test();
+    }
+    path(2);
+    path(4);
+    phase("scene", Duration::from_millis(1500));
+    phase("render", Duration::from_secs(2));
+    phase("scene", Duration::from_millis(500));
+
+    let stats = snapshot();
+    assert_eq!(stats.rays_by_depth, vec![2, 0, 1]);
+    assert_eq!(stats.total_rays(), 6);
+    assert_eq!(stats.intersection_tests, 5);
+    assert_eq!(stats.paths, 2);
+    assert_eq!(stats.average_path_length(), 3.0);
+    // Phases keep the order they were first seen in
+    assert_eq!(
+        stats.phases,
+        vec![
+            ("scene", Duration::from_secs(2)),
+            ("render", Duration::from_secs(2))
+        ]
+    );
+
+    assert_eq!(
+        stats.format(StatsFormat::Json),
+        "{\"total_rays\": 6, \"shadow_rays\": 3, \"intersection_tests\": 5, \
+         \"paths\": 2, \"average_path_length\": 3.000000, \"rays_by_depth\": [2, 0, 1], \
+         \"phase_seconds\": {\"scene\": 2.000000, \"render\": 2.000000}}"
+    );
+    let text = stats.format(StatsFormat::Text);
+    assert!(text.contains("  total rays          6\n"));
+    assert!(text.contains("  average path length 3.000\n"));
+    assert!(text.contains("      2  1\n"));
+    assert!(text.contains("    scene       2.000s\n"));
+}
+
+#[test]
+fn light_sampling_is_not_counted() {
+    use crate::hitable::Hitable;
+    use crate::material::DiffuseLight;
+    use crate::ray::Ray;
+    use crate::rect::Rect;
+    use crate::sphere::Sphere;
+    use crate::triangle::{Face, Mesh, Triangle};
+    use crate::vec3::Vec3;
+
+    let light = DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0));
+    let (a, b, c) = (
+        Vec3::new(-1.0, -1.0, -3.0),
+        Vec3::new(1.0, -1.0, -3.0),
+        Vec3::new(0.0, 1.0, -3.0),
+    );
+    let faces = [
+        Face {
+            vertices: [0, 1, 2],
+            uvs: None,
+        },
+        Face {
+            vertices: [0, 2, 3],
+            uvs: None,
+        },
+    ];
+    let objects: Vec<(Box<dyn Hitable>, u64)> = vec![
+        (Box::new(Sphere::new(c, 0.5, light.clone())), 1),
+        (Box::new(Rect::new(a, b - a, c - a, light.clone())), 1),
+        (Box::new(Triangle::new(a, b, c, light.clone())), 1),
+        (Box::new(Mesh::new(&[a, b, c, c - b + a], &faces, light)), 2),
+    ];
+    // Only tracing a ray counts, not asking where a light is or how likely
+    // it was to be picked
+    let origin = Vec3::default();
+    for (object, tests) in &objects {
+        let before = snapshot().intersection_tests;
+        let sample = object.sample(origin, (0.3, 0.6)).unwrap();
+        assert!(object.pdf(origin, sample.p - origin) > 0.0);
+        assert_eq!(snapshot().intersection_tests, before);
+        assert!(object
+            .hit(&Ray::new(origin, sample.p - origin), 0.001, f64::MAX)
+            .is_some());
+        assert_eq!(snapshot().intersection_tests, before + tests);
+    }
+}
@@ -0,0 +1,284 @@
+use crate::image::Image;
+use crate::vec3::Vec3;
+
+// A color that varies over a surface, looked up by the surface's (u, v)
+// coordinates in [0, 1]
+pub trait Texture {
+    fn value(&self, uv: (f64, f64)) -> Vec3;
+
+    // Mean over all of (u, v), for estimating how much light a textured
+    // emitter gives
+    fn average(&self) -> Vec3;
+
+    // Brightness on a `width` by `height` grid over (u, v), row by row from
+    // v = 0, for sampling textured emitters. It has to be nonzero wherever
+    // `value` is. None if the texture is the same everywhere.
+    fn importance(&self) -> Option<(Vec<f64>, usize, usize)> {
+        None
+    }
+}
+
+pub struct ConstantTexture {
+    color: Vec3,
+}
+
+impl ConstantTexture {
+    pub fn new(color: Vec3) -> ConstantTexture {
+        ConstantTexture { color }
+    }
+}
+
+impl Texture for ConstantTexture {
+    fn value(&self, _uv: (f64, f64)) -> Vec3 {
+        self.color
+    }
+
+    fn average(&self) -> Vec3 {
+        self.color
+    }
+}
+
+fn srgb_eotf(x: f64) -> f64 {
+    if x <= 0.040_45 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// An image stretched over (u, v) with v = 0 at its bottom row, filtered
+// bilinearly and clamped at the edges
+pub struct ImageTexture {
+    image: Image,
+    average: Vec3,
+}
+
+impl ImageTexture {
+    // `srgb` images hold display encoded values, which are made linear
+    pub fn new(mut image: Image, srgb: bool) -> ImageTexture {
+        let (width, height) = (image.width(), image.height());
+        if srgb {
+            for j in 0..height {
+                for i in 0..width {
+                    let c = image.pixel(i, j);
+                    let linear = Vec3::new(srgb_eotf(c.r()), srgb_eotf(c.g()), srgb_eotf(c.b()));
+                    image.set(i, j, linear);
+                }
+            }
+        }
+        let mut average = Vec3::default();
+        for &c in image.pixels() {
+            average += c;
+        }
+        ImageTexture {
+            average: average / (width * height) as f64,
+            image,
+        }
+    }
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, uv: (f64, f64)) -> Vec3 {
+        let (width, height) = (self.image.width(), self.image.height());
+        // Pixel centers sit at half integers
+        let x = (uv.0 * width as f64 - 0.5).clamp(0.0, (width - 1) as f64);
+        let y = (uv.1 * height as f64 - 0.5).clamp(0.0, (height - 1) as f64);
+        let (i, j) = (x as usize, y as usize);
+        let (i1, j1) = ((i + 1).min(width - 1), (j + 1).min(height - 1));
+        let (tx, ty) = (x - i as f64, y - j as f64);
+        let row = |j: usize| (1.0 - tx) * self.image.pixel(i, j) + tx * self.image.pixel(i1, j);
+        (1.0 - ty) * row(j) + ty * row(j1)
+    }
+
+    fn average(&self) -> Vec3 {
+        self.average
+    }
+
+    // Filtering spreads each pixel up to the centers of its neighbors, so a
+    // cell gets the brightest of its pixel and theirs
+    fn importance(&self) -> Option<(Vec<f64>, usize, usize)> {
+        let (width, height) = (self.image.width(), self.image.height());
+        let mut grid = vec![0.0; width * height];
+        for j in 0..height {
+            for i in 0..width {
+                let mut max: f64 = 0.0;
+                for y in j.saturating_sub(1)..(j + 2).min(height) {
+                    for x in i.saturating_sub(1)..(i + 2).min(width) {
+                        max = max.max(self.image.pixel(x, y).luminance());
+                    }
+                }
+                grid[j * width + i] = max;
+            }
+        }
+        Some((grid, width, height))
+    }
+}
+
+#[test]
+fn textured_lights() {
+    use crate::hitable::Hitable;
+    use crate::material::{DiffuseLight, Material};
+    use crate::ray::Ray;
+    use crate::rect::Rect;
+    use std::rc::Rc;
+
+    // Black on the left half, white on the right, bottom row half as bright
+    let mut image = Image::new(4, 2);
+    for i in 2..4 {
+        image.set(i, 0, Vec3::new(0.5, 0.5, 0.5));
+        image.set(i, 1, Vec3::new(1.0, 1.0, 1.0));
+    }
+    let texture = ImageTexture::new(image.clone(), false);
+    assert_eq!(texture.value((0.1, 0.9)), Vec3::default());
+    assert_eq!(texture.value((0.9, 0.9)), Vec3::new(1.0, 1.0, 1.0));
+    // Halfway between pixel centers
+    assert_eq!(texture.value((0.5, 0.9)).r(), 0.5);
+    assert_eq!(texture.average(), Vec3::new(0.375, 0.375, 0.375));
+    let srgb = ImageTexture::new(image, true);
+    assert!((srgb.value((0.9, 0.1)).r() - 0.214).abs() < 1e-3);
+
+    // A unit panel in the z = 0 plane facing +z, seen from either side
+    let rays = [
+        Ray::new(Vec3::new(0.75, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0)),
+        Ray::new(Vec3::new(0.75, 0.25, -1.0), Vec3::new(0.0, 0.0, 1.0)),
+    ];
+    let mut phi = Vec::new();
+    for &two_sided in &[false, true] {
+        let mut image = Image::new(1, 1);
+        image.set(0, 0, Vec3::new(2.0, 2.0, 2.0));
+        let light = DiffuseLight::textured(
+            Rc::new(ImageTexture::new(image, false)),
+            Vec3::new(1.5, 1.0, 1.0),
+            two_sided,
+        );
+        assert_eq!(light.emission(), Vec3::new(3.0, 2.0, 2.0));
+        let panel = Rect::new(
+            Vec3::default(),
+            Vec3::new(1.0, 0.0, 0.0),
+            Vec3::new(0.0, 1.0, 0.0),
+            light,
+        );
+        let front = panel.hit(&rays[0], 0.0, f64::MAX).unwrap();
+        assert!((front.uv.0 - 0.75).abs() < 1e-12 && (front.uv.1 - 0.25).abs() < 1e-12);
+        assert_eq!(
+            front.material.emitted(&rays[0], &front),
+            Vec3::new(3.0, 2.0, 2.0)
+        );
+        let back = panel.hit(&rays[1], 0.0, f64::MAX).unwrap();
+        let expected = if two_sided {
+            Vec3::new(3.0, 2.0, 2.0)
+        } else {
+            Vec3::default()
+        };
+        assert_eq!(back.material.emitted(&rays[1], &back), expected);
+        let bounds = panel.light_bounds().unwrap();
+        assert_eq!(bounds.two_sided, two_sided);
+        phi.push(bounds.phi);
+    }
+    // Emitting from both sides doubles the power
+    assert!((phi[1] - 2.0 * phi[0]).abs() < 1e-12);
+}
+
+#[test]
+fn textured_light_sampling() {
+    use crate::hitable::Hitable;
+    use crate::material::DiffuseLight;
+    use crate::onb::Onb;
+    use crate::ray::Ray;
+    use crate::rect::Rect;
+    use crate::sampler::{IndependentSampler, Sampler};
+    use crate::sphere::Sphere;
+    use std::rc::Rc;
+
+    // Dark but for one bright pixel and a dim one
+    let light = |bright: (usize, usize)| {
+        let mut image = Image::new(8, 4);
+        image.set(bright.0, bright.1, Vec3::new(10.0, 10.0, 10.0));
+        image.set(0, 3, Vec3::new(0.5, 0.5, 0.5));
+        DiffuseLight::textured(
+            Rc::new(ImageTexture::new(image, false)),
+            Vec3::new(1.0, 1.0, 1.0),
+            false,
+        )
+    };
+    let emitted = |object: &dyn Hitable, ray: &Ray| {
+        object
+            .hit(ray, 0.001, f64::MAX)
+            .map_or(Vec3::default(), |rec| rec.material.emitted(ray, &rec))
+            .r()
+    };
+    // Radiance integrated over directions within `extent` of `axis`, on a
+    // grid over the plane at distance 1
+    let reference = |object: &dyn Hitable, origin: Vec3, axis: Vec3, extent: f64| {
+        let uvw = Onb::build_from_w(axis);
+        let n = 800;
+        let step = 2.0 * extent / n as f64;
+        let mut sum = 0.0;
+        for j in 0..n {
+            for i in 0..n {
+                let x = -extent + (i as f64 + 0.5) * step;
+                let y = -extent + (j as f64 + 0.5) * step;
+                let jacobian = (1.0 + x * x + y * y).powf(-1.5);
+                let ray = Ray::new(origin, uvw.local(x, y, 1.0));
+                sum += emitted(object, &ray) * jacobian * step * step;
+            }
+        }
+        sum
+    };
+
+    let rect = Rect::new(
+        Vec3::default(),
+        Vec3::new(1.0, 0.0, 0.0),
+        Vec3::new(0.0, 1.0, 0.0),
+        light((5, 1)),
+    );
+    let sphere = Sphere::new(Vec3::default(), 1.0, light((2, 2)));
+    let cases: [(&dyn Hitable, Vec3, Vec3, f64); 2] = [
+        (
+            &rect,
+            Vec3::new(0.3, 0.6, 1.0),
+            Vec3::new(0.2, -0.1, -1.0),
+            1.0,
+        ),
+        (
+            &sphere,
+            Vec3::new(0.0, 0.5, 3.0),
+            Vec3::new(0.0, -0.5, -3.0),
+            0.45,
+        ),
+    ];
+    let mut sampler = IndependentSampler::new(0);
+    for (object, origin, axis, extent) in cases.iter() {
+        let n = 20_000;
+        let mut estimate = 0.0;
+        let mut lit = 0;
+        for index in 0..n {
+            sampler.start_pixel_sample((0, 0), index);
+            let sample = object.sample(*origin, sampler.get_2d()).unwrap();
+            // As for a shadow ray, only a sample seen from `origin` counts
+            let ray = Ray::new(*origin, sample.p - *origin);
+            let rec = object.hit(&ray, 0.001, f64::MAX).unwrap();
+            if (rec.t - 1.0).abs() > 1e-6 {
+                continue;
+            }
+            let pdf = object.pdf(*origin, ray.direction());
+            assert!((pdf - sample.pdf).abs() < 1e-6 * pdf);
+            let radiance = emitted(*object, &ray);
+            if radiance > 0.0 {
+                lit += 1;
+            }
+            estimate += radiance / sample.pdf;
+        }
+        estimate /= n as f64;
+        let expected = reference(*object, *origin, *axis, *extent);
+        assert!(
+            (estimate - expected).abs() < 0.02 * expected,
+            "{} vs {}",
+            estimate,
+            expected
+        );
+        // Most samples land where the texture is bright, against an eighth
+        // of them for sampling by area alone
+        assert!(lit > n / 3, "{} of {} samples lit", lit, n);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::vec3::Vec3;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ToneMapper {
+    None,
+    Reinhard,
+    Aces,
+    Hable,
+    Agx,
+}
+
+impl FromStr for ToneMapper {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ToneMapper::None),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "aces" => Ok(ToneMapper::Aces),
+            "hable" => Ok(ToneMapper::Hable),
+            "agx" => Ok(ToneMapper::Agx),
+            _ => Err(()),
+        }
+    }
+}
+
+type Matrix = [[f64; 3]; 3];
+
+fn mul(m: &Matrix, v: Vec3) -> Vec3 {
+    Vec3::new(
+        m[0][0] * v.r() + m[0][1] * v.g() + m[0][2] * v.b(),
+        m[1][0] * v.r() + m[1][1] * v.g() + m[1][2] * v.b(),
+        m[2][0] * v.r() + m[2][1] * v.g() + m[2][2] * v.b(),
+    )
+}
+
+fn map(v: Vec3, f: impl Fn(f64) -> f64) -> Vec3 {
+    Vec3::new(f(v.r()), f(v.g()), f(v.b()))
+}
+
+fn reinhard(col: Vec3) -> Vec3 {
+    let lum = col.luminance();
+    if lum <= 0.0 {
+        return Vec3::default();
+    }
+    col / (1.0 + lum)
+}
+
+// Stephen Hill's fit of the ACES RRT + sRGB ODT
+fn aces(col: Vec3) -> Vec3 {
+    const INPUT: Matrix = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: Matrix = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let v = mul(&INPUT, col);
+    let v = map(v, |x| {
+        (x * (x + 0.024_578_6) - 0.000_090_537) / (x * (0.983_729 * x + 0.432_951) + 0.238_081)
+    });
+    mul(&OUTPUT, v)
+}
+
+// John Hable's filmic curve from Uncharted 2
+fn hable(col: Vec3) -> Vec3 {
+    let curve = |x: f64| {
+        let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+        ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+    };
+    let white = 11.2;
+    map(col, |x| curve(2.0 * x) / curve(white))
+}
+
+// Minimal AgX (Troy Sobotka's AgX, polynomial fit by Benjamin Wrensch)
+fn agx(col: Vec3) -> Vec3 {
+    #[rustfmt::skip]
+    const INSET: Matrix = [
+        [0.842_479_062_253_094, 0.078_433_599_999_999_2, 0.079_223_745_147_764_3],
+        [0.042_328_242_261_012_3, 0.878_468_636_469_772, 0.079_166_127_460_543_4],
+        [0.042_375_654_905_705_1, 0.078_433_6, 0.879_142_973_793_104],
+    ];
+    #[rustfmt::skip]
+    const OUTSET: Matrix = [
+        [1.196_879_005_120_17, -0.098_020_881_140_136_8, -0.099_029_744_079_720_5],
+        [-0.052_896_851_757_456_2, 1.151_903_129_904_17, -0.098_961_176_844_843_3],
+        [-0.052_971_635_514_443_8, -0.098_043_450_117_124_1, 1.151_073_672_641_16],
+    ];
+    let (min_ev, max_ev) = (-12.473_931_188, 4.026_068_812);
+    let v = mul(&INSET, col);
+    let v = map(v, |x| {
+        let x = (x.max(1e-10).log2().max(min_ev).min(max_ev) - min_ev) / (max_ev - min_ev);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+    // Back out of the AgX encoding to linear, ready for the sRGB OETF
+    map(mul(&OUTSET, v), |x| x.max(0.0).powf(2.2))
+}
+
+fn srgb_oetf(x: f64) -> f64 {
+    if x <= 0.003_130_8 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+// Far past white for every tone mapper, while small enough that squaring
+// it doesn't overflow
+const BRIGHTEST: f64 = 1e30;
+
+// Maps linear scene-referred radiance to display-referred sRGB in [0, 1].
+// Only used when writing LDR images; everything upstream stays linear.
+#[derive(Copy, Clone, Debug)]
+pub struct OutputTransform {
+    exposure: f64,
+    tone_mapper: ToneMapper,
+}
+
+impl OutputTransform {
+    // `exposure` is in stops
+    pub fn new(exposure: f64, tone_mapper: ToneMapper) -> OutputTransform {
+        OutputTransform {
+            exposure,
+            tone_mapper,
+        }
+    }
+
+    pub fn apply(&self, col: Vec3) -> Vec3 {
+        // NaN shows as black. Anything brighter than BRIGHTEST, up to
+        // infinity, shows as white; the tone mappers would overflow on it.
+        let col = map(col * 2f64.powf(self.exposure), |x| {
+            if x.is_nan() {
+                0.0
+            } else {
+                x.clamp(0.0, BRIGHTEST)
+            }
+        });
+        let col = match self.tone_mapper {
+            ToneMapper::None => col,
+            ToneMapper::Reinhard => reinhard(col),
+            ToneMapper::Aces => aces(col),
+            ToneMapper::Hable => hable(col),
+            ToneMapper::Agx => agx(col),
+        };
+        map(col, |x| srgb_oetf(x.clamp(0.0, 1.0)))
+    }
+}
+
+#[test]
+fn srgb_encoding() {
+    assert_eq!(srgb_oetf(0.0), 0.0);
+    assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-12);
+    assert!((srgb_oetf(0.002) - 0.02584).abs() < 1e-12);
+    // Middle gray, and the two pieces meeting at the breakpoint
+    assert!((srgb_oetf(0.18) - 0.461_356).abs() < 1e-6);
+    let x = 0.003_130_8;
+    assert!((srgb_oetf(x) - srgb_oetf(x + 1e-12)).abs() < 1e-6);
+}
+
+#[test]
+fn tone_mappers() {
+    let mappers = [
+        ToneMapper::None,
+        ToneMapper::Reinhard,
+        ToneMapper::Aces,
+        ToneMapper::Hable,
+        ToneMapper::Agx,
+    ];
+    let gray = |x: f64| Vec3::new(x, x, x);
+    assert_eq!(reinhard(gray(1.0)), gray(0.5));
+    for &mapper in &mappers {
+        let transform = OutputTransform::new(0.0, mapper);
+        // Black stays black, and brighter grays come out brighter, up to
+        // white, in range and gray
+        assert!(transform.apply(gray(0.0)).r() < 0.01, "{:?}", mapper);
+        let mut last = -1.0;
+        for i in 0..=40 {
+            let out = transform.apply(gray(2f64.powf(i as f64 / 2.0 - 10.0)));
+            assert!(out.r() >= last, "{:?}", mapper);
+            assert!((0.0..=1.0).contains(&out.r()), "{:?}", mapper);
+            assert!((out.g() - out.r()).abs() < 1e-3 && (out.b() - out.r()).abs() < 1e-3);
+            last = out.r();
+        }
+        assert!(transform.apply(gray(1e6)).r() > 0.95, "{:?}", mapper);
+        // Infinitely bright is white, as near as AgX's fit gets, and NaN or
+        // negative is black
+        let white = transform.apply(gray(f64::INFINITY));
+        assert!((white - gray(1.0)).len() < 0.01, "{:?}", mapper);
+        let out = transform.apply(Vec3::new(f64::INFINITY, f64::NAN, -1.0));
+        assert!(out.r() > 0.95 && out.g().is_finite() && out.b().is_finite());
+        let black = transform.apply(gray(f64::NAN));
+        assert!(black.r() < 0.01 && black.r() >= 0.0, "{:?}", mapper);
+    }
+    // A stop of exposure doubles the input
+    let brighter = OutputTransform::new(1.0, ToneMapper::None).apply(gray(0.25));
+    assert_eq!(
+        brighter,
+        OutputTransform::new(0.0, ToneMapper::None).apply(gray(0.5))
+    );
+}
@@ -0,0 +1,437 @@
+use crate::distribution::Distribution1D;
+use crate::hitable::{area_to_solid_angle, HitRecord, Hitable, LightSample};
+use crate::light_sampler::{Aabb, DirectionCone, LightBounds};
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::sampler::remap;
+use crate::stats;
+use crate::vec3::Vec3;
+use std::rc::Rc;
+
+// The front of a triangle is the side its vertices run counterclockwise on,
+// which is where its normal points. Texture coordinates are interpolated
+// from its corners, by default (0, 0), (1, 0) and (0, 1).
+#[derive(Clone)]
+pub struct Triangle {
+    v0: Vec3,
+    e1: Vec3,
+    e2: Vec3,
+    normal: Vec3,
+    area: f64,
+    uvs: [(f64, f64); 3],
+    material: Rc<dyn Material>,
+}
+
+impl Triangle {
+    pub fn new(v0: Vec3, v1: Vec3, v2: Vec3, material: Rc<dyn Material>) -> Triangle {
+        let (e1, e2) = (v1 - v0, v2 - v0);
+        let cross = Vec3::cross(e1, e2);
+        Triangle {
+            v0,
+            e1,
+            e2,
+            normal: Vec3::unit_vector(cross),
+            area: 0.5 * cross.len(),
+            uvs: [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)],
+            material,
+        }
+    }
+
+    pub fn with_uvs(mut self, uvs: [(f64, f64); 3]) -> Triangle {
+        self.uvs = uvs;
+        self
+    }
+
+    pub fn area(&self) -> f64 {
+        self.area
+    }
+
+    // Uniformly distributed point on the triangle
+    fn sample_point(&self, u: (f64, f64)) -> Vec3 {
+        let su0 = u.0.sqrt();
+        self.v0 + (1.0 - su0) * self.e1 + (u.1 * su0) * self.e2
+    }
+
+    // Möller–Trumbore, without counting an intersection test, for pdf
+    fn intersect(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        let p = Vec3::cross(ray.direction(), self.e2);
+        let det = Vec3::dot(self.e1, p);
+        if det == 0.0 {
+            return None;
+        }
+        let s = ray.origin() - self.v0;
+        let b1 = Vec3::dot(s, p) / det;
+        if !(0.0..=1.0).contains(&b1) {
+            return None;
+        }
+        let q = Vec3::cross(s, self.e1);
+        let b2 = Vec3::dot(ray.direction(), q) / det;
+        if b2 < 0.0 || b1 + b2 > 1.0 {
+            return None;
+        }
+        let t = Vec3::dot(self.e2, q) / det;
+        if t <= t_min || t >= t_max {
+            return None;
+        }
+        let b0 = 1.0 - b1 - b2;
+        let [uv0, uv1, uv2] = self.uvs;
+        Some(HitRecord {
+            t,
+            p: ray.point_at_parameter(t),
+            normal: self.normal,
+            uv: (
+                b0 * uv0.0 + b1 * uv1.0 + b2 * uv2.0,
+                b0 * uv0.1 + b1 * uv1.1 + b2 * uv2.1,
+            ),
+            material: self.material.as_ref(),
+            object_id: 0,
+        })
+    }
+}
+
+impl Hitable for Triangle {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        stats::intersection_test();
+        self.intersect(ray, t_min, t_max)
+    }
+
+    // Samples the triangle uniformly by area
+    fn sample(&self, origin: Vec3, u: (f64, f64)) -> Option<LightSample> {
+        let p = self.sample_point(u);
+        Some(LightSample {
+            p,
+            normal: self.normal,
+            pdf: area_to_solid_angle(1.0 / self.area, origin, p, self.normal),
+            two_sided: self.material.two_sided(),
+        })
+    }
+
+    fn pdf(&self, origin: Vec3, direction: Vec3) -> f64 {
+        match self.intersect(&Ray::new(origin, direction), 0.001, f64::MAX) {
+            Some(rec) => area_to_solid_angle(1.0 / self.area, origin, rec.p, self.normal),
+            None => 0.0,
+        }
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        Some(
+            LightBounds::new(
+                Aabb::new(self.v0, self.v0 + self.e1).include(self.v0 + self.e2),
+                DirectionCone::new(self.normal, 1.0),
+                self.area,
+                self.material.emission(),
+            )
+            .two_sided(self.material.two_sided()),
+        )
+    }
+}
+
+// Triangles sharing one material. Sampled as a light by picking a triangle
+// in proportion to its area, so points are uniform over the whole surface.
+// Those points can be hidden behind other triangles, while `pdf` only knows
+// the first triangle a direction hits, so a sample only holds if nothing is
+// in front of it. Cloning shares the triangles.
+#[derive(Clone)]
+pub struct Mesh {
+    triangles: Rc<[Triangle]>,
+    areas: Rc<Distribution1D>,
+    area: f64,
+}
+
+impl Mesh {
+    pub fn new(vertices: &[Vec3], faces: &[Face], material: Rc<dyn Material>) -> Mesh {
+        let triangles: Vec<Triangle> = faces
+            .iter()
+            .map(|f| {
+                let [a, b, c] = f.vertices;
+                let triangle =
+                    Triangle::new(vertices[a], vertices[b], vertices[c], material.clone());
+                match f.uvs {
+                    Some(uvs) => triangle.with_uvs(uvs),
+                    None => triangle,
+                }
+            })
+            .collect();
+        let areas: Vec<f64> = triangles.iter().map(|t| t.area()).collect();
+        Mesh {
+            area: areas.iter().sum(),
+            areas: Rc::new(Distribution1D::new(&areas)),
+            triangles: triangles.into(),
+        }
+    }
+
+    // The closest of the hits `hit` finds on each triangle
+    fn closest<'a>(
+        &'a self,
+        ray: &Ray,
+        t_min: f64,
+        t_max: f64,
+        hit: impl Fn(&'a Triangle, &Ray, f64, f64) -> Option<HitRecord<'a>>,
+    ) -> Option<HitRecord<'a>> {
+        let mut closest = None;
+        let mut closest_so_far = t_max;
+        for triangle in self.triangles.iter() {
+            if let Some(rec) = hit(triangle, ray, t_min, closest_so_far) {
+                closest_so_far = rec.t;
+                closest = Some(rec);
+            }
+        }
+        closest
+    }
+}
+
+impl Hitable for Mesh {
+    fn hit(&self, ray: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord<'_>> {
+        self.closest(ray, t_min, t_max, <Triangle as Hitable>::hit)
+    }
+
+    fn sample(&self, origin: Vec3, u: (f64, f64)) -> Option<LightSample> {
+        // The first dimension picks the triangle and is then reused
+        let (x, _, index) = self.areas.sample(u.0);
+        let u0 = remap(x * self.areas.count() as f64, index as f64, 1.0);
+        let triangle = &self.triangles[index];
+        let p = triangle.sample_point((u0, u.1));
+        Some(LightSample {
+            p,
+            normal: triangle.normal,
+            pdf: area_to_solid_angle(1.0 / self.area, origin, p, triangle.normal),
+            two_sided: triangle.material.two_sided(),
+        })
+    }
+
+    fn pdf(&self, origin: Vec3, direction: Vec3) -> f64 {
+        let ray = Ray::new(origin, direction);
+        match self.closest(&ray, 0.001, f64::MAX, Triangle::intersect) {
+            Some(rec) => area_to_solid_angle(1.0 / self.area, origin, rec.p, rec.normal),
+            None => 0.0,
+        }
+    }
+
+    fn light_bounds(&self) -> Option<LightBounds> {
+        self.triangles
+            .iter()
+            .filter_map(|t| t.light_bounds())
+            .reduce(LightBounds::union)
+    }
+}
+
+// A triangle of a mesh: indices into its vertices, and the texture
+// coordinates at its corners if the file gives them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Face {
+    pub vertices: [usize; 3],
+    pub uvs: Option<[(f64, f64); 3]>,
+}
+
+// Reads the vertices, texture coordinates and faces of a Wavefront OBJ file,
+// splitting polygons into fans of triangles. Everything else is ignored.
+pub fn parse_obj(text: &str) -> Result<(Vec<Vec3>, Vec<Face>), String> {
+    let mut vertices = Vec::new();
+    let mut uvs = Vec::new();
+    let mut faces = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |msg: &str| format!("line {}: {}", index + 1, msg);
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("v") => {
+                let v = words
+                    .take(3)
+                    .map(|w| w.parse())
+                    .collect::<Result<Vec<f64>, _>>()
+                    .map_err(|_| error("invalid vertex"))?;
+                if v.len() != 3 {
+                    return Err(error("vertex needs 3 coordinates"));
+                }
+                vertices.push(Vec3::new(v[0], v[1], v[2]));
+            }
+            // The second coordinate is optional and a third is ignored
+            Some("vt") => {
+                let uv = words
+                    .take(2)
+                    .map(|w| w.parse())
+                    .collect::<Result<Vec<f64>, _>>()
+                    .map_err(|_| error("invalid texture coordinate"))?;
+                match uv[..] {
+                    [u] => uvs.push((u, 0.0)),
+                    [u, v] => uvs.push((u, v)),
+                    _ => return Err(error("texture coordinate needs a number")),
+                }
+            }
+            Some("f") => {
+                // Indices start at 1, or count back from the last one when
+                // negative
+                let resolve = |word: &str, count: usize, what: &str| {
+                    let i: i64 = word.parse().map_err(|_| error("invalid face"))?;
+                    let i = if i < 0 { count as i64 + i } else { i - 1 };
+                    if i < 0 || i >= count as i64 {
+                        return Err(error(&format!("face refers to a missing {}", what)));
+                    }
+                    Ok(i as usize)
+                };
+                // Each corner is v, v/vt, v//vn or v/vt/vn
+                let corners = words
+                    .map(|w| {
+                        let mut parts = w.split('/');
+                        let v = resolve(parts.next().unwrap(), vertices.len(), "vertex")?;
+                        let vt = match parts.next() {
+                            Some(vt) if !vt.is_empty() => {
+                                Some(uvs[resolve(vt, uvs.len(), "texture coordinate")?])
+                            }
+                            _ => None,
+                        };
+                        Ok((v, vt))
+                    })
+                    .collect::<Result<Vec<(usize, Option<(f64, f64)>)>, String>>()?;
+                if corners.len() < 3 {
+                    return Err(error("face needs at least 3 vertices"));
+                }
+                // Texture coordinates count only if every corner has them
+                let face_uvs: Option<Vec<(f64, f64)>> = corners.iter().map(|c| c.1).collect();
+                for i in 1..corners.len() - 1 {
+                    faces.push(Face {
+                        vertices: [corners[0].0, corners[i].0, corners[i + 1].0],
+                        uvs: face_uvs.as_ref().map(|uv| [uv[0], uv[i], uv[i + 1]]),
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+    if faces.is_empty() {
+        return Err("mesh has no faces".to_string());
+    }
+    Ok((vertices, faces))
+}
+
+#[test]
+fn mesh_light_sampling_matches_pdf() {
+    use crate::material::DiffuseLight;
+    use crate::sampler::{IndependentSampler, Sampler};
+
+    // A unit square split unevenly, facing down at a point below it
+    let (vertices, faces) = parse_obj(
+        "v 0 1 0\nv 1 1 0\nv 1 1 1\nv 0 1 1\nv 0.2 1 0.1\nvt 0 0\n\
+         f 1 2 5\nf 2/1 3//2 -1\nf 3 4 5\nf 4 1 5\n",
+    )
+    .unwrap();
+    let mesh = Mesh::new(
+        &vertices,
+        &faces,
+        DiffuseLight::new(Vec3::new(1.0, 1.0, 1.0)),
+    );
+    assert!((mesh.area - 1.0).abs() < 1e-12);
+    let origin = Vec3::new(0.3, 0.0, 0.6);
+    let mut sampler = IndependentSampler::new(0);
+    // The average of 1 / pdf is the solid angle the mesh covers
+    let n = 20_000;
+    let mut solid_angle = 0.0;
+    for index in 0..n {
+        sampler.start_pixel_sample((0, 0), index);
+        let sample = mesh.sample(origin, sampler.get_2d()).unwrap();
+        assert!((sample.p.y() - 1.0).abs() < 1e-12);
+        let pdf = mesh.pdf(origin, sample.p - origin);
+        assert!((pdf - sample.pdf).abs() < 1e-9 * pdf);
+        solid_angle += 1.0 / pdf;
+    }
+    solid_angle /= n as f64;
+    // Solid angle of a rectangle seen from a point below one of its
+    // corners, summed over the four rectangles around the foot of the point
+    let corner = |a: f64, b: f64| (a * b / (1.0 + a * a + b * b).sqrt()).atan();
+    let expected = corner(0.3, 0.6) + corner(0.7, 0.6) + corner(0.3, 0.4) + corner(0.7, 0.4);
+    assert!(
+        (solid_angle - expected).abs() < 0.01 * expected,
+        "{} vs {}",
+        solid_angle,
+        expected
+    );
+
+    // A closed unit cube centered 1.5 below the point. Samples often land on
+    // faces hidden behind others, and a light sample only counts if nothing
+    // is in front of it, as in the integrator's shadow rays; then `pdf`
+    // agrees and only the near face's solid angle is covered.
+    let (vertices, faces) = parse_obj(
+        "v 0 -2 0\nv 1 -2 0\nv 1 -2 1\nv 0 -2 1\n\
+         v 0 -1 0\nv 1 -1 0\nv 1 -1 1\nv 0 -1 1\n\
+         f 1 2 3 4\nf 8 7 6 5\nf 1 5 6 2\nf 2 6 7 3\nf 3 7 8 4\nf 4 8 5 1\n",
+    )
+    .unwrap();
+    let cube = Mesh::new(
+        &vertices,
+        &faces,
+        DiffuseLight::textured(
+            std::rc::Rc::new(crate::texture::ConstantTexture::new(Vec3::new(
+                1.0, 1.0, 1.0,
+            ))),
+            Vec3::new(1.0, 1.0, 1.0),
+            true,
+        ),
+    );
+    let origin = Vec3::new(0.5, 0.5, 0.5);
+    let mut solid_angle = 0.0;
+    let mut hidden = 0;
+    for index in 0..n {
+        sampler.start_pixel_sample((0, 0), index);
+        let sample = cube.sample(origin, sampler.get_2d()).unwrap();
+        let to_light = sample.p - origin;
+        let rec = cube
+            .hit(&Ray::new(origin, to_light), 0.001, f64::MAX)
+            .unwrap();
+        if (rec.t - 1.0).abs() > 1e-6 {
+            hidden += 1;
+            continue;
+        }
+        let pdf = cube.pdf(origin, to_light);
+        assert!((pdf - sample.pdf).abs() < 1e-9 * pdf);
+        solid_angle += 1.0 / sample.pdf;
+    }
+    solid_angle /= n as f64;
+    assert!(hidden > n / 2);
+    let expected = 4.0 * corner(0.5 / 1.5, 0.5 / 1.5);
+    assert!(
+        (solid_angle - expected).abs() < 0.02 * expected,
+        "{} vs {}",
+        solid_angle,
+        expected
+    );
+}
+
+#[test]
+fn obj_texture_coordinates() {
+    use crate::material::Lambertian;
+
+    // A quad mapped onto the right half of a texture, and a triangle without
+    // texture coordinates
+    let (vertices, faces) = parse_obj(
+        "v 0 0 0\nv 2 0 0\nv 2 1 0\nv 0 1 0\n\
+         vt 0.5 0\nvt 1 0\nvt 1 1 0\nvt 0.5 1\n\
+         f 1/1 2/2/1 3/-2 4/4\nf 1 2 3/3\n",
+    )
+    .unwrap();
+    assert_eq!(faces.len(), 3);
+    assert_eq!(faces[1].uvs, Some([(0.5, 0.0), (1.0, 1.0), (0.5, 1.0)]));
+    assert_eq!(faces[2].uvs, None);
+    let mesh = Mesh::new(
+        &vertices,
+        &faces[..2],
+        Lambertian::new(Vec3::new(1.0, 1.0, 1.0)),
+    );
+    let ray = Ray::new(Vec3::new(0.5, 0.75, 1.0), Vec3::new(0.0, 0.0, -1.0));
+    let rec = mesh.hit(&ray, 0.0, f64::MAX).unwrap();
+    assert!((rec.uv.0 - 0.625).abs() < 1e-12 && (rec.uv.1 - 0.75).abs() < 1e-12);
+    // Without them, the first and second edges run along u and v
+    let triangle = Mesh::new(
+        &vertices,
+        &faces[2..],
+        Lambertian::new(Vec3::new(1.0, 1.0, 1.0)),
+    );
+    let ray = Ray::new(Vec3::new(1.5, 0.25, 1.0), Vec3::new(0.0, 0.0, -1.0));
+    let rec = triangle.hit(&ray, 0.0, f64::MAX).unwrap();
+    assert!((rec.uv.0 - 0.5).abs() < 1e-12 && (rec.uv.1 - 0.25).abs() < 1e-12);
+
+    let error = |text: &str| parse_obj(text).unwrap_err();
+    assert_eq!(
+        error("v 0 0 0\nv 1 0 0\nv 0 1 0\nvt 0 0\nf 1/1 2/2 3/1\n"),
+        "line 5: face refers to a missing texture coordinate"
+    );
+    assert_eq!(error("vt\n"), "line 1: texture coordinate needs a number");
+}
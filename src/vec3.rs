@@ -68,6 +68,14 @@ impl Vec3 {
     pub fn unit_vector(vec: Vec3) -> Vec3 {
         vec / vec.len()
     }
+
+    pub fn luminance(self) -> f64 {
+        0.2126 * self.e[0] + 0.7152 * self.e[1] + 0.0722 * self.e[2]
+    }
+
+    pub fn max_component(self) -> f64 {
+        self.e[0].max(self.e[1]).max(self.e[2])
+    }
 }
 
 impl Display for Vec3 {